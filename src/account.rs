@@ -1,23 +1,37 @@
-use std::thread;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::Duration;
 
-use log::{debug, error, info};
-use reqwest::header::{HeaderMap};
-use reqwest::header::{ACCEPT, REFERER, USER_AGENT};
-use reqwest::{self, Url};
+use log::{debug, error};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::header::{ACCEPT, CONTENT_TYPE, REFERER, RETRY_AFTER, USER_AGENT};
+use reqwest::{self, StatusCode, Url};
 use reqwest::{Client, Proxy};
 use serde::{Deserialize, Serialize};
-use thirtyfour::common::cookie::Cookie as TCookie;
-use thirtyfour::prelude::*;
+use tokio::sync::Semaphore;
 
-#[derive(Debug,Clone)]
+use crate::error::AccountError;
+use crate::throttle::{Backoff, RateLimit, Throttle, ThrottleState};
+
+/// ti.com 官网地址，库存接口都挂在这个地址下面
+pub const DEFAULT_BASE_URL: &str = "https://www.ti.com/";
+
+/// 默认的请求超时时间
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 批量查询时默认同时进行的请求数
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// 克隆出来的 `Account` 共用同一个连接池、cookie 和限速器
+#[derive(Debug, Clone)]
 pub struct Account {
     client: Client,
+    base_url: Url,
+    throttle: Throttle,
 }
 
 /// 一些辅助函数
 impl Account {
-
     fn gen_default_headers() -> HeaderMap {
         let mut default_headers = HeaderMap::new();
 
@@ -45,77 +59,583 @@ impl Account {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Store {
-    orderable_number: String,
-    inventory: usize,
+/// 库存接口 `/storeservices/cart/opninventory` 返回的结果
+///
+/// 除了型号和库存，其它字段不一定每个产品都有，所以都是可选的。
+/// 只认接口自己用的字段名(生命周期还有 `lifecycle`，货期还有 `leadtime` 的写法)，
+/// 没有单独列出来的字段原样保存在 `extra` 里面，接口新增字段也不会丢。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Inventory {
+    /// 产品型号(OPN)
+    pub orderable_number: String,
+    /// 库存数量
+    pub inventory: usize,
+    /// 各币种的阶梯价格
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pricing: Vec<Pricing>,
+    /// 生命周期状态，例如 ACTIVE、NRND、OBSOLETE
+    #[serde(default, alias = "lifecycle", skip_serializing_if = "Option::is_none")]
+    pub lifecycle_status: Option<String>,
+    /// 包装方式，例如 LARGE T&R、TUBE
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packaging: Option<String>,
+    /// 每个包装的数量
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package_quantity: Option<u64>,
+    /// 货期，接口有时候返回数字有时候返回文字，统一转成文字
+    #[serde(
+        default,
+        alias = "leadtime",
+        deserialize_with = "string_or_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub lead_time: Option<String>,
+    /// 最小起订量
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum_order_quantity: Option<u64>,
+    /// 单次最多能买的数量
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purchase_limit: Option<u64>,
+    /// 其它没有单独解析的字段
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// 某个币种的阶梯价格
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pricing {
+    #[serde(default)]
+    pub currency: String,
+    #[serde(default, alias = "priceBreaks")]
+    pub price_breaks: Vec<PriceBreak>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// 买到 `quantity` 个及以上时的单价
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceBreak {
+    #[serde(alias = "price_break_quantity", alias = "priceBreakQuantity")]
+    pub quantity: u64,
+    pub price: f64,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Inventory {
+    /// 指定币种的阶梯价格，没有就返回 `None`
+    pub fn pricing(&self, currency: &str) -> Option<&Pricing> {
+        self.pricing
+            .iter()
+            .find(|v| v.currency.eq_ignore_ascii_case(currency))
+    }
+
+    /// 买 `quantity` 个时的单价
+    pub fn unit_price(&self, currency: &str, quantity: u64) -> Option<f64> {
+        self.pricing(currency)?.unit_price(quantity)
+    }
+}
+
+impl Pricing {
+    /// 买 `quantity` 个时能享受的单价，数量不够第一档时返回 `None`
+    pub fn unit_price(&self, quantity: u64) -> Option<f64> {
+        self.price_breaks
+            .iter()
+            .filter(|v| v.quantity <= quantity)
+            .max_by_key(|v| v.quantity)
+            .map(|v| v.price)
+    }
+}
+
+fn string_or_number<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: Option<serde_json::Value> = Option::deserialize(deserializer)?;
+    Ok(match value {
+        Some(serde_json::Value::String(v)) => Some(v),
+        Some(serde_json::Value::Number(v)) => Some(v.to_string()),
+        _ => None,
+    })
+}
+
+/// 批量查询库存的参数
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// 最多同时进行几个请求，小于 1 按 1 处理
+    pub concurrency: usize,
+    /// 单个产品查询的超时时间，包括排队之后的请求和解析，`None` 表示只用客户端的超时
+    pub timeout: Option<Duration>,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            concurrency: DEFAULT_CONCURRENCY,
+            timeout: None,
+        }
+    }
+}
+
+/// 批量查询的结果，每个型号一个，成功和失败分开记录，互不影响
+pub type BatchResult = HashMap<String, Result<Inventory, AccountError>>;
+
+/// 用来配置并创建 [`Account`]
+///
+/// ```no_run
+/// # async fn run() -> Result<(), ti::AccountError> {
+/// use std::time::Duration;
+/// use ti::Account;
+///
+/// let account = Account::builder()
+///     .timeout(Duration::from_secs(10))
+///     .proxy("http://127.0.0.1:1080")
+///     .build()?;
+/// let inventory = account.get_inventory("OPA1622IDRCR").await?;
+/// println!("{} 库存: {}", inventory.orderable_number, inventory.inventory);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct AccountBuilder {
+    base_url: String,
+    timeout: Duration,
+    headers: HeaderMap,
+    proxy: Option<String>,
+    rate_limit: RateLimit,
+    backoff: Backoff,
+}
+
+impl Default for AccountBuilder {
+    fn default() -> Self {
+        AccountBuilder {
+            base_url: DEFAULT_BASE_URL.to_owned(),
+            timeout: DEFAULT_TIMEOUT,
+            headers: Account::gen_default_headers(),
+            proxy: None,
+            rate_limit: RateLimit::default(),
+            backoff: Backoff::default(),
+        }
+    }
+}
+
+impl AccountBuilder {
+    /// 接口地址，默认是 [`DEFAULT_BASE_URL`]
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_owned();
+        self
+    }
+
+    /// 单个请求的超时时间，默认是 [`DEFAULT_TIMEOUT`]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 添加或者覆盖一个默认请求头
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// 替换全部默认请求头
+    pub fn headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// 所有请求都走这个代理，例如 `http://127.0.0.1:1080`
+    pub fn proxy(mut self, proxy: &str) -> Self {
+        self.proxy = Some(proxy.to_owned());
+        self
+    }
+
+    /// 限速设置，默认每秒 2 个请求
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// 出错之后的退避和熔断设置
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn build(self) -> Result<Account, AccountError> {
+        let mut base_url = match Url::parse(&self.base_url) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(AccountError::Config(format!(
+                    "接口地址 {} 不正确:{}",
+                    self.base_url, e
+                )));
+            }
+        };
+        // 保证 join 的时候不会把最后一段路径替换掉
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        let cookie_store = cookie_store::CookieStore::default();
+        let cookie_store =
+            std::sync::Arc::new(reqwest_cookie_store::CookieStoreMutex::new(cookie_store));
+
+        let mut builder = reqwest::Client::builder()
+            .default_headers(self.headers)
+            .cookie_provider(cookie_store)
+            .timeout(self.timeout);
+
+        if let Some(proxy) = &self.proxy {
+            let proxy = match Proxy::all(proxy.as_str()) {
+                Ok(v) => v,
+                Err(e) => {
+                    return Err(AccountError::Config(format!(
+                        "代理地址 {} 不正确:{}",
+                        proxy, e
+                    )));
+                }
+            };
+            builder = builder.proxy(proxy);
+        }
+
+        let client = match builder.build() {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(AccountError::Config(format!("创建http客户端出错:{}", e)));
+            }
+        };
+
+        Ok(Account {
+            client,
+            base_url,
+            throttle: Throttle::new(self.rate_limit, self.backoff),
+        })
+    }
 }
 
 impl Account {
+    /// 用默认的设置创建
     pub async fn new() -> Self {
-        // 调用selenium 模拟登录，获取登录后的cookie
-        // let cookies = Self::login(username, password).await;
+        Self::builder().build().unwrap()
+    }
 
-        // 根据获取到的cookie创建 reqwest client
-        let client = {
-            let cookie_store = cookie_store::CookieStore::default();
+    pub fn builder() -> AccountBuilder {
+        AccountBuilder::default()
+    }
 
-            let cookie_store =
-                std::sync::Arc::new(reqwest_cookie_store::CookieStoreMutex::new(cookie_store));
+    pub async fn get_store_by_product_name(
+        &self,
+        product_name: &str,
+    ) -> Result<usize, AccountError> {
+        self.get_inventory(product_name).await.map(|v| v.inventory)
+    }
 
-            let default_headers = Self::gen_default_headers();
+    /// 批量获取多个产品的库存信息
+    ///
+    /// 空行和重复的型号会被忽略，某个型号失败不会影响其它型号，
+    /// 失败原因记录在返回结果里对应型号的 `Err` 中。
+    pub async fn get_inventories<I, S>(
+        &self,
+        product_names: I,
+        options: &BatchOptions,
+    ) -> BatchResult
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let semaphore = Arc::new(Semaphore::new(options.concurrency.max(1)));
+        let mut tasks = vec![];
+        let mut seen = HashSet::new();
 
-            reqwest::Client::builder()
-                .default_headers(default_headers.clone())
-                // .proxy(Proxy::all("http://123.128.12.87:30001").unwrap())
-                .cookie_provider(cookie_store)
-                .timeout(Duration::from_secs(30))
-                .build()
-                .unwrap()
-        };
-        Account { client }
+        for product_name in product_names {
+            let product_name = product_name.as_ref().trim().to_owned();
+            if product_name.is_empty() || !seen.insert(product_name.clone()) {
+                continue;
+            }
+
+            let account = self.clone();
+            let semaphore = semaphore.clone();
+            let timeout = options.timeout;
+
+            tasks.push((
+                product_name.clone(),
+                tokio::spawn(async move {
+                    let _permit = semaphore.acquire_owned().await;
+                    match timeout {
+                        Some(timeout) => {
+                            match tokio::time::timeout(
+                                timeout,
+                                account.get_inventory(&product_name),
+                            )
+                            .await
+                            {
+                                Ok(v) => v,
+                                Err(_) => {
+                                    error!("获取 {} 库存超时", product_name);
+                                    Err(AccountError::Timeout(timeout))
+                                }
+                            }
+                        }
+                        None => account.get_inventory(&product_name).await,
+                    }
+                }),
+            ));
+        }
+
+        // 任务崩溃或者被取消的产品也要有结果，调用方才知道它没查到
+        let mut results = HashMap::new();
+        for (product_name, task) in tasks {
+            let res = match task.await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("批量获取 {} 库存的任务出错:{}", product_name, e);
+                    Err(AccountError::Task(e.to_string()))
+                }
+            };
+            results.insert(product_name, res);
+        }
+
+        results
+    }
+
+    /// 产品在 ti.com 商店的购买页面
+    pub fn product_url(&self, product_name: &str) -> String {
+        match self.base_url.join("store/ti/en/p/product/") {
+            Ok(mut url) => {
+                url.query_pairs_mut().append_pair("p", product_name);
+                url.to_string()
+            }
+            Err(_) => self.base_url.to_string(),
+        }
+    }
+
+    /// 当前的限速和熔断状态
+    pub fn throttle_state(&self) -> ThrottleState {
+        self.throttle.state()
     }
 
-    pub async fn get_store_by_product_name(&self, product_name: &str) -> Result<usize, String> {
+    /// 获取产品的库存信息
+    ///
+    /// 请求会先经过限速器排队，熔断中会直接返回 [`AccountError::CircuitOpen`]。
+    pub async fn get_inventory(&self, product_name: &str) -> Result<Inventory, AccountError> {
+        let permit = self.throttle.acquire().await?;
+        let res = self.fetch_inventory(product_name).await;
+        permit.finish(&res);
+        res
+    }
+
+    async fn fetch_inventory(&self, product_name: &str) -> Result<Inventory, AccountError> {
         debug!("正在获取产品库存:{}", product_name);
 
-        let res = match self
-            .client
-            .get(format!(
-                "https://www.ti.com/storeservices/cart/opninventory?opn={}",
-                product_name
-            ))
-            .send()
-            .await
-        {
+        let mut url = match self.base_url.join("storeservices/cart/opninventory") {
             Ok(v) => v,
             Err(e) => {
-                error!("获取库存出错:{}", e);
-                return Err(format!("{}", e));
+                return Err(AccountError::Config(format!("{}", e)));
             }
         };
+        url.query_pairs_mut().append_pair("opn", product_name);
 
-        let text = match res.text().await {
+        let res = match self.client.get(url).send().await {
             Ok(v) => v,
             Err(e) => {
-                error!("获取库存返回的html出错:{}", e);
-                return Err(format!("{}", e));
+                error!("获取库存出错:{}", e);
+                return Err(AccountError::Network(e));
             }
         };
 
-        let store: Store = match serde_json::from_str(&text) {
+        let status = res.status();
+        let retry_after = res
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let is_html = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.contains("text/html"))
+            .unwrap_or(false);
+
+        let text = match res.text().await {
             Ok(v) => v,
             Err(e) => {
-                error!("json解析库存返回的内容出错:{}", e);
-                return Err(format!("{}", e));
+                error!("获取库存返回的html出错:{}", e);
+                return Err(AccountError::Network(e));
             }
         };
 
+        let store = Self::parse_inventory(product_name, status, retry_after, is_html, &text)?;
+
         debug!(
             "获取产品：{} 的库存数:{:#?}",
             store.orderable_number, store.inventory
         );
 
-        Ok(store.inventory)
+        Ok(store)
+    }
+
+    /// 根据状态码和返回内容判断是哪种错误，没有错误就解析出库存信息
+    fn parse_inventory(
+        product_name: &str,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        is_html: bool,
+        text: &str,
+    ) -> Result<Inventory, AccountError> {
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => {
+                error!("获取 {} 库存被限流了", product_name);
+                return Err(AccountError::RateLimited { retry_after });
+            }
+            StatusCode::FORBIDDEN => {
+                error!("获取 {} 库存被拦截了", product_name);
+                return Err(AccountError::Blocked(status));
+            }
+            StatusCode::NOT_FOUND | StatusCode::BAD_REQUEST => {
+                return Err(AccountError::UnknownPart(product_name.to_owned()));
+            }
+            _ => {}
+        }
+
+        if !status.is_success() {
+            error!("获取 {} 库存返回状态码:{}", product_name, status);
+            return Err(AccountError::Status(status));
+        }
+
+        // 正常情况接口返回json，返回html说明被反爬虫页面拦下来了
+        if is_html || text.trim_start().starts_with('<') {
+            error!("获取 {} 库存返回了html页面，可能被拦截了", product_name);
+            return Err(AccountError::Blocked(status));
+        }
+
+        let value: serde_json::Value = match serde_json::from_str(text) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("json解析库存返回的内容出错:{}", e);
+                return Err(AccountError::Schema(format!("{}", e)));
+            }
+        };
+
+        // 型号不存在的时候接口返回的 orderable_number 是空的
+        let opn = value.get("orderable_number").and_then(|v| v.as_str());
+        if opn.map(|v| v.trim().is_empty()).unwrap_or(true) {
+            return Err(AccountError::UnknownPart(product_name.to_owned()));
+        }
+
+        match serde_json::from_value(value) {
+            Ok(v) => Ok(v),
+            Err(e) => {
+                error!("json解析库存返回的内容出错:{}", e);
+                Err(AccountError::Schema(format!("{}", e)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(status: u16, is_html: bool, text: &str) -> Result<Inventory, AccountError> {
+        Account::parse_inventory(
+            "OPA1622IDRCR",
+            StatusCode::from_u16(status).unwrap(),
+            Some(Duration::from_secs(30)),
+            is_html,
+            text,
+        )
+    }
+
+    #[test]
+    fn parse_error_status() {
+        assert!(matches!(
+            parse(429, false, ""),
+            Err(AccountError::RateLimited {
+                retry_after: Some(v)
+            }) if v == Duration::from_secs(30)
+        ));
+        assert!(matches!(
+            parse(403, false, ""),
+            Err(AccountError::Blocked(StatusCode::FORBIDDEN))
+        ));
+        for status in [404, 400] {
+            assert!(matches!(
+                parse(status, false, ""),
+                Err(AccountError::UnknownPart(v)) if v == "OPA1622IDRCR"
+            ));
+        }
+        assert!(matches!(
+            parse(500, false, "{}"),
+            Err(AccountError::Status(StatusCode::INTERNAL_SERVER_ERROR))
+        ));
+    }
+
+    #[test]
+    fn parse_unexpected_body() {
+        // 反爬虫页面
+        assert!(matches!(
+            parse(200, true, "{}"),
+            Err(AccountError::Blocked(StatusCode::OK))
+        ));
+        assert!(matches!(
+            parse(200, false, "  <html></html>"),
+            Err(AccountError::Blocked(StatusCode::OK))
+        ));
+        assert!(matches!(
+            parse(200, false, "not json"),
+            Err(AccountError::Schema(_))
+        ));
+        assert!(matches!(
+            parse(
+                200,
+                false,
+                r#"{"orderable_number":"OPA1622IDRCR","inventory":"many"}"#
+            ),
+            Err(AccountError::Schema(_))
+        ));
+        // 型号不存在的时候 orderable_number 是空的
+        assert!(matches!(
+            parse(200, false, r#"{"orderable_number":" ","inventory":0}"#),
+            Err(AccountError::UnknownPart(_))
+        ));
+        assert!(matches!(
+            parse(200, false, r#"{"inventory":0}"#),
+            Err(AccountError::UnknownPart(_))
+        ));
+    }
+
+    #[test]
+    fn parse_inventory_json() {
+        let text = r#"{
+            "orderable_number": "OPA1622IDRCR",
+            "inventory": 620,
+            "lifecycle": "ACTIVE",
+            "status": "IN_STOCK",
+            "leadtime": 6,
+            "purchase_limit": 5000,
+            "limit": 10,
+            "pricing": [{
+                "currency": "USD",
+                "priceBreaks": [
+                    {"priceBreakQuantity": 1, "price": 1.5},
+                    {"priceBreakQuantity": 1000, "price": 0.9}
+                ]
+            }]
+        }"#;
+        let inventory = parse(200, false, text).unwrap();
+        assert_eq!(inventory.orderable_number, "OPA1622IDRCR");
+        assert_eq!(inventory.inventory, 620);
+        assert_eq!(inventory.lifecycle_status.as_deref(), Some("ACTIVE"));
+        assert_eq!(inventory.purchase_limit, Some(5000));
+        // 别的字段名不会当成已知字段，原样保存
+        assert_eq!(inventory.extra["status"], "IN_STOCK");
+        assert_eq!(inventory.extra["limit"], 10);
+        // 数字的货期转成文字
+        assert_eq!(inventory.lead_time.as_deref(), Some("6"));
+        assert_eq!(inventory.pricing[0].currency, "USD");
+        assert_eq!(inventory.pricing[0].price_breaks[1].quantity, 1000);
+        assert_eq!(inventory.pricing[0].price_breaks[1].price, 0.9);
     }
 }
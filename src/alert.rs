@@ -0,0 +1,633 @@
+//! 什么时候发库存提醒
+//!
+//! 每个产品可以设置几条 [`AlertRule`]，没有设置就是 `>=1`，也就是有库存的时候提醒。
+//! 规则只在库存变化到满足条件的那一次提醒，例如 `>=500` 在库存从 300 变成 600 的时候提醒一次，
+//! 一直在 500 以上不会重复提醒，降到 500 以下再涨上来才会再提醒。设置了重新提醒间隔的话，
+//! 库存一直满足上次提醒的规则，过了这段时间会再提醒一次。
+//!
+//! 每个产品的库存和提醒记录保存在 [`DEFAULT_ALERT_STATE_PATH`]，程序重启之后不会把有库存的产品重新提醒一遍。
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use log::error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// 默认的提醒记录文件
+pub const DEFAULT_ALERT_STATE_PATH: &str = "./alert_state.json";
+
+/// 一条提醒规则
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertRule {
+    /// `>=N`: 库存达到 N 个
+    AtLeast(usize),
+    /// `<N`: 库存降到 N 个以下
+    Below(usize),
+    /// `+N`: 比上次提醒之后的最低库存多了 N 个
+    IncreaseBy(usize),
+    /// `+N%`: 比上次提醒之后的最低库存多了 N%，原来是 0 的话有库存就算
+    IncreasePercent(u32),
+    /// `=0`: 卖完了
+    OutOfStock,
+}
+
+/// 没有设置规则的产品用的规则，有库存就提醒
+pub const DEFAULT_RULES: [AlertRule; 1] = [AlertRule::AtLeast(1)];
+
+impl AlertRule {
+    /// 库存从 `previous` 变成 `stock` 的时候要不要提醒，`low` 是上次提醒之后的最低库存，
+    /// 第一次查询的时候 `previous` 和 `low` 都是 `None`
+    fn triggered(&self, previous: Option<usize>, low: Option<usize>, stock: usize) -> bool {
+        match *self {
+            AlertRule::AtLeast(n) => stock >= n && previous.map(|v| v < n).unwrap_or(true),
+            AlertRule::Below(n) => stock < n && previous.map(|v| v >= n).unwrap_or(false),
+            AlertRule::IncreaseBy(n) => low.map(|v| stock >= v.saturating_add(n)).unwrap_or(false),
+            AlertRule::IncreasePercent(n) => match low {
+                Some(0) => stock > 0,
+                Some(v) => stock as u128 * 100 >= v as u128 * (100 + n as u128),
+                None => false,
+            },
+            AlertRule::OutOfStock => stock == 0 && previous.map(|v| v > 0).unwrap_or(false),
+        }
+    }
+
+    /// 按这条规则提醒之后库存是不是还满足条件，满足的话过了重新提醒间隔要再提醒，
+    /// 缺货的提醒不重复
+    fn still_holds(&self, stock: usize) -> bool {
+        match *self {
+            AlertRule::AtLeast(n) => stock >= n,
+            AlertRule::IncreaseBy(_) | AlertRule::IncreasePercent(_) => stock > 0,
+            AlertRule::Below(_) | AlertRule::OutOfStock => false,
+        }
+    }
+
+    /// 现在的库存是不是满足这条规则，提醒没发出去的时候用来判断要不要重新提醒
+    fn holds(&self, low: Option<usize>, stock: usize) -> bool {
+        match *self {
+            AlertRule::AtLeast(n) => stock >= n,
+            AlertRule::Below(n) => stock < n,
+            AlertRule::OutOfStock => stock == 0,
+            AlertRule::IncreaseBy(_) | AlertRule::IncreasePercent(_) => {
+                self.triggered(None, low, stock)
+            }
+        }
+    }
+
+    /// 提醒的原因，显示在通知的标题里
+    pub fn reason(&self, previous: Option<usize>, low: Option<usize>, stock: usize) -> String {
+        match *self {
+            AlertRule::AtLeast(1) => format!("有 {} 个新库存", stock),
+            AlertRule::AtLeast(n) => format!("库存达到 {}，现在有 {} 个", n, stock),
+            AlertRule::Below(n) => format!("库存降到 {} 以下，现在有 {} 个", n, stock),
+            AlertRule::IncreaseBy(_) | AlertRule::IncreasePercent(_) => {
+                let base = low.or(previous).unwrap_or(0);
+                format!(
+                    "库存增加了 {}，现在有 {} 个",
+                    stock.saturating_sub(base),
+                    stock
+                )
+            }
+            AlertRule::OutOfStock => "已经卖完了".to_owned(),
+        }
+    }
+}
+
+impl FromStr for AlertRule {
+    type Err = String;
+
+    /// 解析 `>=500`、`>499`、`<100`、`<=99`、`+100`、`+20%`、`=0`，符号和数字之间可以有空格
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (op, value) = match s.find(|c: char| c.is_ascii_digit()) {
+            Some(i) => (s[..i].trim(), s[i..].trim()),
+            None => return Err(format!("提醒规则 {} 没有数量", s)),
+        };
+        let (value, percent) = match value.strip_suffix('%') {
+            Some(v) => (v.trim(), true),
+            None => (value, false),
+        };
+        let n: usize = match value.parse() {
+            Ok(v) => v,
+            Err(_) => return Err(format!("提醒规则 {} 的数量不正确", s)),
+        };
+
+        // `>N` 就是 `>=N+1`，`<=N` 就是 `<N+1`
+        let next = || match n.checked_add(1) {
+            Some(v) => Ok(v),
+            None => Err(format!("提醒规则 {} 的数量太大", s)),
+        };
+
+        let rule = match (op, percent) {
+            (">=", false) => AlertRule::AtLeast(n),
+            (">", false) => AlertRule::AtLeast(next()?),
+            ("<", false) => AlertRule::Below(n),
+            ("<=", false) => AlertRule::Below(next()?),
+            ("+", false) => AlertRule::IncreaseBy(n),
+            ("+", true) => match u32::try_from(n) {
+                Ok(v) => AlertRule::IncreasePercent(v),
+                Err(_) => return Err(format!("提醒规则 {} 的百分比太大", s)),
+            },
+            ("=", false) | ("==", false) if n == 0 => AlertRule::OutOfStock,
+            ("=", false) | ("==", false) => {
+                return Err(format!("提醒规则 {} 不支持，只能用 =0 表示卖完", s))
+            }
+            _ => {
+                return Err(format!(
+                    "提醒规则 {} 不认识，只支持 >=N、<N、+N、+N%、=0",
+                    s
+                ))
+            }
+        };
+
+        match rule {
+            AlertRule::AtLeast(0) | AlertRule::Below(0) => {
+                Err(format!("提醒规则 {} 永远不会触发", s))
+            }
+            AlertRule::IncreaseBy(0) | AlertRule::IncreasePercent(0) => {
+                Err(format!("提醒规则 {} 的增加量不能是 0", s))
+            }
+            v => Ok(v),
+        }
+    }
+}
+
+/// 转回 [`AlertRule::from_str`] 能解析的格式
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlertRule::AtLeast(n) => write!(f, ">={}", n),
+            AlertRule::Below(n) => write!(f, "<{}", n),
+            AlertRule::IncreaseBy(n) => write!(f, "+{}", n),
+            AlertRule::IncreasePercent(n) => write!(f, "+{}%", n),
+            AlertRule::OutOfStock => write!(f, "=0"),
+        }
+    }
+}
+
+/// 配置文件里写成字符串
+impl Serialize for AlertRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AlertRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// 一次提醒
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alert {
+    /// 触发的规则
+    pub rule: AlertRule,
+    /// 上次查到的库存，第一次查询是 `None`
+    pub previous: Option<usize>,
+    /// 提醒的原因，见 [`AlertRule::reason`]
+    pub reason: String,
+    /// 库存一直满足上次提醒的规则，过了重新提醒间隔再提醒一次
+    pub repeat: bool,
+}
+
+/// 一个产品的库存和提醒记录
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct ProductState {
+    /// 上次查到的库存
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last: Option<usize>,
+    /// 上次提醒之后的最低库存，`+N`、`+N%` 和它比较
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    low: Option<usize>,
+    /// 上次提醒的时间，unix 时间戳(秒)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notified_at: Option<u64>,
+    /// 上次提醒时的库存
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notified_stock: Option<usize>,
+    /// 上次提醒触发的规则
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notified_rule: Option<AlertRule>,
+    /// 没发出去的提醒的规则，下次查询还满足的话重新提醒
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unsent_rule: Option<AlertRule>,
+}
+
+/// 记录每个产品的库存变化，按规则判断要不要提醒
+///
+/// 用 [`AlertTracker::load`] 创建的话，[`AlertTracker::save`] 会把记录写回文件。
+#[derive(Debug, Clone, Default)]
+pub struct AlertTracker {
+    products: BTreeMap<String, ProductState>,
+    /// 每个产品最近一次提醒之前的记录，提醒没发出去的时候恢复提醒记录
+    before_alert: BTreeMap<String, ProductState>,
+    /// 保存记录的文件，`None` 只保存在内存里
+    path: Option<PathBuf>,
+    /// 记录有修改还没保存
+    dirty: bool,
+    /// 库存一直满足上次提醒的规则时，隔多久再提醒一次，`None` 不再提醒
+    renotify: Option<Duration>,
+}
+
+impl AlertTracker {
+    /// 只保存在内存里的记录
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 读取保存在文件里的记录，文件不存在或者格式不对就从空的开始
+    pub fn load<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let products = match fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("提醒记录 {} 格式不正确，已忽略:{}", path.display(), e);
+                    BTreeMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                error!("读取提醒记录 {} 出错:{}", path.display(), e);
+                BTreeMap::new()
+            }
+        };
+        AlertTracker {
+            products,
+            before_alert: BTreeMap::new(),
+            path: Some(path),
+            dirty: false,
+            renotify: None,
+        }
+    }
+
+    /// 设置重新提醒的间隔
+    pub fn set_renotify(&mut self, renotify: Option<Duration>) {
+        self.renotify = renotify;
+    }
+
+    /// 有修改的话写回文件，先写到临时文件再改名
+    pub fn save(&mut self) {
+        let path = match &self.path {
+            Some(v) if self.dirty => v,
+            _ => return,
+        };
+        let text = match serde_json::to_string_pretty(&self.products) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("保存提醒记录出错:{}", e);
+                return;
+            }
+        };
+        let tmp = path.with_extension("json.tmp");
+        if let Err(e) = fs::write(&tmp, text).and_then(|_| fs::rename(&tmp, path)) {
+            error!("保存提醒记录 {} 出错:{}", path.display(), e);
+            return;
+        }
+        self.dirty = false;
+    }
+
+    /// 记录这次查到的库存，有规则触发的话返回第一条触发的规则，`rules` 是空的就用 [`DEFAULT_RULES`]。
+    /// `now` 是查询的时间，用来判断有没有过重新提醒间隔
+    pub fn update(
+        &mut self,
+        opn: &str,
+        rules: &[AlertRule],
+        stock: usize,
+        now: DateTime<Local>,
+    ) -> Option<Alert> {
+        let rules = if rules.is_empty() {
+            &DEFAULT_RULES[..]
+        } else {
+            rules
+        };
+        let now = now.timestamp().max(0) as u64;
+        let state = self.products.entry(opn.to_owned()).or_default();
+        let before = *state;
+        let ProductState { last, low, .. } = before;
+
+        // 上次的提醒没发出去，库存还满足那条规则就重新提醒
+        let mut alert = rules
+            .iter()
+            .find(|v| v.triggered(last, low, stock))
+            .or_else(|| {
+                state
+                    .unsent_rule
+                    .as_ref()
+                    .filter(|v| rules.contains(v) && v.holds(low, stock))
+            })
+            .map(|rule| Alert {
+                rule: *rule,
+                previous: last,
+                reason: rule.reason(last, low, stock),
+                repeat: false,
+            });
+        state.unsent_rule = None;
+
+        // 库存一直满足上次提醒的规则，而且规则还在，过了间隔再提醒一次
+        if let (None, Some(renotify), Some(rule), Some(notified_at)) = (
+            &alert,
+            self.renotify,
+            state.notified_rule,
+            state.notified_at,
+        ) {
+            if rules.contains(&rule)
+                && rule.still_holds(stock)
+                && now.saturating_sub(notified_at) >= renotify.as_secs()
+            {
+                alert = Some(Alert {
+                    rule,
+                    previous: last,
+                    reason: format!("还有 {} 个库存", stock),
+                    repeat: true,
+                });
+            }
+        }
+
+        state.last = Some(stock);
+        state.low = match (&alert, low) {
+            (None, Some(low)) => Some(low.min(stock)),
+            _ => Some(stock),
+        };
+        if let Some(alert) = &alert {
+            state.notified_at = Some(now);
+            state.notified_stock = Some(stock);
+            state.notified_rule = Some(alert.rule);
+            self.before_alert.insert(opn.to_owned(), before);
+        }
+        if *state != before {
+            self.dirty = true;
+        }
+        alert
+    }
+
+    /// 最近一次提醒没发出去，恢复成提醒之前的提醒记录，上次查到的库存不变，
+    /// 下次查询库存还满足这次提醒的规则的话重新提醒
+    pub fn unsent(&mut self, opn: &str) {
+        let (before, state) = match (self.before_alert.remove(opn), self.products.get_mut(opn)) {
+            (Some(before), Some(state)) => (before, state),
+            _ => return,
+        };
+        state.unsent_rule = state.notified_rule;
+        state.low = before.low;
+        state.notified_at = before.notified_at;
+        state.notified_stock = before.notified_stock;
+        state.notified_rule = before.notified_rule;
+        self.dirty = true;
+    }
+
+    /// 上次查到的库存
+    pub fn last(&self, opn: &str) -> Option<usize> {
+        self.products.get(opn).and_then(|v| v.last)
+    }
+
+    /// 忘掉这个产品的库存变化，下次查询的时候当成第一次查询，例如产品从列表里删掉了
+    pub fn forget(&mut self, opn: &str) {
+        self.before_alert.remove(opn);
+        if self.products.remove(opn).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// 只保留 `keep` 返回 `true` 的产品，例如启动的时候去掉已经不在产品列表里的
+    pub fn retain<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        let count = self.products.len();
+        self.products.retain(|k, _| keep(k));
+        self.before_alert.retain(|k, _| keep(k));
+        if self.products.len() != count {
+            self.dirty = true;
+        }
+    }
+
+    /// 现在有库存的产品，按型号排序
+    pub fn in_stock(&self) -> Vec<(String, usize)> {
+        self.products
+            .iter()
+            .filter_map(|(k, v)| match v.last {
+                Some(stock) if stock > 0 => Some((k.clone(), stock)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn time(secs: i64) -> DateTime<Local> {
+        Local.timestamp_opt(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    #[test]
+    fn parse_rules() {
+        let cases = [
+            (">=500", AlertRule::AtLeast(500)),
+            ("> 499", AlertRule::AtLeast(500)),
+            ("<100", AlertRule::Below(100)),
+            ("<= 99", AlertRule::Below(100)),
+            ("+100", AlertRule::IncreaseBy(100)),
+            ("+ 20 %", AlertRule::IncreasePercent(20)),
+            ("=0", AlertRule::OutOfStock),
+            ("== 0", AlertRule::OutOfStock),
+        ];
+        for (text, rule) in cases {
+            assert_eq!(text.parse::<AlertRule>(), Ok(rule), "{}", text);
+            // 转回文字再解析还是同一条规则
+            assert_eq!(rule.to_string().parse::<AlertRule>(), Ok(rule));
+        }
+
+        for text in [
+            ">=",
+            "abc",
+            ">=0",
+            "<0",
+            "+0",
+            "+0%",
+            "=5",
+            "~5",
+            ">=5%",
+            "+99999999999%",
+        ] {
+            assert!(text.parse::<AlertRule>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rules_trigger_on_crossing() {
+        let at_least = AlertRule::AtLeast(500);
+        assert!(at_least.triggered(None, None, 600));
+        assert!(at_least.triggered(Some(300), Some(300), 600));
+        assert!(!at_least.triggered(Some(550), Some(550), 600));
+        assert!(!at_least.triggered(Some(300), Some(300), 400));
+
+        let below = AlertRule::Below(100);
+        assert!(!below.triggered(None, None, 50));
+        assert!(below.triggered(Some(100), Some(100), 50));
+        assert!(!below.triggered(Some(80), Some(80), 50));
+
+        let increase = AlertRule::IncreaseBy(100);
+        assert!(!increase.triggered(None, None, 500));
+        assert!(increase.triggered(Some(150), Some(50), 150));
+        assert!(!increase.triggered(Some(100), Some(100), 199));
+
+        let percent = AlertRule::IncreasePercent(20);
+        assert!(percent.triggered(Some(0), Some(0), 1));
+        assert!(percent.triggered(Some(100), Some(100), 120));
+        assert!(!percent.triggered(Some(100), Some(100), 119));
+
+        let out = AlertRule::OutOfStock;
+        assert!(!out.triggered(None, None, 0));
+        assert!(out.triggered(Some(3), Some(3), 0));
+        assert!(!out.triggered(Some(0), Some(0), 0));
+    }
+
+    #[test]
+    fn tracker_alerts_once_until_renotify() {
+        let mut tracker = AlertTracker::new();
+        assert!(tracker.update("A", &[], 0, time(0)).is_none());
+
+        let alert = tracker.update("A", &[], 5, time(60)).unwrap();
+        assert_eq!(alert.rule, AlertRule::AtLeast(1));
+        assert_eq!(alert.previous, Some(0));
+        assert_eq!(alert.reason, "有 5 个新库存");
+        assert!(!alert.repeat);
+
+        // 一直有库存不会重复提醒
+        assert!(tracker.update("A", &[], 8, time(120)).is_none());
+        assert_eq!(tracker.last("A"), Some(8));
+        assert_eq!(tracker.in_stock(), vec![("A".to_owned(), 8)]);
+
+        // 过了重新提醒间隔再提醒一次
+        tracker.set_renotify(Some(Duration::from_secs(3600)));
+        assert!(tracker.update("A", &[], 8, time(1800)).is_none());
+        let alert = tracker.update("A", &[], 8, time(3660)).unwrap();
+        assert!(alert.repeat);
+        assert!(tracker.update("A", &[], 8, time(3720)).is_none());
+
+        // 卖完再补货又会提醒
+        assert!(tracker.update("A", &[], 0, time(3780)).is_none());
+        assert!(tracker.update("A", &[], 3, time(3840)).is_some());
+
+        // 忘掉之后当成第一次查询
+        tracker.forget("A");
+        assert_eq!(tracker.last("A"), None);
+        assert!(tracker.update("A", &[], 3, time(3900)).is_some());
+    }
+
+    #[test]
+    fn tracker_uses_first_matching_rule() {
+        let rules = [
+            AlertRule::AtLeast(500),
+            AlertRule::OutOfStock,
+            AlertRule::IncreaseBy(100),
+        ];
+        let mut tracker = AlertTracker::new();
+        assert!(tracker.update("A", &rules, 100, time(0)).is_none());
+        assert_eq!(
+            tracker.update("A", &rules, 250, time(60)).unwrap().rule,
+            AlertRule::IncreaseBy(100)
+        );
+        // 从上次提醒之后的最低库存算起
+        assert!(tracker.update("A", &rules, 300, time(120)).is_none());
+        assert_eq!(
+            tracker.update("A", &rules, 600, time(180)).unwrap().rule,
+            AlertRule::AtLeast(500)
+        );
+        assert_eq!(
+            tracker.update("A", &rules, 0, time(240)).unwrap().rule,
+            AlertRule::OutOfStock
+        );
+    }
+
+    #[test]
+    fn tracker_alerts_again_when_unsent() {
+        let rules = [AlertRule::OutOfStock, AlertRule::IncreaseBy(100)];
+        let mut tracker = AlertTracker::new();
+        assert!(tracker.update("A", &rules, 5, time(0)).is_none());
+        assert_eq!(
+            tracker.update("A", &rules, 0, time(60)).unwrap().rule,
+            AlertRule::OutOfStock
+        );
+
+        // 没发出去，库存还是 0 就再提醒一次，上次查到的库存不变
+        tracker.unsent("A");
+        assert_eq!(tracker.last("A"), Some(0));
+        let alert = tracker.update("A", &rules, 0, time(120)).unwrap();
+        assert_eq!(alert.rule, AlertRule::OutOfStock);
+        assert_eq!(alert.previous, Some(0));
+        assert!(!alert.repeat);
+        assert!(tracker.update("A", &rules, 0, time(180)).is_none());
+
+        // 库存已经不满足了就不再提醒
+        assert!(tracker.update("A", &rules, 50, time(240)).is_none());
+        assert_eq!(
+            tracker.update("A", &rules, 0, time(300)).unwrap().rule,
+            AlertRule::OutOfStock
+        );
+        tracker.unsent("A");
+        assert!(tracker.update("A", &rules, 20, time(360)).is_none());
+
+        // 增加的规则从提醒之前的最低库存算起
+        assert_eq!(
+            tracker.update("A", &rules, 150, time(420)).unwrap().rule,
+            AlertRule::IncreaseBy(100)
+        );
+        tracker.unsent("A");
+        assert_eq!(
+            tracker.update("A", &rules, 140, time(480)).unwrap().rule,
+            AlertRule::IncreaseBy(100)
+        );
+    }
+
+    #[test]
+    fn tracker_saves_to_file() {
+        let dir = std::env::temp_dir().join(format!("ti-alert-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("alert_state.json");
+
+        let mut tracker = AlertTracker::load(&path);
+        assert!(tracker.update("A", &[], 5, time(0)).is_some());
+        tracker.save();
+
+        // 重启之后不会重新提醒
+        let mut tracker = AlertTracker::load(&path);
+        assert_eq!(tracker.last("A"), Some(5));
+        assert!(tracker.update("A", &[], 5, time(60)).is_none());
+        tracker.retain(|opn| opn != "A");
+        tracker.save();
+        assert_eq!(AlertTracker::load(&path).last("A"), None);
+    }
+
+    #[test]
+    fn huge_numbers_do_not_overflow() {
+        let max = usize::MAX;
+        assert_eq!(
+            format!(">={}", max).parse::<AlertRule>(),
+            Ok(AlertRule::AtLeast(max))
+        );
+        assert!(format!(">{}", max).parse::<AlertRule>().is_err());
+        assert!(format!("<={}", max).parse::<AlertRule>().is_err());
+        assert_eq!(
+            format!(">{}", max - 1).parse::<AlertRule>(),
+            Ok(AlertRule::AtLeast(max))
+        );
+
+        let rule = AlertRule::IncreaseBy(max);
+        assert!(!rule.triggered(Some(10), Some(10), 100));
+        assert!(rule.triggered(Some(0), Some(0), max));
+
+        let rule = AlertRule::IncreasePercent(u32::MAX);
+        assert!(!rule.triggered(Some(max), Some(max), max));
+    }
+}
@@ -0,0 +1,1188 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{info, warn};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::account::{Account, AccountBuilder, BatchOptions};
+use crate::error::AccountError;
+use crate::history::DEFAULT_HISTORY_PATH;
+use crate::mail::{address_list, parse_address_list, Recipients, SmtpAuth, SmtpSecurity};
+use crate::notify::WebhookKind;
+use crate::products::{opn_matches, parse_product_list, ProductEntry};
+use crate::schedule::{Schedule, MAX_INTERVAL_SECS, MAX_JITTER_SECS};
+use crate::template::{AlertTemplates, Template, DEFAULT_SUBJECT_TEMPLATE};
+use crate::vault::{Vault, VaultError, PASSPHRASE_ENV};
+
+/// 当前配置文件的版本，格式有不兼容的修改时加一
+pub const CONFIG_VERSION: u32 = 1;
+
+/// 默认的配置文件路径
+pub const DEFAULT_CONFIG_PATH: &str = "./config.toml";
+
+/// 设置了这个环境变量就用它作为发件箱密码，不会保存到配置文件
+pub const SMTP_PASSWORD_ENV: &str = "TI_SMTP_PASSWORD";
+
+/// 请求和命令的超时时间最多一小时
+pub const MAX_TIMEOUT_SECS: u64 = 60 * 60;
+
+/// 重复提醒的间隔最多 30 天
+pub const MAX_RENOTIFY_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// 库存历史最多保留 10 年
+pub const MAX_RETENTION_DAYS: u32 = 10 * 365;
+
+/// 配置文件，保存成 toml 格式
+///
+/// ```toml
+/// version = 1
+///
+/// [[products]]
+/// opn = "OPA1622IDRCR"
+///
+/// [[products]]
+/// opn = "TPS7A4701RGWR"
+/// interval = "5m"
+///
+/// [polling]
+/// interval_secs = 60
+/// jitter_secs = 10
+///
+/// [smtp]
+/// host = "smtp.qq.com"
+/// port = 465
+/// security = "tls"
+/// from = "sender@qq.com"
+/// password_encrypted = "enc:v1:..."
+/// remember_password = true
+///
+/// [notifiers.email]
+/// enabled = true
+/// to = ["receiver@qq.com"]
+///
+/// [[notifiers.email.routes]]
+/// products = ["TPS*"]
+/// to = ["power@example.com"]
+///
+/// [[notifiers.webhook]]
+/// url = "https://example.com/hook"
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// 配置文件版本
+    pub version: u32,
+    /// 监控的产品
+    pub products: Vec<ProductEntry>,
+    /// 轮询设置
+    pub polling: Schedule,
+    /// 发邮件用的邮箱
+    pub smtp: SmtpConfig,
+    /// 通知渠道
+    pub notifiers: NotifiersConfig,
+    /// 库存提醒的模板
+    pub templates: TemplatesConfig,
+    /// 提醒设置
+    pub alerts: AlertsConfig,
+    /// 库存历史
+    pub history: HistoryConfig,
+    /// 网络设置
+    pub network: NetworkConfig,
+    /// 日志设置
+    pub logging: LoggingConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            version: CONFIG_VERSION,
+            products: vec![],
+            polling: Schedule::default(),
+            smtp: SmtpConfig::default(),
+            notifiers: NotifiersConfig::default(),
+            templates: TemplatesConfig::default(),
+            alerts: AlertsConfig::default(),
+            history: HistoryConfig::default(),
+            network: NetworkConfig::default(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+/// 发件邮箱
+///
+/// 密码加密之后保存在 `password_encrypted`，读取配置文件时解密到 `password`。
+/// 旧的配置文件里明文的 `password` 也能读取，下次保存时会换成加密的。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SmtpConfig {
+    /// SMTP 服务器地址
+    pub host: String,
+    /// SMTP 服务器端口
+    pub port: u16,
+    /// 加密方式: tls、starttls、none
+    pub security: SmtpSecurity,
+    /// 登录方式: auto、plain、login
+    pub auth: SmtpAuth,
+    /// 发件人显示的名字，不设置就只显示邮箱地址
+    pub display_name: String,
+    /// 发件箱账号
+    pub from: String,
+    /// 发件箱密码或者授权码，不会明文写到配置文件里
+    #[serde(skip_serializing)]
+    pub password: String,
+    /// 加密后的密码
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_encrypted: Option<String>,
+    /// 是否把密码加密保存到配置文件，不保存的话每次启动都要重新输入，
+    /// 或者用环境变量 [`SMTP_PASSWORD_ENV`] 提供
+    pub remember_password: bool,
+    /// 密码来自环境变量 [`SMTP_PASSWORD_ENV`]
+    #[serde(skip)]
+    pub password_from_env: bool,
+    /// 加密的密码解密失败了，没有重新输入密码的话保存时保留原来加密的密码
+    #[serde(skip)]
+    pub password_locked: bool,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        // 默认用 QQ 邮箱，和旧版本一样
+        SmtpConfig {
+            host: "smtp.qq.com".to_owned(),
+            port: 465,
+            security: SmtpSecurity::Tls,
+            auth: SmtpAuth::Auto,
+            display_name: String::new(),
+            from: String::new(),
+            password: String::new(),
+            password_encrypted: None,
+            remember_password: true,
+            password_from_env: false,
+            password_locked: false,
+        }
+    }
+}
+
+impl SmtpConfig {
+    /// 得到明文密码，`env_password` 是环境变量里的密码，它优先，其次是配置文件里的明文密码，
+    /// 最后解密加密的密码。解密失败只记录日志，密码留空，不影响读取其它配置。
+    fn unlock(&mut self, vault: &Vault, env_password: Option<&str>) {
+        if let Some(v) = env_password.filter(|v| !v.is_empty()) {
+            self.password = v.to_owned();
+            self.password_from_env = true;
+            return;
+        }
+
+        if !self.password.is_empty() {
+            return;
+        }
+
+        if let Some(encrypted) = &self.password_encrypted {
+            match vault.decrypt(encrypted) {
+                Ok(v) => self.password = v,
+                Err(e) => {
+                    warn!("{}，请重新输入发件箱密码", e);
+                    self.password_locked = true;
+                }
+            }
+        }
+    }
+
+    /// 准备保存到配置文件，按设置加密密码或者清掉
+    fn seal(&mut self, vault: &Vault) -> Result<(), VaultError> {
+        // 密码来自环境变量的时候，配置文件里原来的密码保持不变
+        if self.password_from_env {
+            return Ok(());
+        }
+
+        if !self.remember_password {
+            self.password_encrypted = None;
+            return Ok(());
+        }
+        if self.password.is_empty() {
+            // 解密失败又没有重新输入密码，留着原来加密的密码，换回原来的密钥还能解密
+            if !self.password_locked {
+                self.password_encrypted = None;
+            }
+            return Ok(());
+        }
+
+        // 密码没变就不重新加密，避免每次保存配置文件都变
+        if let Some(encrypted) = &self.password_encrypted {
+            if vault.decrypt(encrypted).ok().as_ref() == Some(&self.password) {
+                return Ok(());
+            }
+        }
+        self.password_encrypted = Some(vault.encrypt(&self.password)?);
+        Ok(())
+    }
+
+    /// 配置文件里是不是还有明文密码
+    fn has_plaintext_password(&self) -> bool {
+        !self.password_from_env && !self.password.is_empty() && self.password_encrypted.is_none()
+    }
+}
+
+/// 所有的通知渠道
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotifiersConfig {
+    pub email: EmailNotifierConfig,
+    pub desktop: DesktopNotifierConfig,
+    // 空的列表会写成 `webhook = []`，toml 里普通的值不能放在表后面
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub webhook: Vec<WebhookNotifierConfig>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<CommandNotifierConfig>,
+}
+
+/// 邮件通知
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmailNotifierConfig {
+    pub enabled: bool,
+    /// 收件人，没有匹配到 `routes` 的产品发给他们
+    #[serde(
+        deserialize_with = "address_list::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub to: Vec<String>,
+    /// 抄送
+    #[serde(
+        deserialize_with = "address_list::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub cc: Vec<String>,
+    /// 密送
+    #[serde(
+        deserialize_with = "address_list::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub bcc: Vec<String>,
+    /// 按产品型号发给不同的收件人
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<EmailRoute>,
+}
+
+impl Default for EmailNotifierConfig {
+    fn default() -> Self {
+        EmailNotifierConfig {
+            enabled: true,
+            to: vec![],
+            cc: vec![],
+            bcc: vec![],
+            routes: vec![],
+        }
+    }
+}
+
+impl EmailNotifierConfig {
+    /// 默认的收件人
+    pub fn recipients(&self) -> Recipients {
+        Recipients {
+            to: self.to.clone(),
+            cc: self.cc.clone(),
+            bcc: self.bcc.clone(),
+        }
+    }
+
+    pub fn set_recipients(&mut self, recipients: Recipients) {
+        self.to = recipients.to;
+        self.cc = recipients.cc;
+        self.bcc = recipients.bcc;
+    }
+}
+
+/// 把符合条件的产品的邮件通知发给指定的收件人
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmailRoute {
+    /// 型号，可以用 `*`、`?` 通配，例如 `TPS7A*`
+    #[serde(deserialize_with = "pattern_list")]
+    pub products: Vec<String>,
+    #[serde(
+        deserialize_with = "address_list::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub to: Vec<String>,
+    #[serde(
+        deserialize_with = "address_list::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub cc: Vec<String>,
+    #[serde(
+        deserialize_with = "address_list::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub bcc: Vec<String>,
+}
+
+impl EmailRoute {
+    pub fn matches(&self, opn: &str) -> bool {
+        self.products.iter().any(|v| opn_matches(v, opn))
+    }
+
+    pub fn recipients(&self) -> Recipients {
+        Recipients {
+            to: self.to.clone(),
+            cc: self.cc.clone(),
+            bcc: self.bcc.clone(),
+        }
+    }
+}
+
+/// `products` 可以只写一个型号
+fn pattern_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(v) => vec![v],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+/// 桌面通知
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DesktopNotifierConfig {
+    pub enabled: bool,
+}
+
+/// 把通知用 JSON POST 到一个地址
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookNotifierConfig {
+    /// 渠道名字，不设置就是 `webhook-序号`
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    pub enabled: bool,
+    /// 类型: generic、dingtalk、wecom、feishu、slack
+    pub kind: WebhookKind,
+    pub url: String,
+    /// 钉钉加签、飞书签名校验的密钥
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// 额外的请求头，例如 `Authorization`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Default for WebhookNotifierConfig {
+    fn default() -> Self {
+        WebhookNotifierConfig {
+            name: String::new(),
+            enabled: true,
+            kind: WebhookKind::Generic,
+            url: String::new(),
+            secret: None,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+impl WebhookNotifierConfig {
+    /// 渠道名字，`index` 是在配置文件里的序号，从 0 开始
+    pub fn channel_name(&self, index: usize) -> String {
+        if self.name.is_empty() {
+            format!("webhook-{}", index + 1)
+        } else {
+            self.name.clone()
+        }
+    }
+}
+
+/// 执行本地命令
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CommandNotifierConfig {
+    /// 渠道名字，不设置就是 `command-序号`
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    pub enabled: bool,
+    /// 要执行的程序
+    pub program: String,
+    /// 程序的参数
+    pub args: Vec<String>,
+    /// 超过这么多秒还没结束就算失败
+    pub timeout_secs: u64,
+}
+
+impl Default for CommandNotifierConfig {
+    fn default() -> Self {
+        CommandNotifierConfig {
+            name: String::new(),
+            enabled: true,
+            program: String::new(),
+            args: vec![],
+            timeout_secs: 30,
+        }
+    }
+}
+
+impl CommandNotifierConfig {
+    /// 渠道名字，`index` 是在配置文件里的序号，从 0 开始
+    pub fn channel_name(&self, index: usize) -> String {
+        if self.name.is_empty() {
+            format!("command-{}", index + 1)
+        } else {
+            self.name.clone()
+        }
+    }
+}
+
+/// 库存提醒的模板，语法见 [`crate::template`]
+///
+/// 纯文字模板用在桌面通知、webhook、命令和邮件的纯文字部分，html 模板只用在邮件里。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TemplatesConfig {
+    /// 标题模板
+    pub subject: String,
+    /// 纯文字模板文件，不设置就用内置的
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<PathBuf>,
+    /// html 模板文件，不设置就用内置的
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub html: Option<PathBuf>,
+}
+
+impl Default for TemplatesConfig {
+    fn default() -> Self {
+        TemplatesConfig {
+            subject: DEFAULT_SUBJECT_TEMPLATE.to_owned(),
+            text: None,
+            html: None,
+        }
+    }
+}
+
+impl TemplatesConfig {
+    /// 读取并解析模板文件
+    pub fn load(&self) -> Result<AlertTemplates, ConfigError> {
+        let mut templates = AlertTemplates::default();
+
+        let invalid = |name: &str, e| ConfigError::Invalid(vec![format!("{}:{}", name, e)]);
+        templates.subject =
+            Template::text(&self.subject).map_err(|e| invalid("templates.subject", e))?;
+
+        let read = |path: &Path| match fs::read_to_string(path) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(ConfigError::Io {
+                path: path.to_owned(),
+                source: e,
+            }),
+        };
+        if let Some(path) = &self.text {
+            let name = format!("模板 {}", path.display());
+            templates.text = Template::text(&read(path)?).map_err(|e| invalid(&name, e))?;
+        }
+        if let Some(path) = &self.html {
+            let name = format!("模板 {}", path.display());
+            templates.html = Template::html(&read(path)?).map_err(|e| invalid(&name, e))?;
+        }
+
+        Ok(templates)
+    }
+}
+
+/// 提醒设置，每个产品的提醒规则写在产品列表里
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    /// 库存一直满足上次提醒的规则时，隔多久再提醒一次，写成 `30m`、`6h` 这样，不设置就不再提醒
+    #[serde(
+        with = "crate::products::interval_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub renotify: Option<Duration>,
+}
+
+/// 库存历史，每次查询的结果保存在 SQLite 数据库里
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// 是否保存库存历史
+    pub enabled: bool,
+    /// 数据库文件
+    pub path: PathBuf,
+    /// 保留多少天的记录，0 表示一直保留
+    pub retention_days: u32,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: true,
+            path: PathBuf::from(DEFAULT_HISTORY_PATH),
+            retention_days: 90,
+        }
+    }
+}
+
+impl HistoryConfig {
+    /// 记录保留多久，`None` 表示一直保留
+    pub fn retention(&self) -> Option<Duration> {
+        match self.retention_days {
+            0 => None,
+            v => Some(Duration::from_secs(u64::from(v) * 24 * 60 * 60)),
+        }
+    }
+}
+
+/// 访问 ti.com 的网络设置
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// 代理地址，例如 `http://127.0.0.1:1080`
+    pub proxy: Option<String>,
+    /// 单个请求的超时秒数
+    pub timeout_secs: u64,
+    /// 最多同时查询几个产品
+    pub concurrency: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            proxy: None,
+            timeout_secs: 30,
+            concurrency: crate::account::DEFAULT_CONCURRENCY,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// 按照网络设置创建 [`AccountBuilder`]
+    pub fn account_builder(&self) -> AccountBuilder {
+        let builder = Account::builder().timeout(Duration::from_secs(self.timeout_secs));
+        match &self.proxy {
+            Some(proxy) => builder.proxy(proxy),
+            None => builder,
+        }
+    }
+
+    /// 按照网络设置创建 [`Account`]
+    pub fn account(&self) -> Result<Account, AccountError> {
+        self.account_builder().build()
+    }
+
+    /// 批量查询的参数，单个产品最多等两倍的请求超时时间
+    pub fn batch_options(&self) -> BatchOptions {
+        BatchOptions {
+            concurrency: self.concurrency,
+            timeout: Some(Duration::from_secs(self.timeout_secs.saturating_mul(2))),
+        }
+    }
+}
+
+/// 日志设置
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// 日志级别: off、error、warn、info、debug、trace
+    pub level: String,
+    /// 日志文件，不设置就不写文件
+    pub file: Option<String>,
+    /// 是否输出到控制台
+    pub stdout: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "debug".to_owned(),
+            file: Some("log/log.log".to_owned()),
+            stdout: true,
+        }
+    }
+}
+
+impl LoggingConfig {
+    pub fn level_filter(&self) -> Option<log::LevelFilter> {
+        self.level.trim().parse().ok()
+    }
+}
+
+/// 读取或者保存配置文件出错
+#[derive(Debug)]
+pub enum ConfigError {
+    /// 读写文件失败
+    Io { path: PathBuf, source: io::Error },
+    /// 文件不是正确的 toml，或者字段类型不对
+    Parse { path: PathBuf, message: String },
+    /// 配置文件的版本比程序支持的新
+    UnsupportedVersion(u32),
+    /// 内容不合法，每一条是一个问题
+    Invalid(Vec<String>),
+    /// 加密密码失败
+    Vault(VaultError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "读写配置文件 {} 出错:{}", path.display(), source)
+            }
+            ConfigError::Parse { path, message } => {
+                write!(f, "配置文件 {} 格式不正确:{}", path.display(), message)
+            }
+            ConfigError::UnsupportedVersion(v) => write!(
+                f,
+                "配置文件版本是 {}，当前程序只支持到 {}，请升级程序",
+                v, CONFIG_VERSION
+            ),
+            ConfigError::Invalid(errors) => write!(f, "配置不正确:{}", errors.join("；")),
+            ConfigError::Vault(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Vault(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// 旧版本保存配置用的文件
+const LEGACY_EMAIL_FILE: &str = "email.txt";
+const LEGACY_PRODUCTS_FILE: &str = "products.txt";
+
+/// 从环境变量读取的发件箱密码和密钥口令，测试的时候直接传进去
+#[derive(Debug, Clone, Default)]
+struct Secrets {
+    /// [`SMTP_PASSWORD_ENV`] 里的发件箱密码
+    smtp_password: Option<String>,
+    /// [`PASSPHRASE_ENV`] 里的口令
+    passphrase: Option<String>,
+}
+
+impl Secrets {
+    fn from_env() -> Self {
+        Secrets {
+            smtp_password: std::env::var(SMTP_PASSWORD_ENV).ok(),
+            passphrase: std::env::var(PASSPHRASE_ENV).ok(),
+        }
+    }
+
+    fn vault(&self, config_path: &Path) -> Vault {
+        Vault::for_config(config_path).passphrase(self.passphrase.clone())
+    }
+}
+
+impl Config {
+    /// 读取并检查配置文件，加密的密码会解密好
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        Self::load_with(path.as_ref(), &Secrets::from_env())
+    }
+
+    fn load_with(path: &Path, secrets: &Secrets) -> Result<Config, ConfigError> {
+        let text = match fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ConfigError::Io {
+                    path: path.to_owned(),
+                    source: e,
+                })
+            }
+        };
+        let mut config = Self::parse(path, &text)?;
+        config
+            .smtp
+            .unlock(&secrets.vault(path), secrets.smtp_password.as_deref());
+        Ok(config)
+    }
+
+    fn parse(path: &Path, text: &str) -> Result<Config, ConfigError> {
+        let config: Config = match toml::from_str(text) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ConfigError::Parse {
+                    path: path.to_owned(),
+                    message: format!("{}", e),
+                })
+            }
+        };
+
+        if config.version > CONFIG_VERSION {
+            return Err(ConfigError::UnsupportedVersion(config.version));
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 读取配置文件，配置文件不存在的话从旧版本的 email.txt、products.txt 迁移过来，
+    /// 都没有就用默认配置。返回的 `bool` 表示是否做了迁移。
+    pub fn load_or_migrate<P: AsRef<Path>>(path: P) -> Result<(Config, bool), ConfigError> {
+        Self::load_or_migrate_with(path.as_ref(), &Secrets::from_env())
+    }
+
+    fn load_or_migrate_with(path: &Path, secrets: &Secrets) -> Result<(Config, bool), ConfigError> {
+        if path.exists() {
+            let config = Self::load_with(path, secrets)?;
+            if config.smtp.has_plaintext_password() {
+                // 马上保存一次，把明文密码换成加密的
+                config.save_with(path, secrets)?;
+                info!("配置文件 {} 里已经不再保存明文密码", path.display());
+                return Ok((config, true));
+            }
+            return Ok((config, false));
+        }
+
+        let dir = match path.parent() {
+            Some(v) if !v.as_os_str().is_empty() => v.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        match Self::from_legacy(&dir)? {
+            Some((config, email_migrated)) => {
+                config.save_with(path, secrets)?;
+                // 新的配置文件保存成功之后才动旧文件，把 products.txt 改名，避免下次又迁移一次。
+                // email.txt 里是明文密码，迁移成功的话密码已经加密保存了，直接删掉；
+                // 没能迁移的留着，免得用户保存的账号密码丢了
+                if email_migrated {
+                    let legacy = dir.join(LEGACY_EMAIL_FILE);
+                    if let Err(e) = fs::remove_file(&legacy) {
+                        warn!("删除 {} 出错:{}", legacy.display(), e);
+                    }
+                }
+                let legacy = dir.join(LEGACY_PRODUCTS_FILE);
+                if legacy.exists() {
+                    let _ = fs::rename(&legacy, dir.join(format!("{}.bak", LEGACY_PRODUCTS_FILE)));
+                }
+                info!("已经把旧的配置文件迁移到 {}", path.display());
+                Ok((config, true))
+            }
+            None => Ok((Config::default(), false)),
+        }
+    }
+
+    /// 从旧版本的 email.txt、products.txt 读取配置，一个都没有就返回 `None`，
+    /// 另外返回 email.txt 有没有迁移成功
+    fn from_legacy(dir: &Path) -> Result<Option<(Config, bool)>, ConfigError> {
+        let read = |name: &str| -> Result<Option<String>, ConfigError> {
+            let path = dir.join(name);
+            if !path.exists() {
+                return Ok(None);
+            }
+            match fs::read_to_string(&path) {
+                Ok(v) => Ok(Some(v)),
+                Err(e) => Err(ConfigError::Io { path, source: e }),
+            }
+        };
+
+        let email = read(LEGACY_EMAIL_FILE)?;
+        let products = read(LEGACY_PRODUCTS_FILE)?;
+        if email.is_none() && products.is_none() {
+            return Ok(None);
+        }
+
+        let mut config = Config::default();
+
+        // email.txt 三行分别是发件箱账号、密码、收件箱账号，可能是 CRLF 换行，最后可能有空行
+        let mut email_migrated = false;
+        if let Some(email) = email {
+            let mut t: Vec<&str> = email.lines().map(str::trim).collect();
+            while t.last() == Some(&"") {
+                t.pop();
+            }
+            if t.len() == 3 {
+                config.smtp.from = t[0].to_owned();
+                config.smtp.password = t[1].to_owned();
+                config.notifiers.email.to = parse_address_list(t[2]);
+                email_migrated = true;
+            } else {
+                warn!(
+                    "{} 应该是发件箱账号、密码、收件箱账号三行，现在有 {} 行，没有迁移，请在界面上重新填写",
+                    dir.join(LEGACY_EMAIL_FILE).display(),
+                    t.len()
+                );
+            }
+        }
+
+        // 旧的产品列表里格式不对的行直接丢掉
+        if let Some(products) = products {
+            config.products = parse_product_list(&products).0;
+        }
+
+        Ok(Some((config, email_migrated)))
+    }
+
+    /// 保存配置文件，先写到临时文件再改名，写到一半出错不会把原来的配置弄坏。
+    /// 密码加密之后再保存，密钥文件放在配置文件旁边。
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        self.save_with(path.as_ref(), &Secrets::from_env())
+    }
+
+    fn save_with(&self, path: &Path, secrets: &Secrets) -> Result<(), ConfigError> {
+        let mut config = self.clone();
+        if let Err(e) = config.smtp.seal(&secrets.vault(path)) {
+            return Err(ConfigError::Vault(e));
+        }
+        let text = match toml::to_string_pretty(&config) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(ConfigError::Parse {
+                    path: path.to_owned(),
+                    message: format!("{}", e),
+                })
+            }
+        };
+
+        let tmp = path.with_extension("toml.tmp");
+        let res = fs::write(&tmp, text).and_then(|_| fs::rename(&tmp, path));
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(ConfigError::Io {
+                path: path.to_owned(),
+                source: e,
+            }),
+        }
+    }
+
+    /// 邮件的收件规则，产品列表里单独设置的收件人排在配置文件里的规则前面
+    pub fn email_routes(&self) -> Vec<EmailRoute> {
+        let mut routes: Vec<EmailRoute> = self
+            .products
+            .iter()
+            .filter(|v| !v.recipients().is_empty())
+            .map(|v| EmailRoute {
+                products: vec![v.opn.clone()],
+                to: v.to.clone(),
+                cc: v.cc.clone(),
+                bcc: v.bcc.clone(),
+            })
+            .collect();
+        routes.extend(self.notifiers.email.routes.iter().cloned());
+        routes
+    }
+
+    /// 检查配置是否合法，把所有问题一次性列出来
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = vec![];
+
+        for (i, product) in self.products.iter().enumerate() {
+            let opn = product.opn.trim();
+            if opn.is_empty() {
+                errors.push(format!("第 {} 个产品的型号是空的", i + 1));
+            } else if opn.contains(char::is_whitespace) {
+                errors.push(format!("产品型号 {} 里面不能有空格", opn));
+            }
+            if self.products[..i].iter().any(|v| v.opn == product.opn) {
+                errors.push(format!("产品 {} 重复了", opn));
+            }
+            match product.interval {
+                Some(v) if v.as_secs() == 0 => {
+                    errors.push(format!("产品 {} 的查询间隔不能是 0", opn));
+                }
+                Some(v) if v.as_secs() > MAX_INTERVAL_SECS => {
+                    errors.push(format!(
+                        "产品 {} 的查询间隔不能超过 {} 秒",
+                        opn, MAX_INTERVAL_SECS
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        match self.alerts.renotify {
+            Some(v) if v.as_secs() == 0 => errors.push("alerts.renotify 不能是 0".to_owned()),
+            Some(v) if v.as_secs() > MAX_RENOTIFY_SECS => {
+                errors.push(format!("alerts.renotify 不能超过 {} 秒", MAX_RENOTIFY_SECS))
+            }
+            _ => {}
+        }
+        if self.history.retention_days > MAX_RETENTION_DAYS {
+            errors.push(format!(
+                "history.retention_days 不能超过 {}",
+                MAX_RETENTION_DAYS
+            ));
+        }
+
+        if self.polling.interval_secs == 0 {
+            errors.push("polling.interval_secs 不能是 0".to_owned());
+        } else if self.polling.interval_secs > MAX_INTERVAL_SECS {
+            errors.push(format!(
+                "polling.interval_secs 不能超过 {}",
+                MAX_INTERVAL_SECS
+            ));
+        }
+        if self.polling.jitter_secs > MAX_JITTER_SECS {
+            errors.push(format!("polling.jitter_secs 不能超过 {}", MAX_JITTER_SECS));
+        }
+        for (opn, secs) in &self.polling.overrides {
+            if *secs == 0 {
+                errors.push(format!("polling.overrides 里 {} 的查询间隔不能是 0", opn));
+            } else if *secs > MAX_INTERVAL_SECS {
+                errors.push(format!(
+                    "polling.overrides 里 {} 的查询间隔不能超过 {} 秒",
+                    opn, MAX_INTERVAL_SECS
+                ));
+            }
+        }
+        if let Some(quiet_hours) = &self.polling.quiet_hours {
+            if let Err(e) = quiet_hours.validate() {
+                errors.push(format!("polling.quiet_hours:{}", e));
+            }
+        }
+
+        if self.smtp.host.trim().is_empty() {
+            errors.push("smtp.host 不能是空的".to_owned());
+        }
+        if self.smtp.port == 0 {
+            errors.push("smtp.port 不能是 0".to_owned());
+        }
+        if !self.smtp.from.is_empty() && !self.smtp.from.contains('@') {
+            errors.push(format!("发件箱账号 {} 不是邮箱地址", self.smtp.from));
+        }
+        let email = &self.notifiers.email;
+        let mut recipients = email.recipients();
+        for (i, route) in email.routes.iter().enumerate() {
+            if route.products.iter().all(|v| v.trim().is_empty()) {
+                errors.push(format!("第 {} 条邮件收件规则没有设置 products", i + 1));
+            }
+            if route.recipients().is_empty() {
+                errors.push(format!("第 {} 条邮件收件规则没有设置收件人", i + 1));
+            }
+            recipients.merge(&route.recipients());
+        }
+        for product in &self.products {
+            recipients.merge(&product.recipients());
+        }
+        for address in recipients.addresses() {
+            if !address.contains('@') {
+                errors.push(format!("收件人 {} 不是邮箱地址", address));
+            }
+        }
+
+        let mut channels = vec!["email".to_owned(), "desktop".to_owned()];
+        for (i, webhook) in self.notifiers.webhook.iter().enumerate() {
+            let name = webhook.channel_name(i);
+            if let Err(e) = Url::parse(&webhook.url) {
+                errors.push(format!(
+                    "webhook {} 的地址 {} 不正确:{}",
+                    name, webhook.url, e
+                ));
+            }
+            let signed = matches!(webhook.kind, WebhookKind::DingTalk | WebhookKind::Feishu);
+            if webhook.secret.is_some() && !signed {
+                errors.push(format!("webhook {} 的类型不支持 secret", name));
+            }
+            channels.push(name);
+        }
+        for (i, command) in self.notifiers.command.iter().enumerate() {
+            let name = command.channel_name(i);
+            if command.program.trim().is_empty() {
+                errors.push(format!("命令 {} 没有设置 program", name));
+            }
+            if command.timeout_secs == 0 {
+                errors.push(format!("命令 {} 的 timeout_secs 不能是 0", name));
+            } else if command.timeout_secs > MAX_TIMEOUT_SECS {
+                errors.push(format!(
+                    "命令 {} 的 timeout_secs 不能超过 {}",
+                    name, MAX_TIMEOUT_SECS
+                ));
+            }
+            channels.push(name);
+        }
+        for (i, name) in channels.iter().enumerate() {
+            if channels[..i].contains(name) {
+                errors.push(format!("通知渠道的名字 {} 重复了", name));
+            }
+        }
+
+        if let Err(e) = Template::text(&self.templates.subject) {
+            errors.push(format!("templates.subject:{}", e));
+        }
+
+        if let Some(proxy) = &self.network.proxy {
+            if let Err(e) = Url::parse(proxy) {
+                errors.push(format!("代理地址 {} 不正确:{}", proxy, e));
+            }
+        }
+        if self.network.timeout_secs == 0 {
+            errors.push("network.timeout_secs 不能是 0".to_owned());
+        } else if self.network.timeout_secs > MAX_TIMEOUT_SECS {
+            errors.push(format!(
+                "network.timeout_secs 不能超过 {}",
+                MAX_TIMEOUT_SECS
+            ));
+        }
+        if self.network.concurrency == 0 {
+            errors.push("network.concurrency 不能是 0".to_owned());
+        }
+
+        if self.logging.level_filter().is_none() {
+            errors.push(format!(
+                "日志级别 {} 不正确，只能是 off、error、warn、info、debug、trace",
+                self.logging.level
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 每个测试用自己的临时目录
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ti-config-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn migrates_legacy_files_with_crlf_and_trailing_newline() {
+        let dir = temp_dir("migrate");
+        fs::write(
+            dir.join(LEGACY_EMAIL_FILE),
+            "sender@qq.com\r\nsecret\r\na@example.com,b@example.com\r\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join(LEGACY_PRODUCTS_FILE),
+            "OPA1622IDRCR\nTPS7A4701RGWR @5m\n",
+        )
+        .unwrap();
+
+        let path = dir.join("config.toml");
+        let (config, migrated) = Config::load_or_migrate_with(&path, &Secrets::default()).unwrap();
+        assert!(migrated);
+        assert_eq!(config.smtp.from, "sender@qq.com");
+        assert_eq!(config.smtp.password, "secret");
+        assert_eq!(
+            config.notifiers.email.to,
+            vec!["a@example.com".to_owned(), "b@example.com".to_owned()]
+        );
+        assert_eq!(config.products.len(), 2);
+        assert_eq!(
+            config.products[1].interval,
+            Some(Duration::from_secs(5 * 60))
+        );
+
+        // 明文密码的 email.txt 删掉，其它旧文件改名
+        assert!(path.exists());
+        assert!(!dir.join(LEGACY_EMAIL_FILE).exists());
+        assert!(!dir.join(LEGACY_PRODUCTS_FILE).exists());
+        assert!(dir.join("products.txt.bak").exists());
+
+        // 配置文件里的密码是加密的，读回来是一样的
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(!text.contains("secret"));
+        let (loaded, migrated) = Config::load_or_migrate_with(&path, &Secrets::default()).unwrap();
+        assert!(!migrated);
+        assert_eq!(loaded.smtp.password, "secret");
+    }
+
+    #[test]
+    fn keeps_unparsable_email_file() {
+        let dir = temp_dir("bad-email");
+        fs::write(dir.join(LEGACY_EMAIL_FILE), "sender@qq.com\nsecret\n").unwrap();
+        fs::write(dir.join(LEGACY_PRODUCTS_FILE), "OPA1622IDRCR\n").unwrap();
+
+        let path = dir.join("config.toml");
+        let (config, migrated) = Config::load_or_migrate_with(&path, &Secrets::default()).unwrap();
+        assert!(migrated);
+        assert!(config.smtp.from.is_empty());
+        assert_eq!(config.products.len(), 1);
+        assert!(dir.join(LEGACY_EMAIL_FILE).exists());
+    }
+
+    #[test]
+    fn nothing_to_migrate() {
+        let dir = temp_dir("empty");
+        let path = dir.join("config.toml");
+        let (config, migrated) = Config::load_or_migrate_with(&path, &Secrets::default()).unwrap();
+        assert!(!migrated);
+        assert_eq!(config, Config::default());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rejects_oversized_durations() {
+        let mut config = Config::default();
+        config.polling.interval_secs = MAX_INTERVAL_SECS + 1;
+        config.polling.jitter_secs = MAX_JITTER_SECS + 1;
+        config.alerts.renotify = Some(Duration::from_secs(MAX_RENOTIFY_SECS + 1));
+        config.history.retention_days = MAX_RETENTION_DAYS + 1;
+        config.network.timeout_secs = u64::MAX;
+        config.notifiers.command.push(CommandNotifierConfig {
+            program: "notify-send".to_owned(),
+            timeout_secs: MAX_TIMEOUT_SECS + 1,
+            ..CommandNotifierConfig::default()
+        });
+        match config.validate() {
+            Err(ConfigError::Invalid(errors)) => assert_eq!(errors.len(), 6, "{:?}", errors),
+            v => panic!("{:?}", v),
+        }
+
+        // 不检查也不会溢出
+        assert_eq!(
+            config.network.batch_options().timeout,
+            Some(Duration::from_secs(u64::MAX))
+        );
+    }
+    #[test]
+    fn keeps_password_that_cannot_be_decrypted() {
+        let dir = temp_dir("locked");
+        let path = dir.join("config.toml");
+        let secrets = |passphrase: &str| Secrets {
+            smtp_password: None,
+            passphrase: Some(passphrase.to_owned()),
+        };
+        let mut config = Config::default();
+        config.smtp.password = "secret".to_owned();
+        config.save_with(&path, &secrets("a")).unwrap();
+        let encrypted = Config::load_with(&path, &secrets("a"))
+            .unwrap()
+            .smtp
+            .password_encrypted;
+        assert!(encrypted.is_some());
+
+        // 口令不对解密失败，没有重新输入密码就保存，原来加密的密码不会丢
+        let loaded = Config::load_with(&path, &secrets("b")).unwrap();
+        assert!(loaded.smtp.password.is_empty());
+        assert!(loaded.smtp.password_locked);
+        loaded.save_with(&path, &secrets("b")).unwrap();
+        let loaded = Config::load_with(&path, &secrets("a")).unwrap();
+        assert_eq!(loaded.smtp.password_encrypted, encrypted);
+        assert_eq!(loaded.smtp.password, "secret");
+
+        // 环境变量里的密码优先，也不会改动配置文件里的密码
+        let env = Secrets {
+            smtp_password: Some("from-env".to_owned()),
+            passphrase: Some("b".to_owned()),
+        };
+        let loaded = Config::load_with(&path, &env).unwrap();
+        assert_eq!(loaded.smtp.password, "from-env");
+        assert!(loaded.smtp.password_from_env);
+        loaded.save_with(&path, &env).unwrap();
+        let loaded = Config::load_with(&path, &secrets("a")).unwrap();
+        assert_eq!(loaded.smtp.password, "secret");
+    }
+}
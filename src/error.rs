@@ -0,0 +1,93 @@
+use std::fmt;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+
+/// 查询库存时可能出现的错误
+#[derive(Debug)]
+pub enum AccountError {
+    /// 创建客户端时配置不正确，例如接口地址或者代理地址写错了
+    Config(String),
+    /// 网络错误，连接失败、超时、读取返回内容失败等，一般重试就可以
+    Network(reqwest::Error),
+    /// 在规定的时间内没有查询完
+    Timeout(Duration),
+    /// 接口返回了非成功的http状态码
+    Status(StatusCode),
+    /// 请求太频繁被限流了(429)，`retry_after` 是服务器要求等待的时间
+    RateLimited { retry_after: Option<Duration> },
+    /// 被反爬虫拦截了，返回 403 或者返回了一个html页面而不是json
+    Blocked(StatusCode),
+    /// 连续失败或者被限流之后暂停请求中，还要等待这么久才会再次请求
+    CircuitOpen(Duration),
+    /// 产品型号不存在
+    UnknownPart(String),
+    /// 返回的json和预期的格式不一样，多半是接口改版了
+    Schema(String),
+    /// 批量查询时这个产品的查询任务崩溃或者被取消了
+    Task(String),
+}
+
+impl AccountError {
+    /// 是否值得稍后重试，型号不存在、配置错误这类重试也没用
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            AccountError::Network(_) | AccountError::Timeout(_) => true,
+            AccountError::Status(status) => status.is_server_error(),
+            AccountError::RateLimited { .. }
+            | AccountError::Blocked(_)
+            | AccountError::CircuitOpen(_)
+            | AccountError::Task(_) => true,
+            AccountError::Config(_) | AccountError::UnknownPart(_) | AccountError::Schema(_) => {
+                false
+            }
+        }
+    }
+
+    /// 是否是被 ti.com 限流或者拦截了，这种情况需要放慢请求速度
+    pub fn is_throttled(&self) -> bool {
+        matches!(
+            self,
+            AccountError::RateLimited { .. }
+                | AccountError::Blocked(_)
+                | AccountError::CircuitOpen(_)
+        )
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::Config(e) => write!(f, "配置错误:{}", e),
+            AccountError::Network(e) => write!(f, "网络错误:{}", e),
+            AccountError::Timeout(v) => write!(f, "查询超时，超过了 {} 秒", v.as_secs()),
+            AccountError::Status(status) => write!(f, "接口返回状态码:{}", status),
+            AccountError::RateLimited {
+                retry_after: Some(v),
+            } => write!(f, "请求太频繁被限流了，需要等待 {} 秒", v.as_secs()),
+            AccountError::RateLimited { retry_after: None } => write!(f, "请求太频繁被限流了"),
+            AccountError::Blocked(status) => write!(f, "请求被拦截了，状态码:{}", status),
+            AccountError::CircuitOpen(v) => {
+                write!(f, "请求失败太多暂停中，{} 秒后恢复", v.as_secs())
+            }
+            AccountError::UnknownPart(opn) => write!(f, "产品型号 {} 不存在", opn),
+            AccountError::Schema(e) => write!(f, "库存接口返回的格式不正确:{}", e),
+            AccountError::Task(e) => write!(f, "查询任务出错:{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AccountError::Network(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for AccountError {
+    fn from(e: reqwest::Error) -> Self {
+        AccountError::Network(e)
+    }
+}
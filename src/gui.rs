@@ -0,0 +1,1628 @@
+//! 图形界面，编译时打开了 `gui` 功能(默认打开)才有
+
+use chrono::{DateTime, Local};
+use eframe::{
+    egui::{
+        self,
+        plot::{Legend, Line, MarkerShape, Plot, Points, Value, Values},
+        FontDefinitions, FontFamily,
+    },
+    epi::{self, IconData},
+};
+use log::{error, info};
+use ti::{
+    config::{HistoryConfig, SmtpConfig, DEFAULT_CONFIG_PATH, SMTP_PASSWORD_ENV},
+    format_product_list,
+    history::restocks,
+    mail::{format_address_list, parse_address_list},
+    monitor::AccountSource,
+    schedule::{MAX_INTERVAL_SECS, MAX_JITTER_SECS},
+    Account, Config, History, Mailer, Monitor, MonitorCommand, MonitorEvent, MonitorSettings,
+    MonitorStatus, Notifier, ProductEntry, QuietHours, Reading, Recipients, Restock, Schedule,
+    SmtpAuth, SmtpSecurity,
+};
+
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+#[derive(Default)]
+struct TiApp {
+    // 启动时读取的配置，界面上没有的设置从这里原样保存回去
+    config: Config,
+    // 配置文件读取失败的原因，这种情况下退出时不保存，避免覆盖掉用户的配置文件
+    config_error: Option<String>,
+    // 监控的产品，停用的也在里面
+    products: Vec<ProductEntry>,
+    product_table: ProductTable,
+    // 用来生成产品在 ti.com 的网址
+    account: Option<Account>,
+    email_from: String,
+    email_from_password: String,
+    // 是否把密码加密保存到配置文件
+    remember_password: bool,
+    // 收件人、抄送、密送，多个地址用逗号分隔
+    email_to: String,
+    email_cc: String,
+    email_bcc: String,
+    smtp: SmtpForm,
+    schedule: ScheduleForm,
+    history_view: HistoryView,
+    log_text: String,
+    status: MonitorStatus,
+    reload: HotReload,
+    // 接受执行结果
+    reciver_product_count_log: Option<Receiver<String>>,
+    // 界面上的后台任务(例如发送测试邮件)把结果发到运行记录
+    sender_log: Option<Sender<String>>,
+    // 发送开始、暂停、继续、停止命令
+    sender_command: Option<tokio::sync::mpsc::UnboundedSender<MonitorCommand>>,
+    // 接收监控状态
+    receiver_status: Option<Receiver<MonitorStatus>>,
+    // 接收每个产品的查询结果
+    receiver_products: Option<Receiver<ProductStatus>>,
+}
+
+/// 界面上修改了产品列表或者邮箱配置，停止输入一会儿之后再同步到正在运行的监控，
+/// 避免输入到一半的型号被拿去查询
+#[derive(Default)]
+struct HotReload {
+    // 产品列表最后一次修改的时间
+    products_edited: Option<Instant>,
+    // 邮箱和通知配置最后一次修改的时间
+    notifiers_edited: Option<Instant>,
+    // 上次检查配置文件的时间
+    config_file_checked: Option<Instant>,
+    // 配置文件的修改时间，变了说明被其它程序改过
+    config_file_modified: Option<SystemTime>,
+}
+
+/// 停止输入多久之后同步修改
+const RELOAD_DELAY: Duration = Duration::from_millis(1500);
+
+/// 多久检查一次配置文件有没有被修改
+const CONFIG_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+fn file_modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|v| v.modified()).ok()
+}
+
+/// 界面上编辑的 SMTP 服务器设置
+#[derive(Default)]
+struct SmtpForm {
+    host: String,
+    port: String,
+    security: SmtpSecurity,
+    auth: SmtpAuth,
+    display_name: String,
+}
+
+impl SmtpForm {
+    fn from_config(smtp: &SmtpConfig) -> Self {
+        SmtpForm {
+            host: smtp.host.clone(),
+            port: smtp.port.to_string(),
+            security: smtp.security,
+            auth: smtp.auth,
+            display_name: smtp.display_name.clone(),
+        }
+    }
+
+    /// 把界面上的设置写到 `smtp` 里
+    fn apply(&self, smtp: &mut SmtpConfig) -> Result<(), String> {
+        let host = self.host.trim();
+        if host.is_empty() {
+            return Err("SMTP 服务器不能是空的".to_owned());
+        }
+        smtp.port = match self.port.trim().parse::<u16>() {
+            Ok(v) if v > 0 => v,
+            _ => return Err("SMTP 端口必须是 1 到 65535 之间的整数".to_owned()),
+        };
+        smtp.host = host.to_owned();
+        smtp.security = self.security;
+        smtp.auth = self.auth;
+        smtp.display_name = self.display_name.trim().to_owned();
+        Ok(())
+    }
+}
+
+/// 界面上编辑的轮询设置，输入框里都是文字，点击开始监控的时候再转成 [`Schedule`]
+#[derive(Default)]
+struct ScheduleForm {
+    interval_secs: String,
+    jitter_secs: String,
+    quiet_enabled: bool,
+    quiet_start: String,
+    quiet_end: String,
+    // 配置文件里单独设置间隔的产品，界面上不编辑，原样保留
+    overrides: HashMap<String, u64>,
+}
+
+impl ScheduleForm {
+    fn from_schedule(schedule: Schedule) -> Self {
+        let quiet_enabled = schedule.quiet_hours.is_some();
+        let quiet_hours = schedule.quiet_hours.unwrap_or(QuietHours {
+            start: "00:00".to_owned(),
+            end: "07:00".to_owned(),
+        });
+        ScheduleForm {
+            interval_secs: schedule.interval_secs.to_string(),
+            jitter_secs: schedule.jitter_secs.to_string(),
+            quiet_enabled,
+            quiet_start: quiet_hours.start,
+            quiet_end: quiet_hours.end,
+            overrides: schedule.overrides,
+        }
+    }
+
+    fn to_schedule(&self) -> Result<Schedule, String> {
+        let interval_secs = match self.interval_secs.trim().parse::<u64>() {
+            Ok(v) if v > 0 && v <= MAX_INTERVAL_SECS => v,
+            _ => {
+                return Err(format!(
+                    "查询间隔必须是 1 到 {} 之间的整数",
+                    MAX_INTERVAL_SECS
+                ))
+            }
+        };
+        let jitter_secs = match self.jitter_secs.trim().parse::<u64>() {
+            Ok(v) if v <= MAX_JITTER_SECS => v,
+            _ => {
+                return Err(format!(
+                    "随机抖动必须是 0 到 {} 之间的整数",
+                    MAX_JITTER_SECS
+                ))
+            }
+        };
+        let quiet_hours = if self.quiet_enabled {
+            let v = QuietHours {
+                start: self.quiet_start.trim().to_owned(),
+                end: self.quiet_end.trim().to_owned(),
+            };
+            v.validate()?;
+            Some(v)
+        } else {
+            None
+        };
+
+        Ok(Schedule {
+            interval_secs,
+            jitter_secs,
+            overrides: self.overrides.clone(),
+            quiet_hours,
+        })
+    }
+}
+
+/// 一个产品这次查询的结果，监控线程发给界面显示在产品表格里
+#[derive(Debug, Clone)]
+struct ProductStatus {
+    opn: String,
+    checked_at: DateTime<Local>,
+    result: CheckResult,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CheckResult {
+    /// 查到了库存，`previous` 是上次查到的库存
+    Stock {
+        stock: usize,
+        previous: Option<usize>,
+    },
+    /// 查询失败
+    Failed(String),
+    /// 型号不存在
+    Invalid,
+}
+
+/// 产品表格里一行的查询状态
+#[derive(Debug, Clone, Default)]
+struct ProductRow {
+    // 最近一次查到的库存
+    stock: Option<usize>,
+    // 最近一次查询的时间
+    checked_at: Option<DateTime<Local>>,
+    // 库存上次变化的时间和变化量
+    change: Option<(DateTime<Local>, i64)>,
+    // 最近一次查询失败的原因
+    error: Option<String>,
+    invalid: bool,
+}
+
+impl ProductRow {
+    fn apply(&mut self, checked_at: DateTime<Local>, result: CheckResult) {
+        self.checked_at = Some(checked_at);
+        match result {
+            CheckResult::Stock { stock, previous } => {
+                if let Some(previous) = previous.or(self.stock) {
+                    if previous != stock {
+                        self.change = Some((checked_at, stock as i64 - previous as i64));
+                    }
+                }
+                self.stock = Some(stock);
+                self.error = None;
+                self.invalid = false;
+            }
+            CheckResult::Failed(e) => self.error = Some(e),
+            CheckResult::Invalid => {
+                self.error = None;
+                self.invalid = true;
+            }
+        }
+    }
+
+    /// 状态的文字和颜色，排序的时候按顺序排
+    fn status(&self, enabled: bool) -> (u8, &'static str, egui::Color32) {
+        if !enabled {
+            (0, "已停用", egui::Color32::GRAY)
+        } else if self.invalid {
+            (1, "型号不存在", egui::Color32::from_rgb(200, 80, 80))
+        } else if self.error.is_some() {
+            (2, "出错", egui::Color32::from_rgb(200, 150, 0))
+        } else if self.checked_at.is_some() {
+            (3, "正常", egui::Color32::from_rgb(0, 160, 0))
+        } else {
+            (4, "未查询", egui::Color32::GRAY)
+        }
+    }
+}
+
+/// 产品表格按哪一列排序
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum ProductSort {
+    // 产品列表里的顺序
+    #[default]
+    None,
+    Opn,
+    Stock,
+    Checked,
+    Changed,
+    Status,
+}
+
+/// 产品表格只显示哪些产品
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum ProductFilter {
+    #[default]
+    All,
+    InStock,
+    OutOfStock,
+    Error,
+    Disabled,
+}
+
+impl ProductFilter {
+    const ALL: [ProductFilter; 5] = [
+        ProductFilter::All,
+        ProductFilter::InStock,
+        ProductFilter::OutOfStock,
+        ProductFilter::Error,
+        ProductFilter::Disabled,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            ProductFilter::All => "全部",
+            ProductFilter::InStock => "有库存",
+            ProductFilter::OutOfStock => "没库存",
+            ProductFilter::Error => "出错",
+            ProductFilter::Disabled => "已停用",
+        }
+    }
+
+    fn matches(self, entry: &ProductEntry, row: &ProductRow) -> bool {
+        match self {
+            ProductFilter::All => true,
+            ProductFilter::InStock => entry.enabled && row.stock.unwrap_or(0) > 0,
+            ProductFilter::OutOfStock => entry.enabled && row.stock == Some(0),
+            ProductFilter::Error => entry.enabled && (row.invalid || row.error.is_some()),
+            ProductFilter::Disabled => !entry.enabled,
+        }
+    }
+}
+
+/// 产品表格上的操作，界面画完之后再处理
+enum ProductAction {
+    /// 产品列表修改了
+    Edited,
+    /// 马上查询这个产品
+    CheckNow(String),
+    /// 在 ti.com 打开这个产品
+    Open(String),
+}
+
+/// 监控的产品表格，每行是一个产品和它最近一次的查询结果
+#[derive(Default)]
+struct ProductTable {
+    // 添加产品的输入框，格式和产品列表里的一行一样
+    new_product: String,
+    // 添加产品出错的原因
+    new_product_error: Option<String>,
+    // 只显示型号里有这些文字的产品
+    search: String,
+    filter: ProductFilter,
+    sort: ProductSort,
+    descending: bool,
+    rows: HashMap<String, ProductRow>,
+}
+
+impl ProductTable {
+    /// 开始监控之前先显示库存历史里最近一次的查询结果
+    fn load_history(&mut self, config: &HistoryConfig, products: &[ProductEntry]) {
+        if !config.enabled || !config.path.exists() {
+            return;
+        }
+        let history = match History::open(&config.path) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        for entry in products {
+            if let Ok(Some(reading)) = history.latest(&entry.opn) {
+                let result = match reading.inventory {
+                    Some(stock) => CheckResult::Stock {
+                        stock,
+                        previous: None,
+                    },
+                    None => CheckResult::Failed(reading.error.unwrap_or_default()),
+                };
+                self.update(ProductStatus {
+                    opn: reading.opn,
+                    checked_at: reading.time,
+                    result,
+                });
+            }
+        }
+    }
+
+    fn update(&mut self, status: ProductStatus) {
+        self.rows
+            .entry(status.opn)
+            .or_default()
+            .apply(status.checked_at, status.result);
+    }
+
+    /// 按当前的搜索、筛选和排序，返回要显示的产品在 `products` 里的下标
+    fn visible(&self, products: &[ProductEntry]) -> Vec<usize> {
+        let empty = ProductRow::default();
+        let search = self.search.trim().to_uppercase();
+        let row = |i: usize| self.rows.get(&products[i].opn).unwrap_or(&empty);
+        let mut list: Vec<usize> = (0..products.len())
+            .filter(|i| products[*i].opn.to_uppercase().contains(&search))
+            .filter(|i| self.filter.matches(&products[*i], row(*i)))
+            .collect();
+        match self.sort {
+            ProductSort::None => {}
+            ProductSort::Opn => list.sort_by(|a, b| products[*a].opn.cmp(&products[*b].opn)),
+            ProductSort::Stock => list.sort_by_key(|i| row(*i).stock),
+            ProductSort::Checked => list.sort_by_key(|i| row(*i).checked_at),
+            ProductSort::Changed => list.sort_by_key(|i| row(*i).change.map(|v| v.0)),
+            ProductSort::Status => list.sort_by_key(|i| row(*i).status(products[*i].enabled).0),
+        }
+        if self.descending {
+            list.reverse();
+        }
+        list
+    }
+
+    /// 列标题，点击按这一列排序，再点击一次倒过来
+    fn header(&mut self, ui: &mut egui::Ui, sort: ProductSort, name: &str) {
+        let text = match (self.sort == sort, self.descending) {
+            (true, false) => format!("{} ▲", name),
+            (true, true) => format!("{} ▼", name),
+            (false, _) => name.to_owned(),
+        };
+        if ui.selectable_label(self.sort == sort, text).clicked() {
+            if self.sort == sort {
+                self.descending = !self.descending;
+            } else {
+                self.sort = sort;
+                self.descending = false;
+            }
+        }
+    }
+
+    fn show(&mut self, ui: &mut egui::Ui, products: &mut Vec<ProductEntry>) -> Vec<ProductAction> {
+        let mut actions = vec![];
+
+        ui.horizontal(|ui| {
+            let input = ui
+                .add(egui::TextEdit::singleline(&mut self.new_product).desired_width(360.0))
+                .on_hover_text("型号后面可以跟选项，例如 OPA1622IDRCR >= 500 @5m");
+            let enter = input.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+            if ui.button("添加/修改").clicked() || enter {
+                match ProductEntry::parse(&self.new_product) {
+                    Ok(Some(entry)) => {
+                        // 已经有这个型号就换成新的选项
+                        match products.iter_mut().find(|v| v.opn == entry.opn) {
+                            Some(v) => {
+                                *v = ProductEntry {
+                                    enabled: v.enabled,
+                                    ..entry
+                                }
+                            }
+                            None => products.push(entry),
+                        }
+                        self.new_product.clear();
+                        self.new_product_error = None;
+                        actions.push(ProductAction::Edited);
+                    }
+                    Ok(None) => {}
+                    Err(e) => self.new_product_error = Some(e),
+                }
+            }
+        });
+        if let Some(e) = &self.new_product_error {
+            ui.colored_label(egui::Color32::from_rgb(200, 80, 80), e);
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("搜索:");
+            ui.add(egui::TextEdit::singleline(&mut self.search).desired_width(120.0));
+            for v in ProductFilter::ALL {
+                ui.selectable_value(&mut self.filter, v, v.label());
+            }
+        });
+
+        let visible = self.visible(products);
+        let now = Local::now();
+        let mut remove = None;
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::Grid::new("products")
+                .striped(true)
+                .num_columns(7)
+                .show(ui, |ui| {
+                    ui.label("启用");
+                    self.header(ui, ProductSort::Opn, "型号");
+                    self.header(ui, ProductSort::Stock, "库存");
+                    self.header(ui, ProductSort::Checked, "查询时间");
+                    self.header(ui, ProductSort::Changed, "上次变化");
+                    self.header(ui, ProductSort::Status, "状态");
+                    ui.label("操作");
+                    ui.end_row();
+
+                    let empty = ProductRow::default();
+                    for i in visible {
+                        let entry = &mut products[i];
+                        let row = self.rows.get(&entry.opn).unwrap_or(&empty);
+
+                        if ui.checkbox(&mut entry.enabled, "").changed() {
+                            actions.push(ProductAction::Edited);
+                        }
+                        // 点击型号把这一行放到输入框里修改
+                        if ui
+                            .selectable_label(false, &entry.opn)
+                            .on_hover_text(format!("{}\n点击修改", entry))
+                            .clicked()
+                        {
+                            self.new_product = entry.to_string();
+                        }
+                        ui.label(row.stock.map(|v| v.to_string()).unwrap_or_default());
+                        ui.label(
+                            row.checked_at
+                                .map(|v| format_time(v, now))
+                                .unwrap_or_default(),
+                        );
+                        ui.label(
+                            row.change
+                                .map(|(t, v)| format!("{:+} {}", v, format_time(t, now)))
+                                .unwrap_or_default(),
+                        );
+                        let (_, text, color) = row.status(entry.enabled);
+                        let label = ui.colored_label(color, text);
+                        if let Some(e) = &row.error {
+                            label.on_hover_text(e);
+                        }
+                        ui.horizontal(|ui| {
+                            if ui.small_button("查询").clicked() {
+                                actions.push(ProductAction::CheckNow(entry.opn.clone()));
+                            }
+                            if ui.small_button("网页").clicked() {
+                                actions.push(ProductAction::Open(entry.opn.clone()));
+                            }
+                            if ui.small_button("删除").clicked() {
+                                remove = Some(i);
+                            }
+                        });
+                        ui.end_row();
+                    }
+                });
+        });
+
+        if let Some(i) = remove {
+            let entry = products.remove(i);
+            self.rows.remove(&entry.opn);
+            actions.push(ProductAction::Edited);
+        }
+        actions
+    }
+}
+
+/// 今天的时间只显示时分秒
+fn format_time(time: DateTime<Local>, now: DateTime<Local>) -> String {
+    if time.date_naive() == now.date_naive() {
+        time.format("%H:%M:%S").to_string()
+    } else {
+        time.format("%m-%d %H:%M").to_string()
+    }
+}
+
+/// 用默认浏览器打开网页
+fn open_url(url: &str) -> std::io::Result<()> {
+    let mut command = if cfg!(windows) {
+        let mut v = std::process::Command::new("rundll32");
+        v.arg("url.dll,FileProtocolHandler");
+        v
+    } else if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    command.arg(url).spawn().map(|_| ())
+}
+
+/// 库存历史窗口能选的时间范围
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum HistoryWindow {
+    Day,
+    #[default]
+    Week,
+    Month,
+    All,
+}
+
+impl HistoryWindow {
+    const ALL: [HistoryWindow; 4] = [
+        HistoryWindow::Day,
+        HistoryWindow::Week,
+        HistoryWindow::Month,
+        HistoryWindow::All,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            HistoryWindow::Day => "24 小时",
+            HistoryWindow::Week => "7 天",
+            HistoryWindow::Month => "30 天",
+            HistoryWindow::All => "全部",
+        }
+    }
+
+    fn since(self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            HistoryWindow::Day => Some(now - chrono::Duration::days(1)),
+            HistoryWindow::Week => Some(now - chrono::Duration::days(7)),
+            HistoryWindow::Month => Some(now - chrono::Duration::days(30)),
+            HistoryWindow::All => None,
+        }
+    }
+
+    /// 图表横轴的单位，返回秒数和名字
+    fn unit(self) -> (f64, &'static str) {
+        match self {
+            HistoryWindow::Day => (60.0 * 60.0, "小时"),
+            _ => (24.0 * 60.0 * 60.0, "天"),
+        }
+    }
+}
+
+/// 多久重新读取一次库存历史
+const HISTORY_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 库存历史窗口，显示一个产品的库存变化和补货记录，数据每隔一会儿从数据库重新读取
+#[derive(Default)]
+struct HistoryView {
+    open: bool,
+    // 选中的产品
+    opn: String,
+    window: HistoryWindow,
+    // 数据库里有记录的产品
+    products: Vec<String>,
+    readings: Vec<Reading>,
+    restocks: Vec<Restock>,
+    // 最后一次有库存的记录，不限时间范围
+    last_in_stock: Option<Reading>,
+    error: Option<String>,
+    // 上次读取的时间，`None` 的话马上读取
+    loaded: Option<Instant>,
+}
+
+impl HistoryView {
+    fn load(&mut self, config: &HistoryConfig) {
+        self.loaded = Some(Instant::now());
+        if let Err(e) = self.try_load(config) {
+            self.error = Some(e);
+        }
+    }
+
+    fn try_load(&mut self, config: &HistoryConfig) -> Result<(), String> {
+        self.error = None;
+        if !config.enabled {
+            return Err(
+                "没有开启库存历史，在配置文件的 [history] 里设置 enabled = true".to_owned(),
+            );
+        }
+        if !config.path.exists() {
+            return Err("还没有库存历史，开始监控之后会记录每次查询的结果".to_owned());
+        }
+        let history = History::open(&config.path).map_err(|e| e.to_string())?;
+        self.products = history.products().map_err(|e| e.to_string())?;
+        if !self.products.contains(&self.opn) {
+            self.opn = self.products.first().cloned().unwrap_or_default();
+        }
+        let since = self.window.since(Local::now());
+        self.readings = history
+            .readings(&self.opn, since)
+            .map_err(|e| e.to_string())?;
+        self.restocks = restocks(&self.readings);
+        self.last_in_stock = history
+            .last_in_stock(&self.opn)
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn show(&mut self, ctx: &egui::CtxRef, config: &HistoryConfig) {
+        if !self.open {
+            return;
+        }
+        if self
+            .loaded
+            .map(|v| v.elapsed() >= HISTORY_REFRESH_INTERVAL)
+            .unwrap_or(true)
+        {
+            self.load(config);
+        }
+
+        let mut open = self.open;
+        let mut reload = false;
+        egui::Window::new("库存历史")
+            .open(&mut open)
+            .default_size([720.0, 520.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("产品:");
+                    egui::ComboBox::from_id_source("history_product")
+                        .selected_text(self.opn.as_str())
+                        .show_ui(ui, |ui| {
+                            for v in &self.products {
+                                if ui.selectable_label(self.opn == *v, v).clicked() {
+                                    self.opn = v.clone();
+                                    reload = true;
+                                }
+                            }
+                        });
+                    ui.add_space(10.0);
+                    for v in HistoryWindow::ALL {
+                        reload |= ui
+                            .selectable_value(&mut self.window, v, v.label())
+                            .changed();
+                    }
+                    ui.add_space(10.0);
+                    reload |= ui.button("刷新").clicked();
+                });
+
+                if let Some(e) = &self.error {
+                    ui.colored_label(egui::Color32::from_rgb(200, 80, 80), e);
+                    return;
+                }
+
+                let now = Local::now();
+                match &self.last_in_stock {
+                    Some(v) => ui.label(format!(
+                        "最后一次有库存: {}，{} 个",
+                        v.time.format("%Y-%m-%d %H:%M"),
+                        v.inventory.unwrap_or_default()
+                    )),
+                    None => ui.label("一直没有库存"),
+                };
+
+                // 横轴是距离现在的时间，往左是过去
+                let (unit, unit_name) = self.window.unit();
+                let x = |t: DateTime<Local>| (t - now).num_seconds() as f64 / unit;
+                let stock = Values::from_values(
+                    self.readings
+                        .iter()
+                        .filter_map(|v| v.inventory.map(|s| Value::new(x(v.time), s as f64)))
+                        .collect(),
+                );
+                let failed = Values::from_values(
+                    self.readings
+                        .iter()
+                        .filter(|v| v.inventory.is_none())
+                        .map(|v| Value::new(x(v.time), 0.0))
+                        .collect(),
+                );
+                let restocked = Values::from_values(
+                    self.restocks
+                        .iter()
+                        .map(|v| Value::new(x(v.time), v.to as f64))
+                        .collect(),
+                );
+                let mut plot = Plot::new("history_plot")
+                    .line(Line::new(stock).name("库存"))
+                    .points(
+                        Points::new(restocked)
+                            .name("补货")
+                            .shape(MarkerShape::Up)
+                            .color(egui::Color32::from_rgb(0, 160, 0))
+                            .filled(true)
+                            .radius(5.0),
+                    )
+                    .points(
+                        Points::new(failed)
+                            .name("查询失败")
+                            .shape(MarkerShape::Cross)
+                            .color(egui::Color32::from_rgb(200, 80, 80))
+                            .radius(4.0),
+                    )
+                    .include_x(0.0)
+                    .include_y(0.0)
+                    .height(240.0)
+                    .legend(Legend::default());
+                if let Some(since) = self.window.since(now) {
+                    plot = plot.include_x(x(since));
+                }
+                ui.add(plot);
+                ui.label(format!("横轴是距离现在的{}数，纵轴是库存", unit_name));
+
+                ui.add_space(10.0);
+                ui.horizontal(|ui| {
+                    ui.heading("补货记录");
+                    // 两次以上补货才算得出平均间隔
+                    if let (Some(first), Some(last)) = (self.restocks.first(), self.restocks.last())
+                    {
+                        if self.restocks.len() > 1 {
+                            let every = (last.time - first.time) / (self.restocks.len() as i32 - 1);
+                            ui.label(format!("平均 {} 补一次货", format_span(every)));
+                        }
+                    }
+                });
+                if self.restocks.is_empty() {
+                    ui.label("这段时间没有补货");
+                    return;
+                }
+                egui::ScrollArea::vertical()
+                    .max_height(150.0)
+                    .show(ui, |ui| {
+                        egui::Grid::new("history_restocks")
+                            .striped(true)
+                            .min_col_width(80.0)
+                            .show(ui, |ui| {
+                                for v in ["时间", "补货前", "补货后", "补了多少", "多久卖完"]
+                                {
+                                    ui.strong(v);
+                                }
+                                ui.end_row();
+                                for v in self.restocks.iter().rev() {
+                                    ui.label(v.time.format("%Y-%m-%d %H:%M").to_string());
+                                    ui.label(v.from.to_string());
+                                    ui.label(v.to.to_string());
+                                    ui.label(format!("+{}", v.quantity()));
+                                    ui.label(match v.sold_out {
+                                        Some(_) => format_span(v.duration(now)),
+                                        None => {
+                                            format!("还没卖完({})", format_span(v.duration(now)))
+                                        }
+                                    });
+                                    ui.end_row();
+                                }
+                            });
+                    });
+            });
+        self.open = open;
+        if reload {
+            self.loaded = None;
+        }
+    }
+}
+
+/// 把一段时间写成 "3 天 4 小时" 这样
+fn format_span(v: chrono::Duration) -> String {
+    let minutes = v.num_minutes().max(0);
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    if days > 0 {
+        format!("{} 天 {} 小时", days, hours)
+    } else if hours > 0 {
+        format!("{} 小时 {} 分钟", hours, minutes)
+    } else {
+        format!("{} 分钟", minutes)
+    }
+}
+
+impl TiApp {
+    fn log(&mut self, msg: String) {
+        self.log_text += &msg;
+        self.log_text += "\n";
+    }
+
+    /// 把配置文件里的产品列表和邮箱配置显示到界面上
+    fn apply_config(&mut self, config: &Config) {
+        self.products = config.products.clone();
+        self.email_from = config.smtp.from.clone();
+        self.email_from_password = config.smtp.password.clone();
+        self.remember_password = config.smtp.remember_password;
+        let email = &config.notifiers.email;
+        self.email_to = format_address_list(&email.to);
+        self.email_cc = format_address_list(&email.cc);
+        self.email_bcc = format_address_list(&email.bcc);
+        self.smtp = SmtpForm::from_config(&config.smtp);
+    }
+
+    /// 要监控的产品列表，停用的产品不在里面
+    fn product_list(&self) -> String {
+        format_product_list(&self.products)
+    }
+
+    /// 界面上的发件箱设置
+    fn smtp_config(&self) -> Result<SmtpConfig, String> {
+        let mut smtp = self.config.smtp.clone();
+        smtp.from = self.email_from.trim().to_owned();
+        smtp.password = self.email_from_password.clone();
+        smtp.remember_password = self.remember_password;
+        self.smtp.apply(&mut smtp)?;
+        Ok(smtp)
+    }
+
+    /// 界面上填写的默认收件人
+    fn recipients(&self) -> Recipients {
+        Recipients {
+            to: parse_address_list(&self.email_to),
+            cc: parse_address_list(&self.email_cc),
+            bcc: parse_address_list(&self.email_bcc),
+        }
+    }
+
+    /// 界面上当前的配置
+    fn current_config(&self) -> Config {
+        let mut config = self.config.clone();
+        config.products = self.products.clone();
+        config.notifiers.email.set_recipients(self.recipients());
+        // 邮箱和轮询设置填写不正确的时候不保存，还是上次正确的设置
+        if let Ok(v) = self.smtp_config() {
+            config.smtp = v;
+        }
+        if let Ok(v) = self.schedule.to_schedule() {
+            config.polling = v;
+        }
+        config
+    }
+
+    /// 保存配置文件
+    fn save_config(&mut self) {
+        if self.config_error.is_some() {
+            return;
+        }
+        let config = self.current_config();
+        match config.save(DEFAULT_CONFIG_PATH) {
+            Ok(_) => {
+                self.config = config;
+                self.reload.config_file_modified = file_modified(DEFAULT_CONFIG_PATH);
+            }
+            Err(e) => {
+                error!("{}", e);
+                self.log(format!("{}", e));
+            }
+        }
+    }
+
+    fn send_command(&self, command: MonitorCommand) {
+        if self.status != MonitorStatus::Stopped {
+            let _ = self.sender_command.as_ref().unwrap().send(command);
+        }
+    }
+
+    /// 按界面和配置文件里的设置创建所有启用的通知渠道
+    fn notifiers(&self) -> Result<Vec<Arc<dyn Notifier>>, String> {
+        let mut config = self.current_config();
+        config.smtp = self.smtp_config()?;
+        ti::notify::from_config(&config).map_err(|e| format!("{}", e))
+    }
+
+    /// 检查界面上的设置，没问题就开始监控
+    fn start_monitor(&mut self) {
+        if !self.products.iter().any(|v| v.enabled) {
+            self.log_text = "请添加要监控的产品，再点击 开始监控".to_owned();
+            return;
+        }
+
+        let notifiers = match self.notifiers() {
+            Ok(v) if !v.is_empty() => v,
+            Ok(_) => {
+                self.log_text =
+                    "请输入邮箱信息或者在配置文件里设置其它通知渠道，再点击 开始监控".to_owned();
+                return;
+            }
+            Err(e) => {
+                self.log_text = format!("通知配置不正确:{}", e);
+                return;
+            }
+        };
+
+        let schedule = match self.schedule.to_schedule() {
+            Ok(v) => v,
+            Err(e) => {
+                self.log_text = format!("轮询设置不正确:{}", e);
+                return;
+            }
+        };
+
+        // 模板文件每次开始监控的时候重新读取
+        let templates = match self.config.templates.load() {
+            Ok(v) => v,
+            Err(e) => {
+                self.log_text = format!("{}", e);
+                return;
+            }
+        };
+
+        let settings = MonitorSettings {
+            product_list: self.product_list(),
+            notifiers,
+            schedule,
+            templates,
+            renotify: self.config.alerts.renotify,
+            history: self.config.history.clone(),
+        };
+        self.reload.products_edited = None;
+        self.reload.notifiers_edited = None;
+        let _ = self
+            .sender_command
+            .as_ref()
+            .unwrap()
+            .send(MonitorCommand::Start(Box::new(settings)));
+    }
+
+    /// 同步界面和配置文件的修改到正在运行的监控
+    fn sync_settings(&mut self) {
+        let now = Instant::now();
+
+        // 配置文件被其它程序修改了就重新读取，同步到界面和正在运行的监控
+        if self
+            .reload
+            .config_file_checked
+            .map(|v| now - v >= CONFIG_FILE_CHECK_INTERVAL)
+            .unwrap_or(true)
+        {
+            self.reload.config_file_checked = Some(now);
+            let modified = file_modified(DEFAULT_CONFIG_PATH);
+            if modified.is_some() && modified != self.reload.config_file_modified {
+                self.reload.config_file_modified = modified;
+                match Config::load(DEFAULT_CONFIG_PATH) {
+                    Ok(config) => {
+                        info!("配置文件被修改了，重新读取");
+                        self.apply_config(&config);
+                        self.config = config;
+                        self.config_error = None;
+                        self.reload.products_edited = None;
+                        self.reload.notifiers_edited = None;
+                        self.send_command(MonitorCommand::UpdateProducts(self.product_list()));
+                        if let Ok(v) = self.notifiers() {
+                            self.send_command(MonitorCommand::UpdateNotifiers(v));
+                        }
+                        self.log("配置文件被修改了，已重新读取".to_owned());
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        self.log(format!("{}", e));
+                    }
+                }
+            }
+        }
+
+        // 停止修改产品列表一会儿之后保存到配置文件，并同步到正在运行的监控
+        if self
+            .reload
+            .products_edited
+            .map(|v| now - v >= RELOAD_DELAY)
+            .unwrap_or(false)
+        {
+            self.reload.products_edited = None;
+            self.save_config();
+            self.send_command(MonitorCommand::UpdateProducts(self.product_list()));
+            // 产品列表里可以单独设置收件人
+            if let Ok(v) = self.notifiers() {
+                self.send_command(MonitorCommand::UpdateNotifiers(v));
+            }
+        }
+
+        // 停止修改邮箱和通知配置一会儿之后保存到配置文件，并同步到正在运行的监控
+        if self
+            .reload
+            .notifiers_edited
+            .map(|v| now - v >= RELOAD_DELAY)
+            .unwrap_or(false)
+        {
+            self.reload.notifiers_edited = None;
+            self.save_config();
+            match self.notifiers() {
+                Ok(v) => self.send_command(MonitorCommand::UpdateNotifiers(v)),
+                Err(e) => self.log(format!("通知配置不正确:{}", e)),
+            }
+        }
+    }
+}
+
+impl epi::App for TiApp {
+    fn name(&self) -> &str {
+        "芯片库存监控软件"
+    }
+
+    fn setup(
+        &mut self,
+        ctx: &egui::CtxRef,
+        frame: &mut epi::Frame<'_>,
+        _storage: Option<&dyn epi::Storage>,
+    ) {
+        // 邮箱配置、产品列表、轮询设置都在配置文件里
+        let config = self.config.clone();
+        self.apply_config(&config);
+        self.product_table
+            .load_history(&config.history, &config.products);
+        self.schedule = ScheduleForm::from_schedule(config.polling);
+        self.reload.config_file_modified = file_modified(DEFAULT_CONFIG_PATH);
+
+        if let Some(e) = &self.config_error {
+            self.log_text = format!("{}\n", e);
+        }
+
+        let repaint = frame.repaint_signal();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(100));
+            repaint.request_repaint();
+        });
+
+        let mut font = FontDefinitions::default();
+        font.font_data.insert(
+            "cn_font".to_owned(),
+            std::borrow::Cow::Borrowed(include_bytes!("../fonts/方正标雅宋简体.TTF")),
+        );
+        font.fonts_for_family
+            .get_mut(&FontFamily::Monospace)
+            .unwrap()
+            .insert(0, "cn_font".to_owned());
+        font.fonts_for_family
+            .get_mut(&FontFamily::Proportional)
+            .unwrap()
+            .insert(0, "cn_font".to_owned());
+        // 4. Configure context with modified `FontDefinitions`.
+        ctx.set_fonts(font);
+    }
+
+    fn update(&mut self, ctx: &egui::CtxRef, frame: &mut epi::Frame<'_>) {
+        self.sync_settings();
+        let smtp_config = self.smtp_config();
+        let recipients = self.recipients();
+        // 开始监控要检查的设置比较多，界面画完之后再处理
+        let mut start_clicked = false;
+
+        let Self {
+            config,
+            config_error: _,
+            products,
+            product_table,
+            account,
+            email_from,
+            email_from_password,
+            remember_password,
+            email_to,
+            email_cc,
+            email_bcc,
+            smtp,
+            schedule,
+            history_view,
+            log_text,
+            status,
+            reload,
+            reciver_product_count_log,
+            sender_log,
+            sender_command,
+            receiver_status,
+            receiver_products,
+        } = self;
+
+        while let Ok(v) = receiver_status.as_ref().unwrap().try_recv() {
+            *status = v;
+        }
+
+        while let Ok(v) = receiver_products.as_ref().unwrap().try_recv() {
+            product_table.update(v);
+        }
+
+        if let Ok(data) = reciver_product_count_log.as_ref().unwrap().try_recv() {
+            let t: Vec<String> = log_text
+                .split('\n')
+                .map(|v| {
+                    if v.is_empty() {
+                        "".to_owned()
+                    } else {
+                        v.to_string() + "\n"
+                    }
+                })
+                .collect();
+            // t.reverse();
+            *log_text = "".to_owned();
+
+            let left = if t.len() as i32 - 27 < 0 {
+                0
+            } else {
+                t.len() - 27
+            };
+            let right = if t.len() as i32 - 1 < 0 {
+                0
+            } else {
+                t.len() - 1
+            };
+
+            for i in left..right {
+                *log_text += match t.get(i) {
+                    Some(v) => v,
+                    None => {
+                        break;
+                    }
+                };
+            }
+
+            *log_text += format!("{}\n", data).as_str();
+        }
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.horizontal_wrapped(|ui| {
+                ui.vertical(|ui| {
+                    ui.indent("left", |ui| {
+                        ui.set_height(580.0);
+                        ui.set_max_width(520.0);
+                        ui.set_min_width(520.0);
+                        ui.heading("监控的产品");
+                        for action in product_table.show(ui, products) {
+                            match action {
+                                ProductAction::Edited => {
+                                    reload.products_edited = Some(Instant::now());
+                                }
+                                ProductAction::CheckNow(opn) => {
+                                    if *status == MonitorStatus::Stopped {
+                                        *log_text += "监控没有运行，点击 开始监控 之后才能查询\n";
+                                    } else {
+                                        let _ = sender_command
+                                            .as_ref()
+                                            .unwrap()
+                                            .send(MonitorCommand::CheckNow(opn));
+                                    }
+                                }
+                                ProductAction::Open(opn) => {
+                                    if let Some(account) = account {
+                                        if let Err(e) = open_url(&account.product_url(&opn)) {
+                                            *log_text += &format!("打开网页失败:{}\n", e);
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    });
+                });
+
+                ui.vertical(|ui| {
+                    ui.set_min_height(580.0);
+                    ui.indent("right", |ui| {
+                        ui.separator();
+                        ui.scope(|ui| {
+                            ui.heading("通知配置");
+                            let mut changed = false;
+                            ui.horizontal(|ui| {
+                                let notifiers = &mut config.notifiers;
+                                changed |= ui
+                                    .checkbox(&mut notifiers.email.enabled, "邮件通知")
+                                    .changed();
+                                changed |= ui
+                                    .checkbox(&mut notifiers.desktop.enabled, "桌面通知")
+                                    .changed();
+                                // webhook 和命令只能在配置文件里设置
+                                let others = notifiers.webhook.iter().filter(|v| v.enabled).count()
+                                    + notifiers.command.iter().filter(|v| v.enabled).count();
+                                if others > 0 {
+                                    ui.label(format!("其它渠道 {} 个", others));
+                                }
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("发件箱账号:");
+                                changed |= ui.text_edit_singleline(email_from).changed();
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("发件箱密码:");
+                                // 密码来自环境变量的时候不能在界面上修改
+                                let from_env = config.smtp.password_from_env;
+                                changed |= ui
+                                    .add_enabled(
+                                        !from_env,
+                                        egui::TextEdit::singleline(email_from_password)
+                                            .password(true),
+                                    )
+                                    .changed();
+                                if from_env {
+                                    ui.label(format!("(来自环境变量 {})", SMTP_PASSWORD_ENV));
+                                } else {
+                                    ui.checkbox(remember_password, "记住密码");
+                                }
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("收件箱账号:");
+                                changed |= ui
+                                    .text_edit_singleline(email_to)
+                                    .on_hover_text("多个地址用逗号分隔")
+                                    .changed();
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("抄送:");
+                                changed |= ui
+                                    .add(egui::TextEdit::singleline(email_cc).desired_width(135.0))
+                                    .changed();
+                                ui.label("密送:");
+                                changed |= ui
+                                    .add(egui::TextEdit::singleline(email_bcc).desired_width(135.0))
+                                    .changed();
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("SMTP 服务器:");
+                                changed |= ui
+                                    .add(
+                                        egui::TextEdit::singleline(&mut smtp.host)
+                                            .desired_width(140.0),
+                                    )
+                                    .changed();
+                                ui.label("端口:");
+                                changed |= ui
+                                    .add(
+                                        egui::TextEdit::singleline(&mut smtp.port)
+                                            .desired_width(50.0),
+                                    )
+                                    .changed();
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("加密方式:");
+                                let security = smtp.security;
+                                egui::ComboBox::from_id_source("smtp_security")
+                                    .selected_text(security)
+                                    .show_ui(ui, |ui| {
+                                        for v in SmtpSecurity::ALL {
+                                            ui.selectable_value(&mut smtp.security, v, v);
+                                        }
+                                    });
+                                if smtp.security != security {
+                                    changed = true;
+                                    // 端口还是原来加密方式的默认端口的话跟着改
+                                    if smtp.port.trim() == security.default_port().to_string() {
+                                        smtp.port = smtp.security.default_port().to_string();
+                                    }
+                                }
+                                ui.label("登录方式:");
+                                let auth = smtp.auth;
+                                egui::ComboBox::from_id_source("smtp_auth")
+                                    .selected_text(auth)
+                                    .show_ui(ui, |ui| {
+                                        for v in SmtpAuth::ALL {
+                                            ui.selectable_value(&mut smtp.auth, v, v);
+                                        }
+                                    });
+                                changed |= smtp.auth != auth;
+                            });
+                            ui.horizontal(|ui| {
+                                ui.label("发件人名字:");
+                                changed |= ui
+                                    .add(
+                                        egui::TextEdit::singleline(&mut smtp.display_name)
+                                            .desired_width(140.0),
+                                    )
+                                    .changed();
+                                if ui.button("发送测试邮件").clicked() {
+                                    match &smtp_config {
+                                        Ok(_) if recipients.is_empty() => {
+                                            *log_text =
+                                                "请输入收件箱账号，再发送测试邮件".to_owned();
+                                        }
+                                        Ok(v) => {
+                                            let mailer = Mailer::new(v.clone());
+                                            let to = recipients.clone();
+                                            let sender = sender_log.clone().unwrap();
+                                            *log_text += "正在发送测试邮件\n";
+                                            thread::spawn(move || {
+                                                let msg = match mailer.send(
+                                                    &to,
+                                                    "芯片库存监控测试邮件",
+                                                    "收到这封邮件说明邮件通知配置正确",
+                                                    None,
+                                                ) {
+                                                    Ok(_) => "测试邮件发送成功".to_owned(),
+                                                    Err(e) => {
+                                                        error!("发送测试邮件失败:{}", e);
+                                                        format!("测试邮件发送失败:{}", e)
+                                                    }
+                                                };
+                                                let _ = sender.send(msg);
+                                            });
+                                        }
+                                        Err(e) => {
+                                            *log_text = format!("邮件通知配置不正确:{}", e);
+                                        }
+                                    }
+                                }
+                            });
+                            if changed {
+                                reload.notifiers_edited = Some(Instant::now());
+                            }
+                        });
+
+                        ui.add_space(10.0);
+                        ui.scope(|ui| {
+                            ui.heading("轮询设置");
+                            ui.horizontal(|ui| {
+                                ui.label("查询间隔(秒):");
+                                ui.add(
+                                    egui::TextEdit::singleline(&mut schedule.interval_secs)
+                                        .desired_width(60.0),
+                                );
+                                ui.label("随机抖动(秒):");
+                                ui.add(
+                                    egui::TextEdit::singleline(&mut schedule.jitter_secs)
+                                        .desired_width(60.0),
+                                );
+                            });
+                            ui.horizontal(|ui| {
+                                ui.checkbox(&mut schedule.quiet_enabled, "静默时段:");
+                                ui.add(
+                                    egui::TextEdit::singleline(&mut schedule.quiet_start)
+                                        .desired_width(60.0),
+                                );
+                                ui.label("到");
+                                ui.add(
+                                    egui::TextEdit::singleline(&mut schedule.quiet_end)
+                                        .desired_width(60.0),
+                                );
+                            });
+                        });
+
+                        ui.add_space(10.0);
+                        ui.horizontal(|ui| {
+                            let sender_command = sender_command.as_ref().unwrap();
+
+                            let start = if *status == MonitorStatus::Stopped {
+                                "开始监控"
+                            } else {
+                                "重新开始"
+                            };
+                            if ui.button(start).clicked() {
+                                start_clicked = true;
+                            }
+
+                            match *status {
+                                MonitorStatus::Running => {
+                                    if ui.button("暂停").clicked() {
+                                        let _ = sender_command.send(MonitorCommand::Pause);
+                                    }
+                                }
+                                MonitorStatus::Paused => {
+                                    if ui.button("继续").clicked() {
+                                        let _ = sender_command.send(MonitorCommand::Resume);
+                                    }
+                                }
+                                MonitorStatus::Stopped => {}
+                            }
+
+                            if *status != MonitorStatus::Stopped && ui.button("停止").clicked() {
+                                let _ = sender_command.send(MonitorCommand::Stop);
+                            }
+
+                            ui.add_space(10.0);
+                            let (text, color) = match *status {
+                                MonitorStatus::Running => {
+                                    ("运行中", egui::Color32::from_rgb(0, 160, 0))
+                                }
+                                MonitorStatus::Paused => {
+                                    ("已暂停", egui::Color32::from_rgb(200, 150, 0))
+                                }
+                                MonitorStatus::Stopped => ("已停止", egui::Color32::GRAY),
+                            };
+                            ui.colored_label(color, format!("状态: {}", text));
+                        });
+
+                        ui.separator();
+                        ui.add_space(20.0);
+                        ui.horizontal(|ui| {
+                            ui.heading("运行记录");
+                            if ui.button("库存历史").clicked() {
+                                history_view.open = true;
+                                history_view.loaded = None;
+                            }
+                        });
+                        ui.separator();
+                        egui::ScrollArea::vertical().show(ui, |ui| {
+                            ui.set_width(410.0);
+                            ui.set_height(220.0);
+                            if ui
+                                .colored_label(egui::Color32::from_rgb(0, 100, 0), log_text)
+                                .changed()
+                            {}
+                        });
+                    });
+                });
+            });
+        });
+
+        history_view.show(ctx, &config.history);
+
+        if start_clicked {
+            self.start_monitor();
+        }
+
+        frame.set_window_size(egui::vec2(1000.0, 600.0));
+    }
+
+    fn warm_up_enabled(&self) -> bool {
+        false
+    }
+
+    fn save(&mut self, _storage: &mut dyn epi::Storage) {}
+
+    fn on_exit(&mut self) {
+        self.save_config();
+    }
+
+    fn auto_save_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(30)
+    }
+
+    fn max_size_points(&self) -> egui::Vec2 {
+        egui::Vec2::new(1024.0, 2048.0)
+    }
+
+    fn clear_color(&self) -> egui::Rgba {
+        egui::Color32::from_rgba_unmultiplied(12, 12, 12, 180).into()
+    }
+
+    fn persist_native_window(&self) -> bool {
+        true
+    }
+
+    fn persist_egui_memory(&self) -> bool {
+        true
+    }
+}
+
+/// 显示界面，一直运行到窗口关闭
+pub fn run(config: Config, config_error: Option<String>) {
+    // let options = eframe::NativeOptions::default();
+
+    let image_data = include_bytes!("../2.jpg");
+    use image::GenericImageView;
+    let image = image::load_from_memory(image_data).expect("Failed to load image");
+    let image_buffer = image.to_rgba8();
+
+    let options = eframe::NativeOptions {
+        resizable: false,
+        decorated: true, // 是否显示 标题栏 边框等
+
+        // 软件左上角图标
+        icon_data: Some(IconData {
+            rgba: image_buffer.to_vec(),
+            width: image.width(),
+            height: image.height(),
+        }),
+
+        ..Default::default()
+    };
+
+    // 执行日志通道
+    let (sender_product_count_log, receiver_product_count_log) =
+        std::sync::mpsc::channel::<String>();
+
+    // 控制监控的命令通道
+    let (sender_command, receiver_command) =
+        tokio::sync::mpsc::unbounded_channel::<MonitorCommand>();
+
+    // 监控状态通道
+    let (sender_status, receiver_status) = std::sync::mpsc::channel::<MonitorStatus>();
+
+    // 每个产品的查询结果通道
+    let (sender_products, receiver_products) = std::sync::mpsc::channel::<ProductStatus>();
+
+    // 查询库存用的网络设置，配置不对就用默认的
+    let account = match config.network.account() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("{}", e);
+            Account::builder().build().unwrap()
+        }
+    };
+    let batch_options = config.network.batch_options();
+
+    let app = TiApp {
+        config,
+        config_error,
+        reciver_product_count_log: Some(receiver_product_count_log),
+        sender_log: Some(sender_product_count_log.clone()),
+        sender_command: Some(sender_command),
+        receiver_status: Some(receiver_status),
+        receiver_products: Some(receiver_products),
+        account: Some(account.clone()),
+        ..Default::default()
+    };
+
+    thread::spawn(move || {
+        use tokio::runtime::Runtime;
+
+        let runtime = Runtime::new().unwrap();
+
+        let sender_ui = sender_product_count_log.clone();
+
+        sender_ui
+            .send("在左侧添加要监控的产品，然后点击开始监控按钮".to_string())
+            .unwrap();
+
+        runtime.block_on(async move {
+            let source = AccountSource::new(account, batch_options);
+            let (mut monitor, events) = Monitor::builder(source).build(receiver_command);
+            tokio::spawn(forward_events(
+                events,
+                sender_ui,
+                sender_status,
+                sender_products,
+            ));
+            // 一直运行，直到界面关闭
+            monitor.serve().await;
+        });
+    });
+    eframe::run_native(Box::new(app), options);
+}
+
+/// 把监控的事件转给界面，运行记录、运行状态和产品表格分别用各自的通道
+async fn forward_events(
+    mut events: tokio::sync::mpsc::UnboundedReceiver<MonitorEvent>,
+    sender_ui: Sender<String>,
+    sender_status: Sender<MonitorStatus>,
+    sender_products: Sender<ProductStatus>,
+) {
+    while let Some(event) = events.recv().await {
+        if let Some(msg) = event.message() {
+            let _ = sender_ui.send(msg);
+        }
+        let status = match event {
+            MonitorEvent::Status(v) => {
+                let _ = sender_status.send(v);
+                continue;
+            }
+            MonitorEvent::StockChecked {
+                opn,
+                time,
+                stock,
+                previous,
+            } => ProductStatus {
+                opn,
+                checked_at: time,
+                result: CheckResult::Stock { stock, previous },
+            },
+            MonitorEvent::CheckFailed {
+                opn,
+                time,
+                error,
+                invalid,
+            } => ProductStatus {
+                opn,
+                checked_at: time,
+                result: if invalid {
+                    CheckResult::Invalid
+                } else {
+                    CheckResult::Failed(error)
+                },
+            },
+            _ => continue,
+        };
+        let _ = sender_products.send(status);
+    }
+}
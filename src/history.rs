@@ -0,0 +1,409 @@
+//! 库存历史
+//!
+//! 每次查询的结果(型号、时间、库存或者出错的原因)保存在 SQLite 数据库里，
+//! 可以查某个产品最后一次有库存是什么时候、有多少，或者看看它一般什么时候补货。
+//! 超过保留天数的记录用 [`History::prune`] 删除。
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Local, TimeZone};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+/// 默认的库存历史数据库
+pub const DEFAULT_HISTORY_PATH: &str = "./history.db";
+
+/// 读写库存历史出错
+#[derive(Debug)]
+pub enum HistoryError {
+    /// 打开或者创建数据库失败
+    Open {
+        path: PathBuf,
+        source: rusqlite::Error,
+    },
+    /// 读写数据库失败
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::Open { path, source } => {
+                write!(f, "打开库存历史 {} 出错:{}", path.display(), source)
+            }
+            HistoryError::Sqlite(e) => write!(f, "读写库存历史出错:{}", e),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HistoryError::Open { source, .. } => Some(source),
+            HistoryError::Sqlite(e) => Some(e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for HistoryError {
+    fn from(e: rusqlite::Error) -> Self {
+        HistoryError::Sqlite(e)
+    }
+}
+
+/// 一次查询的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reading {
+    /// 产品型号
+    pub opn: String,
+    /// 查询时间，精确到秒
+    pub time: DateTime<Local>,
+    /// 查到的库存，查询失败是 `None`
+    pub inventory: Option<usize>,
+    /// 查询失败的原因
+    pub error: Option<String>,
+}
+
+impl Reading {
+    /// 查询成功
+    pub fn stock(opn: &str, time: DateTime<Local>, inventory: usize) -> Self {
+        Reading {
+            opn: opn.to_owned(),
+            time,
+            inventory: Some(inventory),
+            error: None,
+        }
+    }
+
+    /// 查询失败
+    pub fn failed(opn: &str, time: DateTime<Local>, error: String) -> Self {
+        Reading {
+            opn: opn.to_owned(),
+            time,
+            inventory: None,
+            error: Some(error),
+        }
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let secs: i64 = row.get(1)?;
+        let inventory: Option<i64> = row.get(2)?;
+        Ok(Reading {
+            opn: row.get(0)?,
+            time: from_timestamp(secs),
+            inventory: inventory.map(|v| v.max(0) as usize),
+            error: row.get(3)?,
+        })
+    }
+}
+
+/// 一次补货，两次成功的查询之间库存变多了
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Restock {
+    /// 发现补货的查询时间
+    pub time: DateTime<Local>,
+    /// 补货之前的库存
+    pub from: usize,
+    /// 补货之后的库存
+    pub to: usize,
+    /// 补货之后第一次卖完的时间，还没卖完是 `None`
+    pub sold_out: Option<DateTime<Local>>,
+}
+
+impl Restock {
+    /// 补了多少货
+    pub fn quantity(&self) -> usize {
+        self.to - self.from
+    }
+
+    /// 补货之后多久卖完，还没卖完就是到 `now` 为止
+    pub fn duration(&self, now: DateTime<Local>) -> chrono::Duration {
+        self.sold_out.unwrap_or(now) - self.time
+    }
+}
+
+fn from_timestamp(secs: i64) -> DateTime<Local> {
+    Local
+        .timestamp_opt(secs, 0)
+        .single()
+        .unwrap_or_else(|| Local.timestamp_opt(0, 0).unwrap())
+}
+
+/// 从按时间排序的查询结果里找出每次补货，查询失败的结果跳过
+pub fn restocks(readings: &[Reading]) -> Vec<Restock> {
+    let mut list: Vec<Restock> = vec![];
+    let mut previous: Option<usize> = None;
+    for reading in readings {
+        let stock = match reading.inventory {
+            Some(v) => v,
+            None => continue,
+        };
+        if let Some(from) = previous {
+            if stock > from {
+                list.push(Restock {
+                    time: reading.time,
+                    from,
+                    to: stock,
+                    sold_out: None,
+                });
+            } else if stock == 0 && from > 0 {
+                // 卖完之前的每次补货都算到这次卖完
+                for v in list.iter_mut().rev() {
+                    if v.sold_out.is_some() {
+                        break;
+                    }
+                    v.sold_out = Some(reading.time);
+                }
+            }
+        }
+        previous = Some(stock);
+    }
+    list
+}
+
+const COLUMNS: &str = "opn, checked_at, inventory, error";
+
+/// 保存在 SQLite 里的库存历史
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    /// 打开数据库，不存在就创建
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<History, HistoryError> {
+        let path = path.as_ref();
+        let open_error = |source| HistoryError::Open {
+            path: path.to_owned(),
+            source,
+        };
+        let conn = Connection::open(path).map_err(open_error)?;
+        // 界面和命令行可能同时读，WAL 模式下读写不会互相等待
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(open_error)?;
+        Self::init(conn).map_err(open_error)
+    }
+
+    /// 只保存在内存里的数据库
+    pub fn open_in_memory() -> Result<History, HistoryError> {
+        Ok(Self::init(Connection::open_in_memory()?)?)
+    }
+
+    fn init(conn: Connection) -> rusqlite::Result<History> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS readings (
+                id INTEGER PRIMARY KEY,
+                opn TEXT NOT NULL,
+                checked_at INTEGER NOT NULL,
+                inventory INTEGER,
+                error TEXT
+            );
+            CREATE INDEX IF NOT EXISTS readings_opn_time ON readings (opn, checked_at);
+            CREATE INDEX IF NOT EXISTS readings_time ON readings (checked_at);",
+        )?;
+        Ok(History { conn })
+    }
+
+    /// 保存一次查询的结果
+    pub fn record(&mut self, reading: &Reading) -> Result<(), HistoryError> {
+        self.record_all(std::slice::from_ref(reading))
+    }
+
+    /// 在一个事务里保存一轮查询的结果
+    pub fn record_all(&mut self, readings: &[Reading]) -> Result<(), HistoryError> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO readings (opn, checked_at, inventory, error) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for v in readings {
+                stmt.execute(params![
+                    v.opn,
+                    v.time.timestamp(),
+                    v.inventory.map(|v| v as i64),
+                    v.error
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 最后一次查询的结果，不管成功还是失败
+    pub fn latest(&self, opn: &str) -> Result<Option<Reading>, HistoryError> {
+        let sql = format!(
+            "SELECT {} FROM readings WHERE opn = ?1 ORDER BY checked_at DESC, id DESC LIMIT 1",
+            COLUMNS
+        );
+        Ok(self
+            .conn
+            .query_row(&sql, params![opn], Reading::from_row)
+            .optional()?)
+    }
+
+    /// 最后一次查到有库存的结果
+    pub fn last_in_stock(&self, opn: &str) -> Result<Option<Reading>, HistoryError> {
+        let sql = format!(
+            "SELECT {} FROM readings WHERE opn = ?1 AND inventory > 0
+             ORDER BY checked_at DESC, id DESC LIMIT 1",
+            COLUMNS
+        );
+        Ok(self
+            .conn
+            .query_row(&sql, params![opn], Reading::from_row)
+            .optional()?)
+    }
+
+    /// `since` 之后的所有查询结果，按时间排序，`since` 是 `None` 就返回全部
+    pub fn readings(
+        &self,
+        opn: &str,
+        since: Option<DateTime<Local>>,
+    ) -> Result<Vec<Reading>, HistoryError> {
+        let sql = format!(
+            "SELECT {} FROM readings WHERE opn = ?1 AND checked_at >= ?2 ORDER BY checked_at, id",
+            COLUMNS
+        );
+        let since = since.map(|v| v.timestamp()).unwrap_or(i64::MIN);
+        let mut stmt = self.conn.prepare_cached(&sql)?;
+        let rows = stmt.query_map(params![opn, since], Reading::from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// `since` 之后的每次补货，按时间排序，可以看出一般多久补一次货、一次补多少、多久卖完
+    pub fn restocks(
+        &self,
+        opn: &str,
+        since: Option<DateTime<Local>>,
+    ) -> Result<Vec<Restock>, HistoryError> {
+        Ok(restocks(&self.readings(opn, since)?))
+    }
+
+    /// 有记录的产品型号，按型号排序
+    pub fn products(&self) -> Result<Vec<String>, HistoryError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT DISTINCT opn FROM readings ORDER BY opn")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// 删除 `now` 之前超过 `retention` 的记录，返回删除了几条
+    pub fn prune(
+        &mut self,
+        retention: Duration,
+        now: DateTime<Local>,
+    ) -> Result<usize, HistoryError> {
+        let before = now.timestamp() - retention.as_secs().min(i64::MAX as u64) as i64;
+        Ok(self.conn.execute(
+            "DELETE FROM readings WHERE checked_at < ?1",
+            params![before],
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(minutes: i64) -> DateTime<Local> {
+        from_timestamp(1_700_000_000 + minutes * 60)
+    }
+
+    #[test]
+    fn find_restocks() {
+        let readings = vec![
+            Reading::stock("A", time(0), 0),
+            Reading::stock("A", time(1), 100),
+            Reading::failed("A", time(2), "超时".to_owned()),
+            Reading::stock("A", time(3), 80),
+            Reading::stock("A", time(4), 150),
+            Reading::stock("A", time(5), 0),
+            Reading::stock("A", time(6), 0),
+            Reading::stock("A", time(7), 20),
+        ];
+        let list = restocks(&readings);
+        assert_eq!(
+            list,
+            vec![
+                Restock {
+                    time: time(1),
+                    from: 0,
+                    to: 100,
+                    sold_out: Some(time(5)),
+                },
+                Restock {
+                    time: time(4),
+                    from: 80,
+                    to: 150,
+                    sold_out: Some(time(5)),
+                },
+                Restock {
+                    time: time(7),
+                    from: 0,
+                    to: 20,
+                    sold_out: None,
+                },
+            ]
+        );
+        assert_eq!(list[1].quantity(), 70);
+        assert_eq!(list[0].duration(time(60)), chrono::Duration::minutes(4));
+        assert_eq!(list[2].duration(time(60)), chrono::Duration::minutes(53));
+
+        // 第一次查询不算补货，查询失败的跳过
+        assert!(restocks(&[Reading::stock("A", time(0), 5)]).is_empty());
+        assert!(restocks(&[
+            Reading::failed("A", time(0), "超时".to_owned()),
+            Reading::stock("A", time(1), 5),
+        ])
+        .is_empty());
+    }
+
+    #[test]
+    fn record_query_and_prune() {
+        let dir = std::env::temp_dir().join(format!("ti-history-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut history = History::open(dir.join("history.db")).unwrap();
+
+        history
+            .record_all(&[
+                Reading::stock("A", time(0), 0),
+                Reading::stock("B", time(0), 3),
+                Reading::stock("A", time(10), 50),
+            ])
+            .unwrap();
+        history
+            .record(&Reading::failed("A", time(20), "超时".to_owned()))
+            .unwrap();
+
+        assert_eq!(history.products().unwrap(), ["A", "B"]);
+        assert_eq!(
+            history.latest("A").unwrap().unwrap().error.as_deref(),
+            Some("超时")
+        );
+        assert_eq!(history.last_in_stock("A").unwrap().unwrap().time, time(10));
+        // 查询失败的结果跳过
+        assert_eq!(
+            history.restocks("A", None).unwrap(),
+            vec![Restock {
+                time: time(10),
+                from: 0,
+                to: 50,
+                sold_out: None,
+            }]
+        );
+        assert!(history.restocks("A", Some(time(5))).unwrap().is_empty());
+
+        // 只删除 `now` 之前超过保留时间的
+        let removed = history
+            .prune(Duration::from_secs(15 * 60), time(25))
+            .unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(history.products().unwrap(), ["A"]);
+        assert_eq!(history.latest("A").unwrap().unwrap().time, time(20));
+
+        drop(history);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1 +1,33 @@
-pub mod account;
\ No newline at end of file
+pub mod account;
+pub mod alert;
+pub mod config;
+pub mod error;
+pub mod history;
+pub mod mail;
+pub mod monitor;
+pub mod notify;
+pub mod outbox;
+pub mod products;
+pub mod schedule;
+pub mod template;
+pub mod throttle;
+pub mod vault;
+
+pub use account::{
+    Account, AccountBuilder, BatchOptions, BatchResult, Inventory, PriceBreak, Pricing,
+};
+pub use alert::{Alert, AlertRule, AlertTracker};
+pub use config::{Config, ConfigError};
+pub use error::AccountError;
+pub use history::{History, HistoryError, Reading, Restock};
+pub use mail::{MailError, Mailer, Recipients, SmtpAuth, SmtpSecurity};
+pub use monitor::{
+    Monitor, MonitorCommand, MonitorEvent, MonitorExit, MonitorSettings, MonitorStatus,
+};
+pub use notify::{Notifier, NotifyError, WebhookKind};
+pub use outbox::{DeliveryEvent, Dispatcher, Notification, RetryPolicy};
+pub use products::{format_product_list, parse_product_list, ProductEntry};
+pub use schedule::{QuietHours, Schedule, Scheduler};
+pub use template::{AlertTemplates, StockAlert, Template, TemplateError};
+pub use throttle::{Backoff, RateLimit, ThrottleState};
+pub use vault::{Vault, VaultError};
@@ -0,0 +1,307 @@
+use std::fmt;
+
+use lettre::smtp::authentication::{Credentials, Mechanism};
+use lettre::smtp::error::Error as SmtpError;
+use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use native_tls::{Protocol, TlsConnector};
+use serde::{Deserialize, Serialize};
+
+use crate::config::SmtpConfig;
+
+/// 和 SMTP 服务器之间怎么加密
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// 直接用 TLS 连接，一般是 465 端口
+    #[default]
+    Tls,
+    /// 先用明文连接再用 STARTTLS 升级，一般是 587 端口
+    StartTls,
+    /// 不加密，一般是 25 端口，只建议在内网使用
+    None,
+}
+
+impl SmtpSecurity {
+    pub const ALL: [SmtpSecurity; 3] = [
+        SmtpSecurity::Tls,
+        SmtpSecurity::StartTls,
+        SmtpSecurity::None,
+    ];
+
+    /// 这种加密方式常用的端口
+    pub fn default_port(&self) -> u16 {
+        match self {
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::None => 25,
+        }
+    }
+}
+
+impl fmt::Display for SmtpSecurity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmtpSecurity::Tls => write!(f, "TLS"),
+            SmtpSecurity::StartTls => write!(f, "STARTTLS"),
+            SmtpSecurity::None => write!(f, "不加密"),
+        }
+    }
+}
+
+/// SMTP 登录方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpAuth {
+    /// 按服务器支持的方式自动选择
+    #[default]
+    Auto,
+    Plain,
+    /// 有些邮箱(例如 Office 365)只支持这种
+    Login,
+}
+
+impl SmtpAuth {
+    pub const ALL: [SmtpAuth; 3] = [SmtpAuth::Auto, SmtpAuth::Plain, SmtpAuth::Login];
+}
+
+impl fmt::Display for SmtpAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmtpAuth::Auto => write!(f, "自动"),
+            SmtpAuth::Plain => write!(f, "PLAIN"),
+            SmtpAuth::Login => write!(f, "LOGIN"),
+        }
+    }
+}
+
+/// 一封邮件的收件人
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recipients {
+    /// 收件人
+    pub to: Vec<String>,
+    /// 抄送
+    pub cc: Vec<String>,
+    /// 密送，其它收件人看不到
+    pub bcc: Vec<String>,
+}
+
+impl Recipients {
+    /// 只有收件人的
+    pub fn to(to: Vec<String>) -> Self {
+        Recipients {
+            to,
+            ..Default::default()
+        }
+    }
+
+    /// 一个收件人都没有
+    pub fn is_empty(&self) -> bool {
+        self.to.is_empty() && self.cc.is_empty() && self.bcc.is_empty()
+    }
+
+    /// 所有的地址，包括抄送和密送
+    pub fn addresses(&self) -> impl Iterator<Item = &String> {
+        self.to.iter().chain(self.cc.iter()).chain(self.bcc.iter())
+    }
+
+    /// 把 `other` 的收件人加进来，已经有的地址不重复加
+    pub fn merge(&mut self, other: &Recipients) {
+        for (list, other) in [
+            (&mut self.to, &other.to),
+            (&mut self.cc, &other.cc),
+            (&mut self.bcc, &other.bcc),
+        ] {
+            for address in other {
+                if !list.contains(address) {
+                    list.push(address.clone());
+                }
+            }
+        }
+    }
+}
+
+/// 解析用逗号、分号或者空格分隔的多个邮箱地址
+pub fn parse_address_list(text: &str) -> Vec<String> {
+    text.split(|c: char| c == ',' || c == ';' || c == '，' || c == '；' || c.is_whitespace())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// 邮箱地址列表转成一行文字，[`parse_address_list`] 可以解析回来
+pub fn format_address_list(addresses: &[String]) -> String {
+    addresses.join(", ")
+}
+
+/// 配置文件里的收件人可以写一个地址、用逗号分隔的多个地址，或者一个列表:
+///
+/// ```toml
+/// to = ["a@example.com", "b@example.com"]
+/// cc = "c@example.com"
+/// ```
+pub(crate) mod address_list {
+    use serde::{Deserialize, Deserializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    /// 旧的配置文件里收件人是一个字符串
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(v) => super::parse_address_list(&v),
+            OneOrMany::Many(v) => v
+                .iter()
+                .flat_map(|v| super::parse_address_list(v))
+                .collect(),
+        })
+    }
+}
+
+/// 发送邮件出错
+#[derive(Debug)]
+pub enum MailError {
+    /// 邮箱地址或者邮件内容不正确
+    Build(String),
+    /// 创建 TLS 连接器失败
+    Tls(String),
+    /// 连接或者和 SMTP 服务器通信出错
+    Smtp(SmtpError),
+}
+
+impl MailError {
+    /// 服务器临时出错或者网络问题，过一会儿重试可能会成功
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            MailError::Smtp(SmtpError::Transient(_))
+                | MailError::Smtp(SmtpError::Io(_))
+                | MailError::Smtp(SmtpError::Resolution)
+        )
+    }
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::Build(e) => write!(f, "邮件内容不正确:{}", e),
+            MailError::Tls(e) => write!(f, "创建 TLS 连接失败:{}", e),
+            // 服务器返回的错误带上错误码和完整的内容，方便查是什么问题
+            MailError::Smtp(SmtpError::Transient(v)) | MailError::Smtp(SmtpError::Permanent(v)) => {
+                write!(f, "SMTP 服务器返回 {} {}", v.code, v.message.join(" "))
+            }
+            MailError::Smtp(SmtpError::Resolution) => write!(f, "找不到 SMTP 服务器的地址"),
+            MailError::Smtp(e) => write!(f, "SMTP 出错:{}", e),
+        }
+    }
+}
+
+impl std::error::Error for MailError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MailError::Smtp(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SmtpError> for MailError {
+    fn from(e: SmtpError) -> Self {
+        MailError::Smtp(e)
+    }
+}
+
+/// 按照 [`SmtpConfig`] 发送邮件
+///
+/// 每次发送都重新连接服务器，会阻塞当前线程，在异步代码里要放到
+/// `tokio::task::spawn_blocking` 里调用。
+#[derive(Debug, Clone)]
+pub struct Mailer {
+    smtp: SmtpConfig,
+}
+
+impl Mailer {
+    pub fn new(smtp: SmtpConfig) -> Self {
+        Mailer { smtp }
+    }
+
+    pub fn smtp(&self) -> &SmtpConfig {
+        &self.smtp
+    }
+
+    /// 发送一封邮件给 `to` 里的所有人，有 `html` 的话和纯文字内容一起发送
+    pub fn send(
+        &self,
+        to: &Recipients,
+        subject: &str,
+        text: &str,
+        html: Option<&str>,
+    ) -> Result<(), MailError> {
+        if to.is_empty() {
+            return Err(MailError::Build("没有收件人".to_owned()));
+        }
+
+        let smtp = &self.smtp;
+        let mut builder = if smtp.display_name.is_empty() {
+            EmailBuilder::new().from(smtp.from.as_str())
+        } else {
+            EmailBuilder::new().from((smtp.from.as_str(), smtp.display_name.as_str()))
+        };
+        for address in &to.to {
+            builder = builder.to(address.as_str());
+        }
+        for address in &to.cc {
+            builder = builder.cc(address.as_str());
+        }
+        // 密送的地址只放在信封里，不会出现在邮件头
+        for address in &to.bcc {
+            builder = builder.bcc(address.as_str());
+        }
+        builder = match html {
+            Some(html) => builder.alternative(html, text),
+            None => builder.text(text),
+        };
+        let email = match builder.subject(subject).build() {
+            Ok(v) => v,
+            Err(e) => return Err(MailError::Build(format!("{}", e))),
+        };
+
+        let tls = || -> Result<ClientTlsParameters, MailError> {
+            let connector = TlsConnector::builder()
+                .min_protocol_version(Some(Protocol::Tlsv12))
+                .build()
+                .map_err(|e| MailError::Tls(format!("{}", e)))?;
+            Ok(ClientTlsParameters::new(smtp.host.clone(), connector))
+        };
+        let security = match smtp.security {
+            SmtpSecurity::Tls => ClientSecurity::Wrapper(tls()?),
+            SmtpSecurity::StartTls => ClientSecurity::Required(tls()?),
+            SmtpSecurity::None => ClientSecurity::None,
+        };
+
+        let mut client = SmtpClient::new((smtp.host.as_str(), smtp.port), security)?;
+        // 内网的 SMTP 服务器可能不需要登录
+        if !smtp.password.is_empty() {
+            client = client.credentials(Credentials::new(smtp.from.clone(), smtp.password.clone()));
+        }
+        client = match smtp.auth {
+            SmtpAuth::Auto => client,
+            SmtpAuth::Plain => client.authentication_mechanism(Mechanism::Plain),
+            SmtpAuth::Login => client.authentication_mechanism(Mechanism::Login),
+        };
+
+        let mut transport = client.transport();
+        let res = transport.send(email.into());
+        transport.close();
+        res?;
+        Ok(())
+    }
+}
@@ -1,487 +1,565 @@
-#![windows_subsystem = "windows"]
+#![cfg_attr(feature = "gui", windows_subsystem = "windows")]
 
-use eframe::{
-    egui::{self, FontDefinitions, FontFamily, Visuals},
-    epi::{self, IconData},
-};
-use log::{debug, info};
-use thirtyfour::support::block_on;
-use ti::account::Account;
-use tokio::sync::Mutex;
-
-use std::{
-    borrow::Borrow,
-    collections::HashMap,
-    fs::{self, read_to_string},
-    ops::AddAssign,
-    path::Path,
-    rc::Rc,
-    sync::{
-        mpsc::{Receiver, Sender},
-        Arc,
-    },
-    thread,
-    time::Duration,
+#[cfg(feature = "gui")]
+mod gui;
+
+use log::{error, info, warn};
+use ti::{
+    config::{LoggingConfig, DEFAULT_CONFIG_PATH},
+    monitor::{AccountSource, MonitorExit},
+    AccountError, Config, Inventory, Monitor, MonitorCommand, MonitorSettings,
 };
 
-use std::os::windows::process::CommandExt;
-use std::process::{Command, Stdio};
-
-#[derive(Default)]
-struct TiApp {
-    product_list: String,
-    email_from: String,
-    email_from_password: String,
-    email_to: String,
-    log_text: String,
-    // 接受执行结果
-    reciver_product_count_log: Option<Receiver<String>>,
-    // 发送产品列表
-    sender_product_name: Option<Sender<String>>,
-    // 发送邮箱信息
-    sender_email: Option<Sender<String>>,
-}
+use std::{collections::HashSet, path::Path};
 
-impl epi::App for TiApp {
-    fn name(&self) -> &str {
-        "芯片库存监控软件"
+#[tokio::main]
+async fn main() {
+    let mode = match parse_args() {
+        Ok(v) => v,
+        Err(e) => {
+            attach_console();
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(QUERY_EXIT_USAGE);
+        }
+    };
+    if mode != Mode::Gui {
+        attach_console();
     }
 
-    fn setup(
-        &mut self,
-        ctx: &egui::CtxRef,
-        frame: &mut epi::Frame<'_>,
-        _storage: Option<&dyn epi::Storage>,
-    ) {
-        // 邮箱配置信息
-        if Path::new("./email.txt").exists() {
-            let data = read_to_string("./email.txt").unwrap_or("".to_owned());
-            let t = data.split("\n").map(|v| v).collect::<Vec<&str>>();
-            if t.len() == 3 {
-                self.email_from = t[0].to_string();
-                self.email_from_password = t[1].to_string();
-                self.email_to = t[2].to_string();
-            }
-        }
+    // 编译时没有打开 `gui` 功能的话只能在命令行运行
+    #[cfg(not(feature = "gui"))]
+    if mode == Mode::Gui {
+        eprintln!(
+            "这个版本没有界面，请用 --headless 或者 query 运行\n\n{}",
+            USAGE
+        );
+        std::process::exit(QUERY_EXIT_USAGE);
+    }
 
-        // 产品列表
-        if Path::new("./products.txt").exists() {
-            let data = read_to_string("./products.txt").unwrap_or("".to_owned());
-            self.product_list = data;
-        }
+    // 查询完就退出，不迁移也不改动配置文件
+    if let Mode::Query(args) = mode {
+        std::process::exit(run_query(args).await);
+    }
 
-        let repaint = frame.repaint_signal();
-        thread::spawn(move || loop {
-            thread::sleep(Duration::from_millis(100));
-            repaint.request_repaint();
-        });
+    // 配置文件不存在的话从旧版本的 email.txt、products.txt 迁移过来
+    let (config, config_error) = match Config::load_or_migrate(DEFAULT_CONFIG_PATH) {
+        Ok((v, _)) => (v, None),
+        Err(e) => (Config::default(), Some(format!("{}", e))),
+    };
 
-        let mut font = FontDefinitions::default();
-        font.font_data.insert(
-            "cn_font".to_owned(),
-            std::borrow::Cow::Borrowed(include_bytes!("../fonts/方正标雅宋简体.TTF")),
-        );
-        font.fonts_for_family
-            .get_mut(&FontFamily::Monospace)
-            .unwrap()
-            .insert(0, "cn_font".to_owned());
-        font.fonts_for_family
-            .get_mut(&FontFamily::Proportional)
-            .unwrap()
-            .insert(0, "cn_font".to_owned());
-        // 4. Configure context with modified `FontDefinitions`.
-        ctx.set_fonts(font);
+    if mode == Mode::Headless {
+        // 没有界面的时候运行记录只能看日志，一定要输出到标准输出
+        let mut logging = config.logging.clone();
+        logging.stdout = true;
+        init_logging(&logging);
+        let code = match config_error {
+            Some(e) => {
+                error!("{}", e);
+                1
+            }
+            None => run_headless(config).await,
+        };
+        std::process::exit(code);
     }
 
-    fn update(&mut self, ctx: &egui::CtxRef, frame: &mut epi::Frame<'_>) {
-        let Self {
-            product_list,
-            email_from,
-            email_from_password,
-            email_to,
-            log_text,
-            reciver_product_count_log,
-            sender_product_name,
-            sender_email,
-        } = self;
-
-        if let Ok(data) = reciver_product_count_log.as_ref().unwrap().try_recv() {
-            let t: Vec<String> = log_text
-                .split("\n")
-                .into_iter()
-                .map(|v| {
-                    if v.is_empty() {
-                        "".to_owned()
-                    } else {
-                        v.to_string() + "\n"
-                    }
-                })
-                .collect();
-            // t.reverse();
-            *log_text = "".to_owned();
+    init_logging(&config.logging);
+    if let Some(e) = &config_error {
+        error!("{}", e);
+    }
 
-            let left = if t.len() as i32 - 27 < 0 {
-                0
-            } else {
-                t.len() - 27
-            };
-            let right = if t.len() as i32 - 1 < 0 {
-                0
-            } else {
-                t.len() - 1
-            };
-
-            for i in left..right {
-                *log_text += match t.get(i) {
-                    Some(v) => v,
-                    None => {
-                        break;
-                    }
-                };
-            }
+    #[cfg(feature = "gui")]
+    gui::run(config, config_error);
+}
 
-            *log_text += format!("{}\n", data).as_str();
-        }
+/// 命令行用法
+const USAGE: &str = "用法: ti [--headless]
+      ti query [--format table|json|csv] [型号...]
+
+  --headless  不显示界面，按 config.toml 里的设置一直监控，运行记录输出到日志，
+              收到 Ctrl+C 或者 SIGTERM 之后退出，收到 SIGHUP 重新读取配置文件
+  query       查询一次型号的库存然后退出，没有给型号或者型号是 - 的时候从标准输入读取，
+              型号之间用空格、逗号或者换行分隔
+  -f, --format
+              query 的输出格式，table(默认)、json 或者 csv
+  -h, --help  显示这个帮助
+
+query 的退出码: 0 有型号有库存，1 都没有库存，2 参数或者配置文件不正确，
+                3 都没有库存而且有型号查询失败";
+
+/// 有型号有库存
+const QUERY_EXIT_IN_STOCK: i32 = 0;
+/// 查询的型号都没有库存
+const QUERY_EXIT_OUT_OF_STOCK: i32 = 1;
+/// 命令行参数或者配置文件不正确
+const QUERY_EXIT_USAGE: i32 = 2;
+/// 都没有库存，而且有型号查询失败，不能确定是不是真的没有库存
+const QUERY_EXIT_FAILED: i32 = 3;
+
+/// 程序的运行方式
+#[derive(Debug, PartialEq, Eq)]
+enum Mode {
+    Gui,
+    Headless,
+    Query(QueryArgs),
+}
 
-        egui::CentralPanel::default().show(ctx, |ui| {
-            ui.horizontal_wrapped(|ui| {
-                ui.vertical(|ui| {
-                    ui.indent("left", |ui| {
-                        // ui.set_max_height(580.0);
-                        ui.set_height(580.0);
-                        ui.set_max_width(300.0);
-                        ui.set_min_width(300.0);
-                        ui.heading("监控的产品列表");
-                        egui::ScrollArea::vertical().show(ui, |ui| {
-                            // ui.set_height(580.0);
-                            if ui.text_edit_multiline(product_list).changed() {
-                                println!("xx");
-                            }
-                        });
-                    });
-                });
-
-                ui.vertical(|ui| {
-                    ui.set_min_height(580.0);
-                    ui.indent("right", |ui| {
-                        ui.separator();
-                        ui.scope(|ui| {
-                            ui.heading("邮件通知配置");
-                            ui.horizontal(|ui| {
-                                ui.label("发件箱账号:");
-                                ui.text_edit_singleline(email_from);
-                            });
-                            ui.horizontal(|ui| {
-                                ui.label("发件箱密码:");
-                                ui.text_edit_singleline(email_from_password);
-                            });
-                            ui.horizontal(|ui| {
-                                ui.label("收件箱账号:");
-                                ui.text_edit_singleline(email_to);
-                            });
-                        });
-
-                        ui.add_space(10.0);
-                        ui.scope(|ui| {
-                            if ui.button("开始监控").clicked() {
-                                if product_list.is_empty() {
-                                    *log_text = "请输入产品列表，再点击 开始监控".to_owned();
-                                    return;
-                                }
-
-                                if email_from.is_empty()
-                                    || email_from_password.is_empty()
-                                    || email_to.is_empty()
-                                {
-                                    *log_text = "请输入邮箱信息，再点击 开始监控".to_owned();
-                                    return;
-                                }
-
-                                // 发送产品列表
-                                sender_product_name
-                                    .as_ref()
-                                    .unwrap()
-                                    .send(product_list.to_string())
-                                    .unwrap();
-
-                                // 发送邮箱配置
-                                sender_email
-                                    .as_ref()
-                                    .unwrap()
-                                    .send(format!(
-                                        "{}\n{}\n{}",
-                                        email_from, email_from_password, email_to
-                                    ))
-                                    .unwrap();
-                            }
-                        });
-
-                        ui.separator();
-                        ui.add_space(20.0);
-                        ui.heading("运行记录");
-                        ui.separator();
-                        egui::ScrollArea::vertical().show(ui, |ui| {
-                            ui.set_width(410.0);
-                            ui.set_height(380.0);
-                            if ui
-                                .colored_label(egui::Color32::from_rgb(0, 100, 0), log_text)
-                                .changed()
-                            {}
-                        });
-                    });
-                });
-            });
-        });
+/// `ti query` 的参数
+#[derive(Debug, PartialEq, Eq)]
+struct QueryArgs {
+    format: QueryFormat,
+    /// 命令行上的型号，`-` 表示从标准输入读取
+    opns: Vec<String>,
+}
 
-        frame.set_window_size(egui::vec2(800.0, 600.0));
-    }
+/// `ti query` 的输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueryFormat {
+    Table,
+    Json,
+    Csv,
+}
 
-    fn warm_up_enabled(&self) -> bool {
-        false
+impl std::str::FromStr for QueryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(QueryFormat::Table),
+            "json" => Ok(QueryFormat::Json),
+            "csv" => Ok(QueryFormat::Csv),
+            v => Err(format!(
+                "不支持的输出格式 {}，只能是 table、json 或者 csv",
+                v
+            )),
+        }
     }
+}
 
-    fn save(&mut self, _storage: &mut dyn epi::Storage) {}
-
-    fn on_exit(&mut self) {
-        // 保存邮箱配置信息
-        fs::write(
-            "./email.txt",
-            format!(
-                "{}\n{}\n{}",
-                self.email_from, self.email_from_password, self.email_to
-            ),
-        )
-        .unwrap();
-
-        // 保存产品列表
-        fs::write("./products.txt", format!("{}", self.product_list)).unwrap();
+/// 解析命令行参数
+fn parse_args() -> Result<Mode, String> {
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(|v| v.as_str()) == Some("query") {
+        args.next();
+        return parse_query_args(args).map(Mode::Query);
     }
 
-    fn auto_save_interval(&self) -> std::time::Duration {
-        std::time::Duration::from_secs(30)
+    let mut mode = Mode::Gui;
+    for arg in args {
+        match arg.as_str() {
+            "--headless" => mode = Mode::Headless,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            v => return Err(format!("不认识的参数 {}", v)),
+        }
     }
+    Ok(mode)
+}
 
-    fn max_size_points(&self) -> egui::Vec2 {
-        egui::Vec2::new(1024.0, 2048.0)
+fn parse_query_args(mut args: impl Iterator<Item = String>) -> Result<QueryArgs, String> {
+    let mut format = QueryFormat::Table;
+    let mut opns = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => match args.next() {
+                Some(v) => format = v.parse()?,
+                None => return Err(format!("{} 后面要写输出格式", arg)),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "-" => opns.push(arg),
+            v if v.starts_with("--format=") => format = v["--format=".len()..].parse()?,
+            v if v.starts_with('-') => return Err(format!("不认识的参数 {}", v)),
+            v => opns.extend(split_opns(v)),
+        }
     }
+    Ok(QueryArgs { format, opns })
+}
 
-    fn clear_color(&self) -> egui::Rgba {
-        egui::Color32::from_rgba_unmultiplied(12, 12, 12, 180).into()
-    }
+/// 按空格、逗号、换行分开型号，去掉空的
+fn split_opns(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_owned())
+}
 
-    fn persist_native_window(&self) -> bool {
-        true
+/// 查询一次库存，按指定的格式输出到标准输出，返回退出码
+async fn run_query(args: QueryArgs) -> i32 {
+    let mut opns: Vec<String> = vec![];
+    let from_stdin = args.opns.is_empty() || args.opns.iter().any(|v| v == "-");
+    for opn in args.opns.into_iter().filter(|v| v != "-") {
+        opns.push(opn);
     }
+    if from_stdin {
+        use std::io::Read;
 
-    fn persist_egui_memory(&self) -> bool {
-        true
+        let mut text = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut text) {
+            eprintln!("读取标准输入出错:{}", e);
+            return QUERY_EXIT_USAGE;
+        }
+        opns.extend(split_opns(&text));
+    }
+    // 重复的型号只查一次，输出的顺序和输入的一样
+    let mut seen = HashSet::new();
+    opns.retain(|v| seen.insert(v.clone()));
+    if opns.is_empty() {
+        eprintln!("没有要查询的型号\n\n{}", USAGE);
+        return QUERY_EXIT_USAGE;
     }
-}
-
-#[tokio::main]
-async fn main() {
-    log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
 
-    // let options = eframe::NativeOptions::default();
+    // 有配置文件的话用里面的网络设置，没有就用默认的
+    let network = if Path::new(DEFAULT_CONFIG_PATH).exists() {
+        match Config::load(DEFAULT_CONFIG_PATH) {
+            Ok(v) => v.network,
+            Err(e) => {
+                eprintln!("{}", e);
+                return QUERY_EXIT_USAGE;
+            }
+        }
+    } else {
+        Default::default()
+    };
+    let account = match network.account() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            return QUERY_EXIT_USAGE;
+        }
+    };
 
-    let image_data = include_bytes!("../2.jpg");
-    use image::GenericImageView;
-    let image = image::load_from_memory(image_data).expect("Failed to load image");
-    let image_buffer = image.to_rgba8();
+    let mut results = account
+        .get_inventories(&opns, &network.batch_options())
+        .await;
+    let results: Vec<(String, Result<Inventory, AccountError>)> = opns
+        .into_iter()
+        .map(|opn| {
+            let res = results
+                .remove(&opn)
+                .unwrap_or_else(|| Err(AccountError::Task("没有返回结果".to_owned())));
+            (opn, res)
+        })
+        .collect();
+
+    let output = match args.format {
+        QueryFormat::Table => format_query_table(&results),
+        QueryFormat::Json => format_query_json(&results),
+        QueryFormat::Csv => format_query_csv(&results),
+    };
+    print!("{}", output);
+
+    let in_stock = results
+        .iter()
+        .any(|(_, res)| matches!(res, Ok(v) if v.inventory > 0));
+    if in_stock {
+        QUERY_EXIT_IN_STOCK
+    } else if results.iter().any(|(_, res)| res.is_err()) {
+        QUERY_EXIT_FAILED
+    } else {
+        QUERY_EXIT_OUT_OF_STOCK
+    }
+}
 
-    let options = eframe::NativeOptions {
-        resizable: false,
-        decorated: true, // 是否显示 标题栏 边框等
+/// 查询结果的一行: 型号、库存、生命周期、货期、美元单价、出错原因
+fn query_columns(opn: &str, res: &Result<Inventory, AccountError>) -> [String; 6] {
+    match res {
+        Ok(v) => [
+            opn.to_owned(),
+            v.inventory.to_string(),
+            v.lifecycle_status.clone().unwrap_or_default(),
+            v.lead_time.clone().unwrap_or_default(),
+            v.unit_price("USD", 1)
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+            String::new(),
+        ],
+        Err(e) => [
+            opn.to_owned(),
+            String::new(),
+            String::new(),
+            String::new(),
+            String::new(),
+            e.to_string(),
+        ],
+    }
+}
 
-        // 软件左上角图标
-        icon_data: Some(IconData {
-            rgba: image_buffer.to_vec(),
-            width: image.width(),
-            height: image.height(),
-        }),
+/// 终端里显示的宽度，中文等宽字符占两格
+fn display_width(text: &str) -> usize {
+    text.chars()
+        .map(|c| if (c as u32) < 0x1100 { 1 } else { 2 })
+        .sum()
+}
 
-        ..Default::default()
-    };
+fn format_query_table(results: &[(String, Result<Inventory, AccountError>)]) -> String {
+    let header =
+        ["型号", "库存", "生命周期", "货期", "单价(USD)", "出错原因"].map(|v| v.to_owned());
+    let rows: Vec<[String; 6]> = std::iter::once(header)
+        .chain(results.iter().map(|(opn, res)| query_columns(opn, res)))
+        .collect();
+    let mut widths = [0; 6];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(display_width(cell));
+        }
+    }
 
-    // 执行日志通道
-    let (sender_product_count_log, receiver_product_count_log) =
-        std::sync::mpsc::channel::<String>();
+    let mut out = String::new();
+    for row in &rows {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            if i > 0 {
+                line.push_str("  ");
+            }
+            let padding = " ".repeat(widths[i] - display_width(cell));
+            // 库存是数字，右对齐
+            if i == 1 {
+                line.push_str(&padding);
+                line.push_str(cell);
+            } else {
+                line.push_str(cell);
+                line.push_str(&padding);
+            }
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
 
-    // 产品名称列表通道
-    let (sender_product_name, receiver_product_name) = std::sync::mpsc::channel::<String>();
+fn format_query_json(results: &[(String, Result<Inventory, AccountError>)]) -> String {
+    let list: Vec<serde_json::Value> = results
+        .iter()
+        .map(|(opn, res)| match res {
+            Ok(v) => serde_json::json!({
+                "opn": opn,
+                "in_stock": v.inventory > 0,
+                "stock": v.inventory,
+                "inventory": v,
+            }),
+            Err(e) => serde_json::json!({
+                "opn": opn,
+                "in_stock": false,
+                "stock": null,
+                "error": e.to_string(),
+            }),
+        })
+        .collect();
+    let mut out = serde_json::to_string_pretty(&list).unwrap_or_default();
+    out.push('\n');
+    out
+}
 
-    // 邮箱信息通道
-    let (sender_email, receiver_email) = std::sync::mpsc::channel::<String>();
+fn format_query_csv(results: &[(String, Result<Inventory, AccountError>)]) -> String {
+    // 有逗号、引号或者换行的字段用引号括起来，里面的引号写两次
+    fn field(v: &str) -> String {
+        if v.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", v.replace('"', "\"\""))
+        } else {
+            v.to_owned()
+        }
+    }
 
-    let mut app = TiApp::default();
-    app.reciver_product_count_log = Some(receiver_product_count_log);
-    app.sender_product_name = Some(sender_product_name);
-    app.sender_email = Some(sender_email);
+    let mut out = String::from("opn,stock,lifecycle,lead_time,unit_price_usd,error\n");
+    for (opn, res) in results {
+        let row: Vec<String> = query_columns(opn, res).iter().map(|v| field(v)).collect();
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
 
-    thread::spawn(move || {
-        use tokio::runtime::Runtime;
+/// 不显示界面，按配置文件里的设置一直监控，直到收到退出信号，返回退出码
+async fn run_headless(config: Config) -> i32 {
+    let settings = match MonitorSettings::from_config(&config) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
 
-        let runtime = Runtime::new().unwrap();
+    let account = match config.network.account() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("{}", e);
+            return 1;
+        }
+    };
 
-        let sender_ui = sender_product_count_log.clone();
+    let (sender_command, receiver_command) =
+        tokio::sync::mpsc::unbounded_channel::<MonitorCommand>();
+    let source = AccountSource::new(account, config.network.batch_options());
+    let (mut monitor, mut events) = Monitor::builder(source).build(receiver_command);
 
-        sender_ui
-            .send("在左侧输入产品名称，一行一个，然后点击开始监控按钮".to_string())
-            .unwrap();
+    // 运行记录写到日志里，没有界面，监控状态和产品表格不需要
+    let logger = tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            if let Some(msg) = event.message() {
+                info!("{}", msg);
+            }
+        }
+    });
+    tokio::spawn(handle_signals(sender_command, config));
 
-        runtime.block_on(async move {
-            // 接收到列表才执行
-            let products;
+    let mut exit = monitor.run(Box::new(settings)).await;
+    while let MonitorExit::Restart(v) = exit {
+        exit = monitor.run(v).await;
+    }
+    // 等最后的运行记录写完再退出
+    drop(monitor);
+    let _ = logger.await;
+    0
+}
 
-            // 一直等待，直到接收到产品列表
-            loop {
-                if let Ok(v) = receiver_product_name.try_recv() {
-                    products = v
-                        .split("\n")
-                        .into_iter()
-                        .map(|v| v.trim().to_string())
-                        .collect::<Vec<String>>();
-                    break;
-                }
-                thread::sleep(Duration::from_millis(100));
+/// 收到 Ctrl+C 或者 SIGTERM 就停止监控，收到 SIGHUP 重新读取配置文件，用新的设置重新开始。
+/// `config` 是启动时读取的配置，`[network]` 和 `[logging]` 只在启动时用，改了要重启程序才会生效
+#[cfg_attr(not(unix), allow(unused_variables))]
+async fn handle_signals(
+    sender: tokio::sync::mpsc::UnboundedSender<MonitorCommand>,
+    config: Config,
+) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let (mut terminate, mut hangup) = match (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::hangup()),
+        ) {
+            (Ok(a), Ok(b)) => (a, b),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("监听退出信号失败:{}", e);
+                return;
             }
-
-            // 一直等待，直到接收到邮箱信息
-            let email_from;
-            let email_from_password;
-            let email_to;
-            loop {
-                if let Ok(v) = receiver_email.try_recv() {
-                    let mut t = v.split("\n");
-                    email_from = t.next().unwrap().to_string();
-                    email_from_password = t.next().unwrap().to_string();
-                    email_to = t.next().unwrap().to_string();
-                    break;
+        };
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => break,
+                _ = terminate.recv() => break,
+                _ = hangup.recv() => {
+                    let settings = Config::load(DEFAULT_CONFIG_PATH).and_then(|v| {
+                        MonitorSettings::from_config(&v).map(|settings| (v, settings))
+                    });
+                    match settings {
+                        Ok((v, settings)) => {
+                            if v.network != config.network {
+                                warn!("配置文件里 [network] 的修改要重启程序才会生效");
+                            }
+                            if v.logging != config.logging {
+                                warn!("配置文件里 [logging] 的修改要重启程序才会生效");
+                            }
+                            info!("重新读取了配置文件，用新的设置重新开始");
+                            let _ = sender.send(MonitorCommand::Start(Box::new(settings)));
+                        }
+                        Err(e) => error!("{}，继续用原来的设置", e),
+                    }
                 }
-                thread::sleep(Duration::from_millis(100));
             }
-            debug!("要监控的产品列表:{:#?}", products);
-
-            let account = Account::new().await;
-
-            // let product_name = "OPA1622IDRCR";
-
-            // hashmap记录有库存是否通知
-            let notices = Arc::new(Mutex::new(HashMap::new()));
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("监听退出信号失败:{}", e);
+            return;
+        }
+    }
 
-            // 记录当前几个任务在执行
-            let mut tasks = vec![];
-            // let tasks = Mutex::new(tasks);
+    info!("收到退出信号，正在停止监控");
+    let _ = sender.send(MonitorCommand::Stop);
+}
 
-            loop {
-                for product_name in &products {
-                    // 忽略空行
-                    if product_name.is_empty() {
-                        continue;
-                    }
+/// 有界面的版本在 Windows 上是窗口程序，没有控制台，在命令行里运行 `query`、`--headless` 的时候
+/// 连到启动它的命令行窗口，不然输出都看不到。标准输出重定向到文件或者管道的时候不用连
+#[cfg(all(windows, feature = "gui"))]
+fn attach_console() {
+    use std::ffi::c_void;
 
-                    let product_name = product_name.clone();
-
-                    let sender_ui = sender_ui.clone();
-
-                    let email_from = email_from.clone();
-                    let email_from_password = email_from_password.clone();
-                    let email_to = email_to.clone();
-
-                    let account = account.clone();
-                    let notices = notices.clone();
-
-                    let v = tokio::spawn(async move {
-                        sender_ui
-                            .send(format!("正在获取 {} 的库存", product_name))
-                            .unwrap();
-
-                        let count = match account
-                            .get_store_by_product_name(product_name.as_str())
-                            .await
-                        {
-                            Ok(v) => v,
-                            Err(e) => {
-                                sender_ui
-                                    .send(format!(
-                                        "获取产品 {} 库存失败，请检查产品名字是否正确",
-                                        product_name
-                                    ))
-                                    .unwrap();
-                                info!("获取失败:{}", e);
-                                0 as usize
-                            }
-                        };
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
 
-                        sender_ui
-                            .send(format!("产品: {}, 库存: {}", product_name, count))
-                            .unwrap();
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+        fn GetStdHandle(std_handle: u32) -> *mut c_void;
+    }
 
-                        let mut t = notices.lock().await;
+    unsafe {
+        let stdout = GetStdHandle(STD_OUTPUT_HANDLE);
+        if !stdout.is_null() && stdout as isize != -1 {
+            return;
+        }
+        // 双击启动的时候没有可以连的命令行窗口，失败了也没关系
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
 
-                        // 如果对应产品有库存，但是没有记录，就发邮件通知并记录一下
-                        if count > 0 && t.get(&product_name).is_none() {
-                            send_email(
-                                email_from.as_str(),
-                                email_from_password.as_str(),
-                                email_to.as_str(),
-                                format!("{} 产品有 {} 个新库存", product_name, count).as_str(),
-                                format!("{} 产品有 {} 个新库存", product_name, count).as_str(),
-                            );
+/// 其它系统和没有界面的版本本来就有控制台
+#[cfg(not(all(windows, feature = "gui")))]
+fn attach_console() {}
 
-                            t.insert(product_name.clone(), true);
-                        }
+/// 旧版本的日志设置文件，现在只看配置文件里的 `[logging]`
+const LEGACY_LOG_CONFIG: &str = "log4rs.yaml";
 
-                        // 如果库存为0  就把之前的记录给删除
-                        if count == 0 && t.get(&product_name).is_some() {
-                            t.remove(&product_name);
-                        }
+/// 按配置文件里的 `[logging]` 初始化日志，日志文件打不开之类的问题初始化之后写到日志里
+fn init_logging(logging: &LoggingConfig) {
+    use log4rs::{
+        append::{console::ConsoleAppender, file::FileAppender},
+        config::{Appender, Config as LogConfig, Root},
+        encode::pattern::PatternEncoder,
+    };
 
-                        info!("库存:{}", count);
-                    });
+    let mut problems = vec![];
+    if Path::new(LEGACY_LOG_CONFIG).exists() {
+        problems.push(format!(
+            "{} 已经不再使用，日志设置以配置文件里的 [logging] 为准",
+            LEGACY_LOG_CONFIG
+        ));
+    }
 
-                    tasks.push(v);
+    let mut builder = LogConfig::builder();
+    let mut root = Root::builder();
 
-                    if tasks.len() == 4 {
-                        while let Some(task) = tasks.pop() {
-                            task.await;
-                        }
-                    }
+    if logging.stdout {
+        builder = builder.appender(
+            Appender::builder().build("stdout", Box::new(ConsoleAppender::builder().build())),
+        );
+        root = root.appender("stdout");
+    }
 
-                    // loop {
-                    //     // let tasks = tasks.lock().await;
-                    //     if tasks > 0 {
-                    //         tokio::time::sleep(Duration::from_millis(300)).await;
-                    //     }
-                    //     break;
-                    // }
-                }
+    if let Some(file) = &logging.file {
+        match FileAppender::builder()
+            .encoder(Box::new(PatternEncoder::new("{d} - {m}{n}")))
+            .build(file)
+        {
+            Ok(v) => {
+                builder = builder.appender(Appender::builder().build("file", Box::new(v)));
+                root = root.appender("file");
             }
-        });
-    });
-    eframe::run_native(Box::new(app), options);
-}
+            Err(e) => problems.push(format!("打开日志文件 {} 出错:{}", file, e)),
+        }
+    }
 
-/// 发送邮件通知
-/// 173126019@qq.com
-/// jslmkwghsxdjbgji
-fn send_email(from: &str, password: &str, to: &str, title: &str, body: &str) {
-    use lettre::smtp::authentication::Credentials;
-    use lettre::{SmtpClient, Transport};
-    use lettre_email::EmailBuilder;
-
-    let email = EmailBuilder::new()
-        .from(from)
-        .to(to)
-        .subject(title)
-        .html(body)
-        .build()
-        .unwrap();
-
-    let mut mailer = SmtpClient::new_simple("smtp.qq.com")
-        .unwrap()
-        .credentials(Credentials::new(from.into(), password.into()))
-        .transport();
-
-    mailer.send(email.into()).unwrap();
+    let level = logging.level_filter().unwrap_or(log::LevelFilter::Debug);
+    let res = match builder.build(root.build(level)) {
+        Ok(v) => log4rs::init_config(v).map_err(|e| format!("初始化日志出错:{}", e)),
+        Err(e) => Err(format!("日志设置不正确:{}", e)),
+    };
+    match res {
+        Ok(_) => {
+            for v in problems {
+                warn!("{}", v);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            for v in problems {
+                eprintln!("{}", v);
+            }
+        }
+    }
 }
@@ -0,0 +1,1319 @@
+//! 库存监控
+//!
+//! [`Monitor`] 按查询计划一直查询库存，满足提醒规则就放到发件箱里发送通知。
+//! 前端(界面、命令行)通过 [`MonitorCommand`] 控制它，通过 [`MonitorEvent`] 知道查询的结果。
+//!
+//! 查询库存用的 [`InventorySource`]、时间 [`Clock`] 和通知渠道都可以换掉，
+//! 测试的时候不需要真的请求 ti.com，也不需要真的等待。
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use log::{debug, error, info, warn};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::account::{Account, BatchOptions, BatchResult};
+use crate::alert::{AlertRule, AlertTracker, DEFAULT_ALERT_STATE_PATH};
+use crate::config::{Config, ConfigError, HistoryConfig};
+use crate::error::AccountError;
+use crate::history::{History, Reading};
+use crate::notify::Notifier;
+use crate::outbox::{DeliveryEvent, Dispatcher, RetryPolicy, DEFAULT_OUTBOX_PATH};
+use crate::products::{format_product_list, parse_product_list};
+use crate::schedule::{Schedule, Scheduler};
+use crate::template::{AlertTemplates, StockAlert};
+use crate::throttle::ThrottleState;
+
+/// 查询库存的地方
+#[async_trait]
+pub trait InventorySource: Send + Sync {
+    /// 查询一轮产品的库存，每个型号一个结果
+    async fn get_inventories(&self, opns: &[&str]) -> BatchResult;
+
+    /// 产品的购买页面，放在通知里
+    fn product_url(&self, opn: &str) -> String;
+
+    /// 当前的限速和熔断状态
+    fn throttle_state(&self) -> ThrottleState {
+        ThrottleState::Closed
+    }
+}
+
+/// 测试的时候可以留一份 `Arc` 在外面修改返回的库存
+#[async_trait]
+impl<T: InventorySource + ?Sized> InventorySource for Arc<T> {
+    async fn get_inventories(&self, opns: &[&str]) -> BatchResult {
+        (**self).get_inventories(opns).await
+    }
+
+    fn product_url(&self, opn: &str) -> String {
+        (**self).product_url(opn)
+    }
+
+    fn throttle_state(&self) -> ThrottleState {
+        (**self).throttle_state()
+    }
+}
+
+/// 通过 [`Account`] 在 ti.com 查询库存
+#[derive(Debug, Clone)]
+pub struct AccountSource {
+    account: Account,
+    options: BatchOptions,
+}
+
+impl AccountSource {
+    pub fn new(account: Account, options: BatchOptions) -> Self {
+        AccountSource { account, options }
+    }
+}
+
+#[async_trait]
+impl InventorySource for AccountSource {
+    async fn get_inventories(&self, opns: &[&str]) -> BatchResult {
+        self.account.get_inventories(opns, &self.options).await
+    }
+
+    fn product_url(&self, opn: &str) -> String {
+        self.account.product_url(opn)
+    }
+
+    fn throttle_state(&self) -> ThrottleState {
+        self.account.throttle_state()
+    }
+}
+
+/// 监控用的时间，测试的时候可以换成手动拨动的时钟
+#[async_trait]
+pub trait Clock: Send + Sync {
+    /// 现在的时间，用来判断静默时段、记录查询时间、判断重新提醒间隔和删除过期的历史
+    fn now(&self) -> DateTime<Local>;
+
+    /// 用来安排下次查询的时间
+    fn instant(&self) -> Instant;
+
+    /// 等待 `duration`
+    async fn sleep(&self, duration: Duration);
+}
+
+/// 测试的时候可以留一份 `Arc` 在外面拨动时间
+#[async_trait]
+impl<T: Clock + ?Sized> Clock for Arc<T> {
+    fn now(&self) -> DateTime<Local> {
+        (**self).now()
+    }
+
+    fn instant(&self) -> Instant {
+        (**self).instant()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        (**self).sleep(duration).await
+    }
+}
+
+/// 系统时间
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// 前端发给监控的命令
+pub enum MonitorCommand {
+    /// 用新的设置开始监控，正在监控的话用新的设置重新开始
+    Start(Box<MonitorSettings>),
+    Pause,
+    Resume,
+    Stop,
+    /// 产品列表修改了，正在监控的话马上生效
+    UpdateProducts(String),
+    /// 通知配置修改了，正在监控的话马上生效
+    UpdateNotifiers(Vec<Arc<dyn Notifier>>),
+    /// 马上查询这个产品，不存在的型号也重新查询
+    CheckNow(String),
+}
+
+/// 开始监控时需要的设置
+pub struct MonitorSettings {
+    /// 产品列表，格式见 [`parse_product_list`]
+    pub product_list: String,
+    pub notifiers: Vec<Arc<dyn Notifier>>,
+    pub schedule: Schedule,
+    pub templates: AlertTemplates,
+    /// 库存一直满足提醒规则时重新提醒的间隔
+    pub renotify: Option<Duration>,
+    /// 库存历史设置
+    pub history: HistoryConfig,
+}
+
+impl MonitorSettings {
+    /// 按配置文件里的设置开始监控，没有产品或者没有启用通知渠道的话返回错误
+    pub fn from_config(config: &Config) -> Result<MonitorSettings, ConfigError> {
+        let invalid = |msg: String| ConfigError::Invalid(vec![msg]);
+        let product_list = format_product_list(&config.products);
+        if product_list.is_empty() {
+            return Err(invalid("配置文件里没有要监控的产品".to_owned()));
+        }
+        let notifiers = crate::notify::from_config(config).map_err(|e| invalid(e.to_string()))?;
+        if notifiers.is_empty() {
+            return Err(invalid("配置文件里没有启用任何通知渠道".to_owned()));
+        }
+        Ok(MonitorSettings {
+            product_list,
+            notifiers,
+            schedule: config.polling.clone(),
+            templates: config.templates.load()?,
+            renotify: config.alerts.renotify,
+            history: config.history.clone(),
+        })
+    }
+}
+
+/// 监控的运行状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MonitorStatus {
+    #[default]
+    Stopped,
+    Running,
+    Paused,
+}
+
+/// [`Monitor::run`] 为什么结束了
+pub enum MonitorExit {
+    /// 收到了停止命令，或者前端已经关闭
+    Stop,
+    /// 收到了新的设置，要用它重新开始
+    Restart(Box<MonitorSettings>),
+}
+
+/// 处理完命令之后监控循环要怎么做
+enum Control {
+    /// 接着运行
+    Continue,
+    /// 结束运行
+    Exit(MonitorExit),
+}
+
+/// 监控运行中发生的事情，通过 [`MonitorBuilder::build`] 返回的通道告诉前端
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MonitorEvent {
+    /// 运行状态变了
+    Status(MonitorStatus),
+    /// 其它运行记录，例如开始监控、产品列表更新了、进入静默时段
+    Log(String),
+    /// 查到了库存，`previous` 是上次查到的库存
+    StockChecked {
+        opn: String,
+        time: DateTime<Local>,
+        stock: usize,
+        previous: Option<usize>,
+    },
+    /// 库存和上次查到的不一样，紧跟在对应的 `StockChecked` 后面
+    StockChanged {
+        opn: String,
+        time: DateTime<Local>,
+        from: usize,
+        to: usize,
+    },
+    /// 查询失败，`invalid` 表示型号不存在，之后不再查询
+    CheckFailed {
+        opn: String,
+        time: DateTime<Local>,
+        error: String,
+        invalid: bool,
+    },
+    /// 库存通知已经通过 `channel` 发送出去
+    AlertSent { opn: String, channel: String },
+}
+
+impl MonitorEvent {
+    /// 显示在运行记录里的文字，运行状态和库存变化没有单独的记录
+    pub fn message(&self) -> Option<String> {
+        match self {
+            MonitorEvent::Status(_) | MonitorEvent::StockChanged { .. } => None,
+            MonitorEvent::Log(v) => Some(v.clone()),
+            MonitorEvent::StockChecked { opn, stock, .. } => {
+                Some(format!("产品: {}, 库存: {}", opn, stock))
+            }
+            MonitorEvent::CheckFailed {
+                opn, invalid: true, ..
+            } => Some(format!(
+                "产品 {} 不存在，请检查产品名字是否正确，已停止查询",
+                opn
+            )),
+            MonitorEvent::CheckFailed { opn, error, .. } => {
+                Some(format!("获取产品 {} 库存失败:{}", opn, error))
+            }
+            MonitorEvent::AlertSent { opn, channel } => {
+                Some(format!("产品 {} 的库存通知已通过 {} 发送", opn, channel))
+            }
+        }
+    }
+}
+
+/// 用来配置并创建 [`Monitor`]
+///
+/// ```no_run
+/// # async fn run() {
+/// use ti::monitor::{AccountSource, Monitor, MonitorCommand};
+/// use ti::{Account, BatchOptions};
+///
+/// let source = AccountSource::new(Account::builder().build().unwrap(), BatchOptions::default());
+/// let (commands, receiver) = tokio::sync::mpsc::unbounded_channel::<MonitorCommand>();
+/// let (mut monitor, mut events) = Monitor::builder(source).build(receiver);
+/// tokio::spawn(async move { monitor.serve().await });
+/// while let Some(event) = events.recv().await {
+///     if let Some(msg) = event.message() {
+///         println!("{}", msg);
+///     }
+/// }
+/// # drop(commands);
+/// # }
+/// ```
+pub struct MonitorBuilder {
+    source: Arc<dyn InventorySource>,
+    clock: Arc<dyn Clock>,
+    outbox: PathBuf,
+    retry_policy: RetryPolicy,
+    alert_state: Option<PathBuf>,
+    jitter_seed: Option<u64>,
+}
+
+impl MonitorBuilder {
+    /// 替换时间，默认是系统时间
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// 发件箱文件，默认是 [`DEFAULT_OUTBOX_PATH`]
+    pub fn outbox<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.outbox = path.into();
+        self
+    }
+
+    /// 通知发送失败之后的重试策略
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// 保存提醒记录的文件，默认是 [`DEFAULT_ALERT_STATE_PATH`]，`None` 只保存在内存里
+    pub fn alert_state<P: Into<PathBuf>>(mut self, path: Option<P>) -> Self {
+        self.alert_state = path.map(Into::into);
+        self
+    }
+
+    /// 随机抖动用固定的种子，和 [`MonitorBuilder::clock`] 一起用可以让每次运行的查询时间都一样
+    pub fn jitter_seed(mut self, seed: u64) -> Self {
+        self.jitter_seed = Some(seed);
+        self
+    }
+
+    /// 创建监控，通过 `commands` 接收前端的命令，返回的通道接收运行中的事件。
+    /// 要在 tokio 运行时里调用，通知在后台发送，上次没发完的会继续发送。
+    pub fn build(
+        self,
+        commands: UnboundedReceiver<MonitorCommand>,
+    ) -> (Monitor, UnboundedReceiver<MonitorEvent>) {
+        let (dispatcher, deliveries) = Dispatcher::spawn(&self.outbox, self.retry_policy);
+        let (events, events_receiver) = unbounded_channel();
+        let monitor = Monitor {
+            dispatcher,
+            deliveries,
+            source: self.source,
+            clock: self.clock,
+            alert_state: self.alert_state,
+            jitter_seed: self.jitter_seed,
+            commands,
+            events,
+            pending_products: None,
+            pending_notifiers: None,
+            pending_checks: vec![],
+        };
+        (monitor, events_receiver)
+    }
+}
+
+/// 按照设置一直查询库存，通过 `commands` 接收前端的命令
+pub struct Monitor {
+    dispatcher: Dispatcher,
+    // 通知的发送结果
+    deliveries: UnboundedReceiver<DeliveryEvent>,
+    source: Arc<dyn InventorySource>,
+    clock: Arc<dyn Clock>,
+    // 保存提醒记录的文件
+    alert_state: Option<PathBuf>,
+    // 随机抖动的种子，`None` 每次都不一样
+    jitter_seed: Option<u64>,
+    commands: UnboundedReceiver<MonitorCommand>,
+    events: UnboundedSender<MonitorEvent>,
+    // 运行中收到的还没有生效的产品列表
+    pending_products: Option<String>,
+    // 运行中收到的还没有生效的通知渠道
+    pending_notifiers: Option<Vec<Arc<dyn Notifier>>>,
+    // 要马上查询的产品
+    pending_checks: Vec<String>,
+}
+
+/// 一条库存通知在各个渠道的发送情况
+struct Sending {
+    /// 还没有结果的渠道
+    pending: HashSet<String>,
+    /// 有渠道遇到了不会自己恢复的错误
+    permanent: bool,
+}
+
+impl Monitor {
+    pub fn builder<S: InventorySource + 'static>(source: S) -> MonitorBuilder {
+        MonitorBuilder {
+            source: Arc::new(source),
+            clock: Arc::new(SystemClock),
+            outbox: PathBuf::from(DEFAULT_OUTBOX_PATH),
+            retry_policy: RetryPolicy::default(),
+            alert_state: Some(PathBuf::from(DEFAULT_ALERT_STATE_PATH)),
+            jitter_seed: None,
+        }
+    }
+
+    fn emit(&self, event: MonitorEvent) {
+        let _ = self.events.send(event);
+    }
+
+    fn log(&self, msg: String) {
+        self.emit(MonitorEvent::Log(msg));
+    }
+
+    fn set_status(&self, status: MonitorStatus) {
+        self.emit(MonitorEvent::Status(status));
+    }
+
+    /// 一直运行，停止之后等待下一次开始，前端关闭了才返回
+    pub async fn serve(&mut self) {
+        let mut settings = match self.wait_for_start().await {
+            Some(v) => v,
+            None => return,
+        };
+        loop {
+            settings = match self.run(settings).await {
+                MonitorExit::Restart(v) => v,
+                MonitorExit::Stop => match self.wait_for_start().await {
+                    Some(v) => v,
+                    None => return,
+                },
+            };
+        }
+    }
+
+    /// 等待开始监控的命令，前端关闭了就返回 `None`
+    pub async fn wait_for_start(&mut self) -> Option<Box<MonitorSettings>> {
+        // 开始的时候会带上最新的设置，之前没生效的修改不需要了
+        self.pending_products = None;
+        self.pending_notifiers = None;
+        self.pending_checks.clear();
+        loop {
+            match self.commands.recv().await {
+                Some(MonitorCommand::Start(v)) => return Some(v),
+                Some(_) => continue,
+                None => return None,
+            }
+        }
+    }
+
+    /// 处理一个命令，暂停的话一直等到继续或者停止
+    async fn handle(&mut self, command: Option<MonitorCommand>) -> Control {
+        match command {
+            Some(MonitorCommand::Pause) => {
+                self.set_status(MonitorStatus::Paused);
+                self.log("监控已暂停".to_owned());
+                loop {
+                    match self.commands.recv().await {
+                        Some(MonitorCommand::Resume) => {
+                            self.set_status(MonitorStatus::Running);
+                            self.log("继续监控".to_owned());
+                            return Control::Continue;
+                        }
+                        Some(MonitorCommand::Pause) => continue,
+                        Some(MonitorCommand::UpdateProducts(v)) => {
+                            self.pending_products = Some(v);
+                        }
+                        Some(MonitorCommand::UpdateNotifiers(v)) => {
+                            self.pending_notifiers = Some(v);
+                        }
+                        Some(MonitorCommand::CheckNow(v)) => self.pending_checks.push(v),
+                        Some(MonitorCommand::Start(v)) => {
+                            return Control::Exit(MonitorExit::Restart(v))
+                        }
+                        Some(MonitorCommand::Stop) | None => {
+                            return Control::Exit(MonitorExit::Stop)
+                        }
+                    }
+                }
+            }
+            Some(MonitorCommand::Resume) => Control::Continue,
+            Some(MonitorCommand::UpdateProducts(v)) => {
+                self.pending_products = Some(v);
+                Control::Continue
+            }
+            Some(MonitorCommand::UpdateNotifiers(v)) => {
+                self.pending_notifiers = Some(v);
+                Control::Continue
+            }
+            Some(MonitorCommand::CheckNow(v)) => {
+                self.pending_checks.push(v);
+                Control::Continue
+            }
+            Some(MonitorCommand::Start(v)) => Control::Exit(MonitorExit::Restart(v)),
+            Some(MonitorCommand::Stop) | None => Control::Exit(MonitorExit::Stop),
+        }
+    }
+
+    /// 等待一段时间，期间收到命令马上处理
+    async fn sleep(&mut self, duration: Duration) -> Control {
+        let clock = self.clock.clone();
+        tokio::select! {
+            _ = clock.sleep(duration) => Control::Continue,
+            command = self.commands.recv() => self.handle(command).await,
+        }
+    }
+
+    /// 处理已经收到但还没处理的命令
+    async fn poll_commands(&mut self) -> Control {
+        loop {
+            match self.commands.try_recv() {
+                Ok(command) => match self.handle(Some(command)).await {
+                    Control::Continue => continue,
+                    v => return v,
+                },
+                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => return Control::Continue,
+                Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
+                    return Control::Exit(MonitorExit::Stop)
+                }
+            }
+        }
+    }
+
+    /// 处理通知的发送结果，所有渠道都发送失败的产品下次查询时重新判断要不要提醒，
+    /// 有渠道遇到不会自己恢复的错误(例如密码错误)的不再重新提醒，免得一直发送失败
+    fn handle_deliveries(
+        &mut self,
+        sending: &mut HashMap<String, Sending>,
+        tracker: &mut AlertTracker,
+    ) {
+        while let Ok(event) = self.deliveries.try_recv() {
+            match event {
+                DeliveryEvent::Sent { key, channel } => {
+                    // 任何一个渠道发送成功就算通知过了
+                    sending.remove(&key);
+                    self.emit(MonitorEvent::AlertSent { opn: key, channel });
+                }
+                DeliveryEvent::Retrying {
+                    key,
+                    channel,
+                    attempts,
+                    retry_in,
+                    error,
+                } => {
+                    self.log(format!(
+                        "产品 {} 的库存通知通过 {} 第 {} 次发送失败，{} 秒后重试:{}",
+                        key,
+                        channel,
+                        attempts,
+                        retry_in.as_secs(),
+                        error
+                    ));
+                }
+                DeliveryEvent::Failed {
+                    key,
+                    channel,
+                    error,
+                    permanent,
+                } => {
+                    self.log(format!(
+                        "产品 {} 的库存通知通过 {} 发送失败，已放弃:{}",
+                        key, channel, error
+                    ));
+                    let state = match sending.get_mut(&key) {
+                        Some(v) => v,
+                        None => continue,
+                    };
+                    state.pending.remove(&channel);
+                    state.permanent |= permanent;
+                    if !state.pending.is_empty() {
+                        continue;
+                    }
+                    // 所有渠道都失败了，下次查询还满足条件会重新提醒
+                    if let Some(state) = sending.remove(&key) {
+                        if !state.permanent {
+                            tracker.unsent(&key);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// 解析产品列表，返回要监控的型号，列表里单独设置的间隔覆盖到 `schedule` 里，
+    /// 提醒规则放到 `rules` 里
+    fn parse_products(
+        &self,
+        product_list: &str,
+        base_overrides: &HashMap<String, u64>,
+        schedule: &mut Schedule,
+        rules: &mut HashMap<String, Vec<AlertRule>>,
+    ) -> Vec<String> {
+        let (entries, errors) = parse_product_list(product_list);
+        for e in errors {
+            self.log(format!("产品列表{}", e));
+        }
+
+        // 产品列表里单独设置的间隔优先
+        schedule.overrides = base_overrides.clone();
+        for entry in &entries {
+            if let Some(interval) = entry.interval {
+                schedule
+                    .overrides
+                    .insert(entry.opn.clone(), interval.as_secs());
+            }
+        }
+
+        rules.clear();
+        for entry in &entries {
+            if !entry.alerts.is_empty() {
+                rules.insert(entry.opn.clone(), entry.alerts.clone());
+            }
+        }
+
+        entries.into_iter().map(|v| v.opn).collect()
+    }
+
+    /// 按照设置一直查询库存，直到收到停止或者重新开始的命令
+    pub async fn run(&mut self, settings: Box<MonitorSettings>) -> MonitorExit {
+        let exit = self.run_until_exit(settings).await;
+        if let MonitorExit::Stop = exit {
+            self.set_status(MonitorStatus::Stopped);
+            self.log("监控已停止".to_owned());
+        }
+        exit
+    }
+
+    async fn run_until_exit(&mut self, settings: Box<MonitorSettings>) -> MonitorExit {
+        let MonitorSettings {
+            product_list,
+            notifiers,
+            mut schedule,
+            templates,
+            renotify,
+            history,
+        } = *settings;
+
+        self.set_status(MonitorStatus::Running);
+        self.log("开始监控".to_owned());
+
+        // 配置文件里单独设置的间隔，产品列表修改之后在这个基础上重新覆盖
+        let base_overrides = schedule.overrides.clone();
+        // 每个产品的提醒规则，没有设置的产品有库存就提醒
+        let mut rules: HashMap<String, Vec<AlertRule>> = HashMap::new();
+        let mut products =
+            self.parse_products(&product_list, &base_overrides, &mut schedule, &mut rules);
+        debug!("要监控的产品列表:{:#?}", products);
+
+        // 发件箱里每条通知发给这些渠道
+        let mut channels: HashSet<String> = notifiers.iter().map(|v| v.name().to_owned()).collect();
+        self.dispatcher.set_notifiers(notifiers);
+
+        // 每个产品的库存变化，按提醒规则判断什么时候提醒，重启之后接着上次的记录，
+        // 已经提醒过的产品不会重复提醒
+        let mut tracker = match &self.alert_state {
+            Some(path) => AlertTracker::load(path),
+            None => AlertTracker::new(),
+        };
+        tracker.set_renotify(renotify);
+        tracker.retain(|opn| products.iter().any(|v| v == opn));
+        tracker.save();
+
+        // 每次查询的结果保存到库存历史，打开失败就不保存。
+        // SQLite 读写文件会阻塞，都放到 `spawn_blocking` 里
+        let retention = history.retention();
+        let mut history = if history.enabled {
+            let path = history.path.clone();
+            let res = match tokio::task::spawn_blocking(move || History::open(path)).await {
+                Ok(v) => v.map_err(|e| e.to_string()),
+                Err(e) => Err(format!("打开库存历史的任务出错:{}", e)),
+            };
+            match res {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    self.log(format!("{}，这次不保存库存历史", e));
+                    error!("{}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // 上次删除过期历史的时间
+        let mut pruned_at: Option<Instant> = None;
+
+        // 通知还在发件箱里没有发出去的产品
+        let mut sending: HashMap<String, Sending> = HashMap::new();
+
+        // 型号不存在的产品，后面就不再查询了
+        let mut invalid_products: HashSet<String> = HashSet::new();
+
+        // 记录每个产品下次什么时候查询
+        let mut scheduler = match self.jitter_seed {
+            Some(seed) => Scheduler::with_seed(seed),
+            None => Scheduler::new(),
+        };
+
+        // 是否处于静默时段
+        let mut quiet = false;
+
+        // 上一次的限速和熔断状态
+        let mut throttle_state = ThrottleState::Closed;
+
+        loop {
+            if let Control::Exit(v) = self.poll_commands().await {
+                return v;
+            }
+
+            // 运行中修改了产品列表，新增的产品马上查询，删除的产品清掉记录
+            if let Some(product_list) = self.pending_products.take() {
+                let new_products =
+                    self.parse_products(&product_list, &base_overrides, &mut schedule, &mut rules);
+                let added = new_products
+                    .iter()
+                    .filter(|v| !products.contains(v))
+                    .count();
+                let mut removed = 0;
+                for product_name in &products {
+                    if !new_products.contains(product_name) {
+                        removed += 1;
+                        sending.remove(product_name);
+                        tracker.forget(product_name);
+                        invalid_products.remove(product_name);
+                        scheduler.reset(product_name);
+                    }
+                }
+                products = new_products;
+                debug!("要监控的产品列表:{:#?}", products);
+                self.log(format!(
+                    "产品列表已更新，新增 {} 个，删除 {} 个",
+                    added, removed
+                ));
+            }
+
+            if let Some(notifiers) = self.pending_notifiers.take() {
+                channels = notifiers.iter().map(|v| v.name().to_owned()).collect();
+                self.dispatcher.set_notifiers(notifiers);
+                self.log("通知配置已更新".to_owned());
+            }
+
+            for product_name in self.pending_checks.drain(..) {
+                if products.contains(&product_name) {
+                    invalid_products.remove(&product_name);
+                    scheduler.reset(&product_name);
+                }
+            }
+
+            self.handle_deliveries(&mut sending, &mut tracker);
+            tracker.save();
+
+            if schedule.is_quiet(self.clock.now().time()) {
+                if !quiet {
+                    quiet = true;
+                    self.log("现在是静默时段，暂停查询".to_owned());
+                }
+                match self.sleep(Duration::from_secs(30)).await {
+                    Control::Continue => continue,
+                    Control::Exit(v) => return v,
+                }
+            }
+            if quiet {
+                quiet = false;
+                self.log("静默时段结束，继续查询".to_owned());
+            }
+
+            // 忽略不存在的型号
+            let active: Vec<&str> = products
+                .iter()
+                .map(String::as_str)
+                .filter(|v| !invalid_products.contains(*v))
+                .collect();
+
+            // 只查询到时间的产品，都没到时间就等到最早的那个
+            let now = self.clock.instant();
+            let round = scheduler.due(active.iter().copied(), now);
+            if round.is_empty() {
+                let wait = scheduler
+                    .next_wakeup(active.iter().copied())
+                    .map(|v| v.saturating_duration_since(now))
+                    .unwrap_or(Duration::from_secs(1))
+                    .min(Duration::from_secs(30));
+                match self.sleep(wait).await {
+                    Control::Continue => continue,
+                    Control::Exit(v) => return v,
+                }
+            }
+
+            self.log(format!("正在获取 {} 个产品的库存", round.len()));
+
+            let mut results = self.source.get_inventories(&round).await;
+            let checked_at = self.clock.now();
+            let mut readings = Vec::with_capacity(round.len());
+
+            // 是否有产品被限流或者拦截了
+            let mut throttled = false;
+
+            scheduler.checked(&schedule, round.iter().copied(), self.clock.instant());
+
+            for product_name in round {
+                let failed = |error: &AccountError, invalid| MonitorEvent::CheckFailed {
+                    opn: product_name.to_owned(),
+                    time: checked_at,
+                    error: error.to_string(),
+                    invalid,
+                };
+                let inventory = match results.remove(product_name) {
+                    Some(Ok(v)) => v,
+                    Some(Err(e @ AccountError::UnknownPart(_))) => {
+                        readings.push(Reading::failed(product_name, checked_at, e.to_string()));
+                        self.emit(failed(&e, true));
+                        invalid_products.insert(product_name.to_owned());
+                        continue;
+                    }
+                    Some(Err(e)) if e.is_throttled() => {
+                        // 被限流或者拦截了，恢复之后马上重新查询，库存状态保持不变
+                        throttled = true;
+                        scheduler.reset(product_name);
+                        // 熔断中没有真的去查询，不用记到历史里
+                        if !matches!(e, AccountError::CircuitOpen(_)) {
+                            readings.push(Reading::failed(product_name, checked_at, e.to_string()));
+                            self.emit(failed(&e, false));
+                        }
+                        warn!("获取失败:{}", e);
+                        continue;
+                    }
+                    Some(Err(e)) => {
+                        // 其它错误不知道真实库存，下一轮再查
+                        readings.push(Reading::failed(product_name, checked_at, e.to_string()));
+                        self.emit(failed(&e, false));
+                        info!("获取失败:{}", e);
+                        continue;
+                    }
+                    None => continue,
+                };
+
+                let count = inventory.inventory;
+                let previous = tracker.last(product_name);
+                readings.push(Reading::stock(product_name, checked_at, count));
+                self.emit(MonitorEvent::StockChecked {
+                    opn: product_name.to_owned(),
+                    time: checked_at,
+                    stock: count,
+                    previous,
+                });
+                if let Some(from) = previous.filter(|v| *v != count) {
+                    self.emit(MonitorEvent::StockChanged {
+                        opn: product_name.to_owned(),
+                        time: checked_at,
+                        from,
+                        to: count,
+                    });
+                }
+
+                // 满足提醒规则就放到发件箱里发送
+                let rules = rules
+                    .get(product_name)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                if let Some(alert) = tracker.update(product_name, rules, count, checked_at) {
+                    let others = tracker
+                        .in_stock()
+                        .into_iter()
+                        .filter(|(k, _)| k != product_name)
+                        .collect();
+                    let alert = StockAlert {
+                        opn: product_name,
+                        inventory: &inventory,
+                        alert: &alert,
+                        url: self.source.product_url(product_name),
+                        time: checked_at,
+                        others,
+                    };
+                    self.dispatcher.send(templates.render(&alert));
+                    if !channels.is_empty() {
+                        sending.insert(
+                            product_name.to_owned(),
+                            Sending {
+                                pending: channels.clone(),
+                                permanent: false,
+                            },
+                        );
+                    }
+                }
+
+                info!("库存:{}", count);
+            }
+            tracker.save();
+
+            if let Some(mut db) = history.take() {
+                // 每小时删除一次过期的历史
+                let mut prune = None;
+                if let Some(retention) = retention {
+                    let now = self.clock.instant();
+                    if pruned_at
+                        .map(|v| now.saturating_duration_since(v) >= Duration::from_secs(60 * 60))
+                        .unwrap_or(true)
+                    {
+                        pruned_at = Some(now);
+                        prune = Some((retention, self.clock.now()));
+                    }
+                }
+                let task = tokio::task::spawn_blocking(move || {
+                    let recorded = db.record_all(&readings);
+                    let pruned = prune.map(|(retention, now)| db.prune(retention, now));
+                    (db, recorded, pruned)
+                });
+                match task.await {
+                    Ok((db, recorded, pruned)) => {
+                        history = Some(db);
+                        if let Err(e) = recorded {
+                            self.log(format!("{}", e));
+                            error!("{}", e);
+                        }
+                        match pruned {
+                            Some(Ok(0)) | None => {}
+                            Some(Ok(v)) => info!("删除了 {} 条过期的库存历史", v),
+                            Some(Err(e)) => error!("{}", e),
+                        }
+                    }
+                    Err(e) => {
+                        self.log(format!(
+                            "保存库存历史的任务出错，这次不再保存库存历史:{}",
+                            e
+                        ));
+                        error!("保存库存历史的任务出错:{}", e);
+                    }
+                }
+            }
+
+            // 限速和熔断状态变化了就显示到运行记录里
+            let state = self.source.throttle_state();
+            if !state.same_kind(&throttle_state) {
+                self.log(format!("请求状态:{}", state));
+            }
+            throttle_state = state;
+
+            // 熔断中就等到可以再次请求
+            if throttled {
+                if let ThrottleState::Open { retry_in, .. } = state {
+                    if let Control::Exit(v) = self.sleep(retry_in).await {
+                        return v;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Inventory;
+    use crate::notify::NotifyError;
+    use crate::outbox::Notification;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    /// 每轮按顺序返回 `stock` 里的库存，最后一个一直返回，`BAD` 是不存在的型号
+    struct FakeSource {
+        stock: Mutex<Vec<usize>>,
+        calls: AtomicUsize,
+    }
+
+    impl FakeSource {
+        fn new(stock: Vec<usize>) -> Arc<Self> {
+            Arc::new(FakeSource {
+                stock: Mutex::new(stock),
+                calls: AtomicUsize::new(0),
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl InventorySource for FakeSource {
+        async fn get_inventories(&self, opns: &[&str]) -> BatchResult {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let stock = {
+                let mut stock = self.stock.lock().unwrap();
+                if stock.len() > 1 {
+                    stock.remove(0)
+                } else {
+                    stock[0]
+                }
+            };
+            opns.iter()
+                .map(|opn| {
+                    let res = if *opn == "BAD" {
+                        Err(AccountError::UnknownPart(opn.to_string()))
+                    } else {
+                        let value = serde_json::json!({
+                            "orderable_number": opn,
+                            "inventory": stock,
+                        });
+                        Ok(serde_json::from_value::<Inventory>(value).unwrap())
+                    };
+                    (opn.to_string(), res)
+                })
+                .collect()
+        }
+
+        fn product_url(&self, opn: &str) -> String {
+            format!("https://www.ti.com/product/{}", opn)
+        }
+    }
+
+    /// 不真的等待，每次等待把时间往后拨
+    struct FakeClock {
+        start: Instant,
+        offset: Mutex<Duration>,
+    }
+
+    #[async_trait]
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<Local> {
+            let offset = *self.offset.lock().unwrap();
+            Local::now() + chrono::Duration::from_std(offset).unwrap()
+        }
+
+        fn instant(&self) -> Instant {
+            self.start + *self.offset.lock().unwrap()
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            *self.offset.lock().unwrap() += duration;
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// 记下每条通知的标题，`error` 不是 `None` 就每次都发送失败
+    struct FakeNotifier {
+        sent: Mutex<Vec<String>>,
+        error: Option<fn() -> NotifyError>,
+    }
+
+    impl FakeNotifier {
+        fn new(error: Option<fn() -> NotifyError>) -> Arc<Self> {
+            Arc::new(FakeNotifier {
+                sent: Mutex::new(vec![]),
+                error,
+            })
+        }
+
+        fn sent(&self) -> Vec<String> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Notifier for FakeNotifier {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        async fn notify(&self, notification: &Notification) -> Result<(), NotifyError> {
+            self.sent.lock().unwrap().push(notification.subject.clone());
+            match self.error {
+                Some(error) => Err(error()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    struct Harness {
+        dir: PathBuf,
+        commands: UnboundedSender<MonitorCommand>,
+        events: UnboundedReceiver<MonitorEvent>,
+        monitor: Option<Monitor>,
+    }
+
+    impl Harness {
+        fn new(name: &str, source: Arc<FakeSource>, retry_policy: RetryPolicy) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("ti-monitor-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let clock = FakeClock {
+                start: Instant::now(),
+                offset: Mutex::new(Duration::ZERO),
+            };
+            let (commands, receiver) = unbounded_channel();
+            let (monitor, events) = Monitor::builder(source)
+                .clock(clock)
+                .outbox(dir.join("outbox.json"))
+                .retry_policy(retry_policy)
+                .alert_state(None::<&str>)
+                .jitter_seed(0)
+                .build(receiver);
+            Harness {
+                dir,
+                commands,
+                events,
+                monitor: Some(monitor),
+            }
+        }
+
+        fn send(&self, command: MonitorCommand) {
+            if self.commands.send(command).is_err() {
+                panic!("监控已经结束了");
+            }
+        }
+
+        /// 一直接收事件直到 `f` 返回 `true`，返回收到的所有事件
+        async fn wait_for<F: Fn(&MonitorEvent) -> bool>(&mut self, f: F) -> Vec<MonitorEvent> {
+            let events = &mut self.events;
+            let wait = async {
+                let mut list = vec![];
+                loop {
+                    let event = events.recv().await.expect("监控已经结束了");
+                    let done = f(&event);
+                    list.push(event);
+                    if done {
+                        return list;
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(10), wait)
+                .await
+                .expect("等待事件超时")
+        }
+
+        async fn wait_for_status(&mut self, status: MonitorStatus) {
+            self.wait_for(|e| *e == MonitorEvent::Status(status)).await;
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn settings(product_list: &str, notifier: Arc<FakeNotifier>) -> Box<MonitorSettings> {
+        Box::new(MonitorSettings {
+            product_list: product_list.to_owned(),
+            notifiers: vec![notifier],
+            schedule: Schedule {
+                interval_secs: 60,
+                jitter_secs: 0,
+                ..Default::default()
+            },
+            templates: AlertTemplates::default(),
+            renotify: None,
+            history: HistoryConfig {
+                enabled: false,
+                ..Default::default()
+            },
+        })
+    }
+
+    fn is_checked(e: &MonitorEvent) -> bool {
+        matches!(e, MonitorEvent::StockChecked { .. })
+    }
+
+    fn is_given_up(e: &MonitorEvent) -> bool {
+        matches!(e, MonitorEvent::Log(v) if v.contains("已放弃"))
+    }
+
+    #[tokio::test]
+    async fn start_pause_resume_stop() {
+        let source = FakeSource::new(vec![0]);
+        let mut harness = Harness::new("control", source.clone(), RetryPolicy::default());
+        let mut monitor = harness.monitor.take().unwrap();
+        let task = tokio::spawn(async move { monitor.serve().await });
+
+        let notifier = FakeNotifier::new(None);
+        harness.send(MonitorCommand::Start(settings("LM358DR", notifier.clone())));
+        harness.wait_for_status(MonitorStatus::Running).await;
+        harness.wait_for(is_checked).await;
+
+        // 暂停的时候不查询
+        harness.send(MonitorCommand::Pause);
+        harness.wait_for_status(MonitorStatus::Paused).await;
+        let calls = source.calls();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(source.calls(), calls);
+
+        harness.send(MonitorCommand::Resume);
+        harness.wait_for_status(MonitorStatus::Running).await;
+        harness.wait_for(is_checked).await;
+        assert!(source.calls() > calls);
+
+        // 停止之后可以重新开始
+        harness.send(MonitorCommand::Stop);
+        harness.wait_for_status(MonitorStatus::Stopped).await;
+        harness.send(MonitorCommand::Start(settings("LM358DR", notifier.clone())));
+        harness.wait_for_status(MonitorStatus::Running).await;
+        harness.wait_for(is_checked).await;
+
+        // 前端关闭了就结束
+        let (commands, _) = unbounded_channel();
+        drop(std::mem::replace(&mut harness.commands, commands));
+        harness.wait_for_status(MonitorStatus::Stopped).await;
+        tokio::time::timeout(Duration::from_secs(10), task)
+            .await
+            .unwrap()
+            .unwrap();
+        // 库存一直是 0，不会提醒
+        assert!(notifier.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn alert_on_restock() {
+        let source = FakeSource::new(vec![0, 0, 5]);
+        let mut harness = Harness::new("alert", source.clone(), RetryPolicy::default());
+        let mut monitor = harness.monitor.take().unwrap();
+        let notifier = FakeNotifier::new(None);
+        let list = settings("OPA1622IDRCR\nBAD", notifier.clone());
+        let task = tokio::spawn(async move { monitor.run(list).await });
+
+        let events = harness
+            .wait_for(|e| matches!(e, MonitorEvent::AlertSent { .. }))
+            .await;
+        harness.send(MonitorCommand::Stop);
+        harness.wait_for_status(MonitorStatus::Stopped).await;
+        assert!(matches!(task.await.unwrap(), MonitorExit::Stop));
+
+        assert_eq!(
+            events.last(),
+            Some(&MonitorEvent::AlertSent {
+                opn: "OPA1622IDRCR".to_owned(),
+                channel: "fake".to_owned(),
+            })
+        );
+        assert!(events.iter().any(|e| matches!(
+            e,
+            MonitorEvent::StockChanged { opn, from: 0, to: 5, .. } if opn == "OPA1622IDRCR"
+        )));
+        // 不存在的型号只查一次
+        let invalid = events
+            .iter()
+            .filter(|e| matches!(e, MonitorEvent::CheckFailed { opn, invalid: true, .. } if opn == "BAD"))
+            .count();
+        assert_eq!(invalid, 1);
+
+        let sent = notifier.sent();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].contains("OPA1622IDRCR"), "{}", sent[0]);
+    }
+
+    /// 发送一次就放弃的重试策略
+    fn no_retry() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_failure_alerts_again() {
+        let source = FakeSource::new(vec![5]);
+        let mut harness = Harness::new("transient", source, no_retry());
+        let mut monitor = harness.monitor.take().unwrap();
+        let notifier = FakeNotifier::new(Some(|| NotifyError::Command("退出码 1".to_owned())));
+        let list = settings("LM358DR", notifier.clone());
+        let task = tokio::spawn(async move { monitor.run(list).await });
+
+        // 放弃之后下次查询还有库存就重新提醒
+        harness.wait_for(is_given_up).await;
+        harness.wait_for(is_given_up).await;
+        harness.send(MonitorCommand::Stop);
+        harness.wait_for_status(MonitorStatus::Stopped).await;
+        task.await.unwrap();
+
+        assert!(notifier.sent().len() >= 2);
+    }
+
+    #[tokio::test]
+    async fn failed_out_of_stock_alerts_again() {
+        let source = FakeSource::new(vec![5, 0]);
+        let mut harness = Harness::new("out-of-stock", source, no_retry());
+        let mut monitor = harness.monitor.take().unwrap();
+        let notifier = FakeNotifier::new(Some(|| NotifyError::Command("退出码 1".to_owned())));
+        let list = settings("LM358DR =0", notifier.clone());
+        let task = tokio::spawn(async move { monitor.run(list).await });
+
+        // 卖完的提醒没发出去，下次查询还是 0 就重新提醒，上次的库存还记着
+        let mut events = harness.wait_for(is_given_up).await;
+        events.extend(harness.wait_for(is_given_up).await);
+        harness.send(MonitorCommand::Stop);
+        harness.wait_for_status(MonitorStatus::Stopped).await;
+        task.await.unwrap();
+
+        let sent = notifier.sent();
+        assert!(sent.len() >= 2);
+        assert!(sent.iter().all(|v| v.contains("已经卖完了")), "{:?}", sent);
+        let changed = events
+            .iter()
+            .filter(|e| matches!(e, MonitorEvent::StockChanged { .. }))
+            .count();
+        assert_eq!(changed, 1);
+        let first = events
+            .iter()
+            .filter(|e| matches!(e, MonitorEvent::StockChecked { previous: None, .. }))
+            .count();
+        assert_eq!(first, 1);
+    }
+
+    #[tokio::test]
+    async fn permanent_failure_does_not_alert_again() {
+        let source = FakeSource::new(vec![5]);
+        let mut harness = Harness::new("permanent", source, no_retry());
+        let mut monitor = harness.monitor.take().unwrap();
+        let notifier = FakeNotifier::new(Some(|| NotifyError::Config("密码错误".to_owned())));
+        let list = settings("LM358DR", notifier.clone());
+        let task = tokio::spawn(async move { monitor.run(list).await });
+
+        harness.wait_for(is_given_up).await;
+        let mut events = vec![];
+        for _ in 0..3 {
+            events.extend(harness.wait_for(is_checked).await);
+        }
+        harness.send(MonitorCommand::Stop);
+        events.extend(
+            harness
+                .wait_for(|e| *e == MonitorEvent::Status(MonitorStatus::Stopped))
+                .await,
+        );
+        task.await.unwrap();
+        // 给发件箱一点时间，有重新提醒的话会发出去
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(!events.iter().any(is_given_up));
+        assert_eq!(notifier.sent().len(), 1);
+    }
+}
@@ -0,0 +1,246 @@
+//! 钉钉、企业微信、飞书、Slack 群机器人的消息格式和签名
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use super::NotifyError;
+use crate::outbox::Notification;
+
+/// 企业微信 markdown 消息的内容最长 4096 字节
+const WECOM_MARKDOWN_LIMIT: usize = 4096;
+
+/// webhook 的类型，决定消息格式、签名方式和怎么判断发送成功
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookKind {
+    /// 通用 JSON，返回 2xx 就算成功
+    #[default]
+    Generic,
+    /// 钉钉群机器人，设置了 `secret` 就用加签
+    DingTalk,
+    /// 企业微信群机器人
+    WeCom,
+    /// 飞书群机器人，设置了 `secret` 就用签名校验
+    Feishu,
+    /// Slack 以及兼容 Slack 格式的 incoming webhook
+    Slack,
+}
+
+impl WebhookKind {
+    /// 请求的地址和内容，`now` 是当前的 unix 时间戳(毫秒)，签名要用
+    pub(super) fn request(
+        &self,
+        url: &Url,
+        secret: Option<&str>,
+        notification: &Notification,
+        now: i64,
+    ) -> (Url, Value) {
+        let title = &notification.subject;
+        let text = &notification.body;
+        let mut url = url.clone();
+
+        let body = match self {
+            WebhookKind::Generic => json!({
+                "key": notification.key,
+                "subject": title,
+                "body": text,
+                "sent_at": notification.time.to_rfc3339(),
+            }),
+            WebhookKind::DingTalk => {
+                // 加签放在地址的参数里
+                if let Some(secret) = secret {
+                    let sign = dingtalk_sign(secret, now);
+                    url.query_pairs_mut()
+                        .append_pair("timestamp", &now.to_string())
+                        .append_pair("sign", &sign);
+                }
+                json!({
+                    "msgtype": "markdown",
+                    "markdown": {
+                        "title": title,
+                        "text": format!("### {}\n\n{}", title, text),
+                    },
+                })
+            }
+            WebhookKind::WeCom => json!({
+                "msgtype": "markdown",
+                "markdown": {
+                    "content": truncate(format!("### {}\n{}", title, text), WECOM_MARKDOWN_LIMIT),
+                },
+            }),
+            WebhookKind::Feishu => {
+                let mut body = json!({
+                    "msg_type": "interactive",
+                    "card": {
+                        "header": {
+                            "title": {"tag": "plain_text", "content": title},
+                            "template": "green",
+                        },
+                        "elements": [
+                            {"tag": "div", "text": {"tag": "lark_md", "content": text}},
+                        ],
+                    },
+                });
+                // 飞书的签名放在请求内容里，时间戳是秒
+                if let Some(secret) = secret {
+                    let timestamp = now / 1000;
+                    body["timestamp"] = json!(timestamp.to_string());
+                    body["sign"] = json!(feishu_sign(secret, timestamp));
+                }
+                body
+            }
+            WebhookKind::Slack => json!({
+                "text": title,
+                "blocks": [
+                    {"type": "header", "text": {"type": "plain_text", "text": title}},
+                    {"type": "section", "text": {"type": "mrkdwn", "text": text}},
+                ],
+            }),
+        };
+
+        (url, body)
+    }
+
+    /// 这几个群机器人出错的时候状态码也是 200，要看返回的内容
+    pub(super) fn check_response(&self, body: &str) -> Result<(), NotifyError> {
+        let code_field = match self {
+            WebhookKind::Generic | WebhookKind::Slack => return Ok(()),
+            WebhookKind::DingTalk | WebhookKind::WeCom => "errcode",
+            WebhookKind::Feishu => "code",
+        };
+
+        let v: Value = match serde_json::from_str(body) {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(NotifyError::Rejected {
+                    code: None,
+                    message: body.to_owned(),
+                })
+            }
+        };
+        // 飞书旧版本的接口返回的是 StatusCode
+        let code = v
+            .get(code_field)
+            .or_else(|| v.get("StatusCode"))
+            .and_then(Value::as_i64)
+            .unwrap_or(0);
+        if code == 0 {
+            return Ok(());
+        }
+
+        let message = ["errmsg", "msg", "StatusMessage"]
+            .iter()
+            .find_map(|k| v.get(*k).and_then(Value::as_str))
+            .unwrap_or_default()
+            .to_owned();
+        Err(NotifyError::Rejected {
+            code: Some(code),
+            message,
+        })
+    }
+}
+
+/// 超过 `max` 字节就截断，最后加上省略号，不会截断在一个字符中间
+fn truncate(mut text: String, max: usize) -> String {
+    const ELLIPSIS: &str = "…";
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max.saturating_sub(ELLIPSIS.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    text.push_str(ELLIPSIS);
+    text
+}
+
+fn hmac_sha256(key: &[u8], msg: &[u8]) -> Vec<u8> {
+    // HMAC 可以用任意长度的密钥，这里不会失败
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC 密钥长度不限");
+    mac.update(msg);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// 钉钉加签: 用 `secret` 对 `毫秒时间戳\nsecret` 做 HmacSHA256，再 base64
+pub fn dingtalk_sign(secret: &str, timestamp_ms: i64) -> String {
+    let msg = format!("{}\n{}", timestamp_ms, secret);
+    BASE64.encode(hmac_sha256(secret.as_bytes(), msg.as_bytes()))
+}
+
+/// 飞书签名: 用 `秒时间戳\nsecret` 作为密钥对空内容做 HmacSHA256，再 base64
+pub fn feishu_sign(secret: &str, timestamp: i64) -> String {
+    let key = format!("{}\n{}", timestamp, secret);
+    BASE64.encode(hmac_sha256(key.as_bytes(), b""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_vectors() {
+        // 用 Python 的 hmac 模块算出来的结果
+        assert_eq!(
+            dingtalk_sign("SECtest", 1700000000000),
+            "aZLLrriXgn05YbwaGR7knYsLeJADjr9NwLaNNKpxh4g="
+        );
+        assert_eq!(
+            feishu_sign("SECtest", 1700000000),
+            "G7XpBpG8NgG02fJOAhX6FRAObIljmFoxVReo8I62pEk="
+        );
+    }
+
+    #[test]
+    fn truncate_long_text() {
+        assert_eq!(truncate("库存".to_owned(), 6), "库存");
+        // 不会截断在汉字中间
+        assert_eq!(truncate("库存充足".to_owned(), 10), "库存…");
+        let text = truncate("库".repeat(2000), WECOM_MARKDOWN_LIMIT);
+        assert!(text.len() <= WECOM_MARKDOWN_LIMIT);
+        assert!(text.ends_with('…'));
+    }
+
+    #[test]
+    fn check_response_codes() {
+        assert!(WebhookKind::Generic.check_response("not json").is_ok());
+        assert!(WebhookKind::Slack.check_response("ok").is_ok());
+        assert!(WebhookKind::DingTalk
+            .check_response(r#"{"errcode":0,"errmsg":"ok"}"#)
+            .is_ok());
+        assert!(WebhookKind::Feishu
+            .check_response(r#"{"StatusCode":0,"StatusMessage":"success"}"#)
+            .is_ok());
+
+        match WebhookKind::WeCom
+            .check_response(r#"{"errcode":45009,"errmsg":"api freq out of limit"}"#)
+        {
+            Err(
+                e @ NotifyError::Rejected {
+                    code: Some(45009), ..
+                },
+            ) => assert!(e.is_transient()),
+            v => panic!("{:?}", v),
+        }
+        match WebhookKind::Feishu.check_response(r#"{"code":19021,"msg":"sign match fail"}"#) {
+            Err(
+                e @ NotifyError::Rejected {
+                    code: Some(19021), ..
+                },
+            ) => {
+                assert!(!e.is_transient());
+                assert_eq!(e.to_string(), "webhook 返回错误码 19021:sign match fail");
+            }
+            v => panic!("{:?}", v),
+        }
+        assert!(matches!(
+            WebhookKind::DingTalk.check_response("<html>"),
+            Err(NotifyError::Rejected { code: None, .. })
+        ));
+    }
+}
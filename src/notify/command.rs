@@ -0,0 +1,155 @@
+use std::io;
+use std::process::Stdio;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use super::{Notifier, NotifyError};
+use crate::config::CommandNotifierConfig;
+use crate::outbox::Notification;
+
+/// 执行一个本地命令，可以用来接入任何其它通知方式
+///
+/// 通知的内容通过环境变量 `TI_NOTIFY_KEY`、`TI_NOTIFY_SUBJECT`、`TI_NOTIFY_BODY` 传给命令，
+/// 标准输入里是通知的 JSON，有 `key`、`subject`、`body`、`time` 字段，有 html 内容的话还有 `html`。
+/// 退出码是 0 就算发送成功，写标准输入和等命令结束一共不能超过超时时间。
+#[derive(Debug, Clone)]
+pub struct CommandNotifier {
+    name: String,
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl CommandNotifier {
+    /// `index` 是配置文件里第几个命令，没有设置名字的时候用来生成名字
+    pub fn from_config(config: &CommandNotifierConfig, index: usize) -> Self {
+        CommandNotifier {
+            name: config.channel_name(index),
+            program: config.program.clone(),
+            args: config.args.clone(),
+            timeout: Duration::from_secs(config.timeout_secs),
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for CommandNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifyError> {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .env("TI_NOTIFY_KEY", &notification.key)
+            .env("TI_NOTIFY_SUBJECT", &notification.subject)
+            .env("TI_NOTIFY_BODY", &notification.body)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        // 不要弹出黑色的命令行窗口
+        #[cfg(windows)]
+        command.creation_flags(0x08000000);
+
+        let mut child = match command.spawn() {
+            Ok(v) => v,
+            Err(e) => {
+                let message = format!("{} {}", self.program, e);
+                return Err(match e.kind() {
+                    io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => {
+                        NotifyError::Spawn(message)
+                    }
+                    _ => NotifyError::Command(message),
+                });
+            }
+        };
+
+        // 命令不读标准输入的话写的时候可能一直卡住，所以写标准输入也要算在超时时间里
+        let stdin = child.stdin.take();
+        let json = serde_json::to_string(notification).unwrap_or_default();
+        let run = async move {
+            if let Some(mut stdin) = stdin {
+                // 命令不读标准输入也没关系
+                let _ = stdin.write_all(json.as_bytes()).await;
+            }
+            child.wait_with_output().await
+        };
+
+        let output = match tokio::time::timeout(self.timeout, run).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => return Err(NotifyError::Command(format!("{} {}", self.program, e))),
+            Err(_) => {
+                return Err(NotifyError::Command(format!(
+                    "{} 超过 {} 秒没有结束",
+                    self.program,
+                    self.timeout.as_secs()
+                )))
+            }
+        };
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(NotifyError::Command(format!(
+                "{} 退出码 {}:{}",
+                self.program,
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )))
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn notifier(program: &str, args: &[&str]) -> CommandNotifier {
+        let config = CommandNotifierConfig {
+            program: program.to_owned(),
+            args: args.iter().map(|v| v.to_string()).collect(),
+            timeout_secs: 1,
+            ..CommandNotifierConfig::default()
+        };
+        CommandNotifier::from_config(&config, 0)
+    }
+
+    fn notification(html: Option<String>) -> Notification {
+        Notification {
+            key: "OPA1622IDRCR".to_owned(),
+            subject: "OPA1622IDRCR 有库存了".to_owned(),
+            body: "库存 620 个".to_owned(),
+            html,
+            time: chrono::Local::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_json_to_stdin() {
+        let notifier = notifier("sh", &["-c", r#"grep -q '"html":"<b>620</b>"'"#]);
+        notifier
+            .notify(&notification(Some("<b>620</b>".to_owned())))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn timeout_covers_stdin() {
+        // 命令不读标准输入，内容又比管道的缓冲区大，写标准输入会一直卡住
+        let notifier = notifier("sleep", &["10"]);
+        let notification = notification(Some("x".repeat(1 << 20)));
+        let res = tokio::time::timeout(Duration::from_secs(5), notifier.notify(&notification))
+            .await
+            .expect("超时时间没有包括写标准输入");
+        match res {
+            Err(NotifyError::Command(e)) => assert!(e.contains("没有结束"), "{}", e),
+            v => panic!("{:?}", v),
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use async_trait::async_trait;
+
+use super::{Notifier, NotifyError};
+use crate::outbox::Notification;
+
+/// 在电脑右下角(或者右上角)弹出系统通知
+#[derive(Debug, Clone, Default)]
+pub struct DesktopNotifier {}
+
+impl DesktopNotifier {
+    pub fn new() -> Self {
+        DesktopNotifier {}
+    }
+}
+
+#[async_trait]
+impl Notifier for DesktopNotifier {
+    fn name(&self) -> &str {
+        "desktop"
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifyError> {
+        let notification = notification.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            notify_rust::Notification::new()
+                .appname("芯片库存监控")
+                .summary(&notification.subject)
+                .body(&notification.body)
+                .show()
+                .map(|_| ())
+                .map_err(|e| format!("{}", e))
+        })
+        .await;
+        match res {
+            Ok(v) => v.map_err(NotifyError::Desktop),
+            Err(e) => Err(NotifyError::Task(format!("{}", e))),
+        }
+    }
+}
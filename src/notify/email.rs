@@ -0,0 +1,79 @@
+use async_trait::async_trait;
+
+use super::{Notifier, NotifyError};
+use crate::config::EmailRoute;
+use crate::mail::{Mailer, Recipients};
+use crate::outbox::Notification;
+
+/// 发邮件通知
+///
+/// 通知的 `key` 是产品型号，符合 `routes` 里的规则就发给规则里的收件人，
+/// 符合多条规则就发给所有这些收件人，一条都不符合才发给默认的收件人。
+#[derive(Debug, Clone)]
+pub struct EmailNotifier {
+    mailer: Mailer,
+    recipients: Recipients,
+    routes: Vec<EmailRoute>,
+}
+
+impl EmailNotifier {
+    pub fn new(mailer: Mailer, recipients: Recipients) -> Self {
+        EmailNotifier {
+            mailer,
+            recipients,
+            routes: vec![],
+        }
+    }
+
+    /// 按产品型号发给不同的收件人
+    pub fn with_routes(mut self, routes: Vec<EmailRoute>) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// 型号是 `key` 的产品的邮件发给谁
+    pub fn recipients(&self, key: &str) -> Recipients {
+        let mut recipients = Recipients::default();
+        for route in self.routes.iter().filter(|v| v.matches(key)) {
+            recipients.merge(&route.recipients());
+        }
+        if recipients.is_empty() {
+            recipients = self.recipients.clone();
+        }
+        recipients
+    }
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifyError> {
+        let to = self.recipients(&notification.key);
+        if to.is_empty() {
+            return Err(NotifyError::Config(format!(
+                "产品 {} 没有匹配的邮件收件人",
+                notification.key
+            )));
+        }
+
+        // lettre 是同步的，放到单独的线程里发送
+        let mailer = self.mailer.clone();
+        let notification = notification.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            mailer.send(
+                &to,
+                &notification.subject,
+                &notification.body,
+                notification.html.as_deref(),
+            )
+        })
+        .await;
+        match res {
+            Ok(v) => Ok(v?),
+            Err(e) => Err(NotifyError::Task(format!("{}", e))),
+        }
+    }
+}
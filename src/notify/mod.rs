@@ -0,0 +1,163 @@
+//! 库存通知的各种渠道
+//!
+//! 每个渠道实现 [`Notifier`]，[`Dispatcher`](crate::Dispatcher) 把每条通知发给所有的渠道，
+//! 新增渠道只需要实现这个 trait，再在 [`from_config`] 里按配置创建。
+
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use reqwest::StatusCode;
+
+use crate::config::Config;
+use crate::mail::{MailError, Mailer};
+use crate::outbox::Notification;
+
+mod chat;
+mod command;
+mod desktop;
+mod email;
+mod webhook;
+
+pub use chat::{dingtalk_sign, feishu_sign, WebhookKind};
+pub use command::CommandNotifier;
+pub use desktop::DesktopNotifier;
+pub use email::EmailNotifier;
+pub use webhook::WebhookNotifier;
+
+/// 一个通知渠道
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// 渠道的名字，同一个配置里不能重复，发件箱靠它区分每条通知发给哪个渠道
+    fn name(&self) -> &str;
+
+    /// 发送一条通知
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifyError>;
+}
+
+/// 发送通知出错
+#[derive(Debug)]
+pub enum NotifyError {
+    /// 渠道的配置不正确
+    Config(String),
+    /// 发邮件出错
+    Mail(MailError),
+    /// 请求 webhook 出错
+    Http(reqwest::Error),
+    /// webhook 返回的状态码表示失败
+    Status { status: StatusCode, body: String },
+    /// 群机器人返回了错误码，例如签名不对、发送太频繁
+    Rejected { code: Option<i64>, message: String },
+    /// 命令执行失败或者退出码不是 0
+    Command(String),
+    /// 命令不存在或者没有执行权限，重试也不会成功
+    Spawn(String),
+    /// 显示桌面通知失败
+    Desktop(String),
+    /// 发送通知的后台任务出错
+    Task(String),
+}
+
+impl NotifyError {
+    /// 过一会儿重试可能会成功
+    pub fn is_transient(&self) -> bool {
+        match self {
+            NotifyError::Mail(e) => e.is_transient(),
+            NotifyError::Http(_) | NotifyError::Command(_) => true,
+            NotifyError::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            // 钉钉、企业微信、飞书发送太频繁的错误码
+            NotifyError::Rejected { code, .. } => {
+                matches!(code, Some(130101) | Some(45009) | Some(9499) | Some(11232))
+            }
+            NotifyError::Config(_)
+            | NotifyError::Spawn(_)
+            | NotifyError::Desktop(_)
+            | NotifyError::Task(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotifyError::Config(e) => write!(f, "通知渠道配置不正确:{}", e),
+            NotifyError::Mail(e) => write!(f, "{}", e),
+            NotifyError::Http(e) => write!(f, "请求 webhook 出错:{}", e),
+            NotifyError::Status { status, body } => {
+                write!(f, "webhook 返回 {}:{}", status, body)
+            }
+            NotifyError::Rejected {
+                code: Some(code),
+                message,
+            } => write!(f, "webhook 返回错误码 {}:{}", code, message),
+            NotifyError::Rejected {
+                code: None,
+                message,
+            } => write!(f, "webhook 返回的内容不正确:{}", message),
+            NotifyError::Command(e) => write!(f, "执行命令出错:{}", e),
+            NotifyError::Spawn(e) => write!(f, "启动命令出错:{}", e),
+            NotifyError::Desktop(e) => write!(f, "显示桌面通知出错:{}", e),
+            NotifyError::Task(e) => write!(f, "发送通知的任务出错:{}", e),
+        }
+    }
+}
+
+impl std::error::Error for NotifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NotifyError::Mail(e) => Some(e),
+            NotifyError::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<MailError> for NotifyError {
+    fn from(e: MailError) -> Self {
+        NotifyError::Mail(e)
+    }
+}
+
+impl From<reqwest::Error> for NotifyError {
+    fn from(e: reqwest::Error) -> Self {
+        NotifyError::Http(e)
+    }
+}
+
+/// 按配置文件创建所有启用的通知渠道
+///
+/// 邮件通知要填了发件箱，并且设置了收件人或者收件规则才会启用。
+pub fn from_config(config: &Config) -> Result<Vec<Arc<dyn Notifier>>, NotifyError> {
+    let notifiers = &config.notifiers;
+    let mut list: Vec<Arc<dyn Notifier>> = vec![];
+
+    let email = &notifiers.email;
+    let routes = config.email_routes();
+    if email.enabled
+        && !config.smtp.from.is_empty()
+        && (!email.recipients().is_empty() || !routes.is_empty())
+    {
+        let notifier = EmailNotifier::new(Mailer::new(config.smtp.clone()), email.recipients());
+        list.push(Arc::new(notifier.with_routes(routes)));
+    }
+
+    if notifiers.desktop.enabled {
+        list.push(Arc::new(DesktopNotifier::new()));
+    }
+
+    for (i, webhook) in notifiers.webhook.iter().enumerate() {
+        if webhook.enabled {
+            list.push(Arc::new(WebhookNotifier::from_config(webhook, i)?));
+        }
+    }
+
+    for (i, command) in notifiers.command.iter().enumerate() {
+        if command.enabled {
+            list.push(Arc::new(CommandNotifier::from_config(command, i)));
+        }
+    }
+
+    Ok(list)
+}
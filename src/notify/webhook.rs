@@ -0,0 +1,347 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Url};
+
+use super::{Notifier, NotifyError, WebhookKind};
+use crate::config::WebhookNotifierConfig;
+use crate::outbox::Notification;
+
+/// webhook 请求的超时时间
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// 把通知用 JSON POST 到一个地址
+///
+/// 通用格式是:
+///
+/// ```json
+/// {"key": "OPA1622IDRCR", "subject": "...", "body": "...", "sent_at": "2022-01-01T08:00:00+08:00"}
+/// ```
+///
+/// `sent_at` 是通知里的时间，也就是查到库存的时间。
+/// 返回 2xx 状态码就算发送成功。钉钉、企业微信、飞书、Slack 按各自的格式发送，见 [`WebhookKind`]。
+#[derive(Debug, Clone)]
+pub struct WebhookNotifier {
+    name: String,
+    kind: WebhookKind,
+    url: Url,
+    secret: Option<String>,
+    client: Client,
+}
+
+impl WebhookNotifier {
+    /// `index` 是配置文件里第几个 webhook，没有设置名字的时候用来生成名字
+    pub fn from_config(config: &WebhookNotifierConfig, index: usize) -> Result<Self, NotifyError> {
+        let url = match Url::parse(&config.url) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(NotifyError::Config(format!(
+                    "webhook 地址 {} 不正确:{}",
+                    config.url, e
+                )))
+            }
+        };
+
+        let mut headers = HeaderMap::new();
+        for (k, v) in &config.headers {
+            let name = HeaderName::from_bytes(k.as_bytes());
+            let value = HeaderValue::from_str(v);
+            match (name, value) {
+                (Ok(name), Ok(value)) => {
+                    headers.insert(name, value);
+                }
+                _ => {
+                    return Err(NotifyError::Config(format!(
+                        "webhook 请求头 {}: {} 不正确",
+                        k, v
+                    )))
+                }
+            }
+        }
+
+        let client = Client::builder()
+            .timeout(TIMEOUT)
+            .default_headers(headers)
+            .build()?;
+
+        Ok(WebhookNotifier {
+            name: config.channel_name(index),
+            kind: config.kind,
+            url,
+            secret: config.secret.clone().filter(|v| !v.is_empty()),
+            client,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn notify(&self, notification: &Notification) -> Result<(), NotifyError> {
+        let now = chrono::Local::now().timestamp_millis();
+        let (url, payload) =
+            self.kind
+                .request(&self.url, self.secret.as_deref(), notification, now);
+        let res = self.client.post(url).json(&payload).send().await?;
+
+        let status = res.status();
+        let body = res.text().await.unwrap_or_default();
+        if !status.is_success() {
+            return Err(NotifyError::Status { status, body });
+        }
+        self.kind.check_response(&body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::{dingtalk_sign, feishu_sign};
+    use chrono::TimeZone;
+    use reqwest::StatusCode;
+    use serde_json::Value;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// 收到的请求: 地址里的路径和参数，请求内容
+    struct Received {
+        target: String,
+        body: Value,
+    }
+
+    /// 在本地开一个只处理一次请求的 HTTP 服务，返回 `status` 和 `response`
+    async fn serve(status: u16, response: &'static str) -> (String, JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/hook?access_token=abc",
+            listener.local_addr().unwrap()
+        );
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut data = vec![];
+            let mut buf = [0u8; 4096];
+            // 先读到请求头结束，再按 Content-Length 读请求内容
+            let header_end = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "请求没有发完连接就断了");
+                data.extend_from_slice(&buf[..n]);
+                if let Some(i) = data.windows(4).position(|v| v == b"\r\n\r\n") {
+                    break i + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+            let length: usize = head
+                .lines()
+                .find_map(|v| {
+                    let (k, v) = v.split_once(':')?;
+                    k.eq_ignore_ascii_case("content-length")
+                        .then(|| v.trim().parse().ok())?
+                })
+                .unwrap_or(0);
+            while data.len() < header_end + length {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "请求没有发完连接就断了");
+                data.extend_from_slice(&buf[..n]);
+            }
+
+            let reply = format!(
+                "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                response.len(),
+                response
+            );
+            stream.write_all(reply.as_bytes()).await.unwrap();
+            let _ = stream.shutdown().await;
+
+            Received {
+                target: head
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_owned(),
+                body: serde_json::from_slice(&data[header_end..header_end + length]).unwrap(),
+            }
+        });
+        (url, handle)
+    }
+
+    fn notifier(kind: WebhookKind, url: String, secret: Option<&str>) -> WebhookNotifier {
+        let config = WebhookNotifierConfig {
+            kind,
+            url,
+            secret: secret.map(str::to_owned),
+            ..WebhookNotifierConfig::default()
+        };
+        WebhookNotifier::from_config(&config, 0).unwrap()
+    }
+
+    fn notification() -> Notification {
+        Notification {
+            key: "OPA1622IDRCR".to_owned(),
+            subject: "OPA1622IDRCR 有库存了".to_owned(),
+            body: "库存 620 个".to_owned(),
+            html: None,
+            time: chrono::Local.timestamp_opt(1_700_000_000, 0).unwrap(),
+        }
+    }
+
+    /// 发一条通知，返回发送结果和服务收到的请求
+    async fn send(
+        kind: WebhookKind,
+        secret: Option<&str>,
+        status: u16,
+        response: &'static str,
+    ) -> (Result<(), NotifyError>, Received) {
+        let (url, handle) = serve(status, response).await;
+        let res = notifier(kind, url, secret).notify(&notification()).await;
+        (res, handle.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn generic_payload() {
+        let (res, received) = send(WebhookKind::Generic, None, 204, "").await;
+        res.unwrap();
+        assert_eq!(received.target, "/hook?access_token=abc");
+        assert_eq!(received.body["key"], "OPA1622IDRCR");
+        assert_eq!(received.body["subject"], "OPA1622IDRCR 有库存了");
+        assert_eq!(received.body["body"], "库存 620 个");
+        // 用的是通知里的时间，不是发送的时间
+        let sent_at = received.body["sent_at"].as_str().unwrap();
+        assert_eq!(
+            chrono::DateTime::parse_from_rfc3339(sent_at).unwrap(),
+            notification().time
+        );
+    }
+
+    #[tokio::test]
+    async fn dingtalk_payload_and_sign() {
+        let (res, received) = send(
+            WebhookKind::DingTalk,
+            Some("SECtest"),
+            200,
+            r#"{"errcode":0,"errmsg":"ok"}"#,
+        )
+        .await;
+        res.unwrap();
+        assert_eq!(received.body["msgtype"], "markdown");
+        assert_eq!(received.body["markdown"]["title"], "OPA1622IDRCR 有库存了");
+        assert_eq!(
+            received.body["markdown"]["text"],
+            "### OPA1622IDRCR 有库存了\n\n库存 620 个"
+        );
+
+        let url = Url::parse(&format!("http://localhost{}", received.target)).unwrap();
+        let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        assert_eq!(query[0], ("access_token".to_owned(), "abc".to_owned()));
+        assert_eq!(query[1].0, "timestamp");
+        assert_eq!(query[2].0, "sign");
+        let timestamp: i64 = query[1].1.parse().unwrap();
+        assert!((chrono::Local::now().timestamp_millis() - timestamp).abs() < 60_000);
+        assert_eq!(query[2].1, dingtalk_sign("SECtest", timestamp));
+    }
+
+    #[tokio::test]
+    async fn dingtalk_without_secret_has_no_sign() {
+        let (res, received) = send(WebhookKind::DingTalk, None, 200, r#"{"errcode":0}"#).await;
+        res.unwrap();
+        assert_eq!(received.target, "/hook?access_token=abc");
+    }
+
+    #[tokio::test]
+    async fn wecom_payload() {
+        let (res, received) = send(WebhookKind::WeCom, None, 200, r#"{"errcode":0}"#).await;
+        res.unwrap();
+        assert_eq!(received.body["msgtype"], "markdown");
+        assert_eq!(
+            received.body["markdown"]["content"],
+            "### OPA1622IDRCR 有库存了\n库存 620 个"
+        );
+    }
+
+    #[tokio::test]
+    async fn feishu_payload_and_sign() {
+        let (res, received) =
+            send(WebhookKind::Feishu, Some("SECtest"), 200, r#"{"code":0}"#).await;
+        res.unwrap();
+        assert_eq!(received.target, "/hook?access_token=abc");
+        assert_eq!(received.body["msg_type"], "interactive");
+        assert_eq!(
+            received.body["card"]["header"]["title"]["content"],
+            "OPA1622IDRCR 有库存了"
+        );
+        assert_eq!(
+            received.body["card"]["elements"][0]["text"]["content"],
+            "库存 620 个"
+        );
+
+        let timestamp: i64 = received.body["timestamp"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((chrono::Local::now().timestamp() - timestamp).abs() < 60);
+        assert_eq!(received.body["sign"], feishu_sign("SECtest", timestamp));
+    }
+
+    #[tokio::test]
+    async fn slack_payload() {
+        let (res, received) = send(WebhookKind::Slack, None, 200, "ok").await;
+        res.unwrap();
+        assert_eq!(received.body["text"], "OPA1622IDRCR 有库存了");
+        assert_eq!(received.body["blocks"][0]["type"], "header");
+        assert_eq!(received.body["blocks"][1]["text"]["text"], "库存 620 个");
+    }
+
+    #[tokio::test]
+    async fn non_success_status_is_error() {
+        let (res, _) = send(WebhookKind::Generic, None, 503, "busy").await;
+        match res {
+            Err(e @ NotifyError::Status { .. }) => {
+                assert!(e.is_transient());
+                assert_eq!(e.to_string(), "webhook 返回 503 Service Unavailable:busy");
+            }
+            v => panic!("{:?}", v),
+        }
+
+        let (res, _) = send(WebhookKind::DingTalk, None, 404, "").await;
+        match res {
+            Err(e @ NotifyError::Status { status, .. }) => {
+                assert_eq!(status, StatusCode::NOT_FOUND);
+                assert!(!e.is_transient());
+            }
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn error_code_in_body_is_error() {
+        let (res, _) = send(
+            WebhookKind::DingTalk,
+            Some("SECtest"),
+            200,
+            r#"{"errcode":310000,"errmsg":"sign not match"}"#,
+        )
+        .await;
+        match res {
+            Err(e @ NotifyError::Rejected { .. }) => {
+                assert!(!e.is_transient());
+                assert_eq!(e.to_string(), "webhook 返回错误码 310000:sign not match");
+            }
+            v => panic!("{:?}", v),
+        }
+
+        let (res, _) = send(
+            WebhookKind::DingTalk,
+            None,
+            200,
+            r#"{"errcode":130101,"errmsg":"send too fast"}"#,
+        )
+        .await;
+        assert!(res.unwrap_err().is_transient());
+    }
+}
@@ -0,0 +1,613 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Local};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::notify::{Notifier, NotifyError};
+
+/// 默认的发件箱文件，还没发出去的通知保存在这里，程序重启之后继续发送
+pub const DEFAULT_OUTBOX_PATH: &str = "./outbox.json";
+
+/// 多次发送失败放弃的通知追加到这个文件，和发件箱放在同一个目录
+pub const DEAD_LETTER_FILE: &str = "dead_letter.jsonl";
+
+/// 要发送的一条通知
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    /// 同一个 `key` 的通知在发件箱里只保留一条，一般是产品型号
+    pub key: String,
+    pub subject: String,
+    /// 纯文字内容，桌面通知、webhook、命令用的都是它
+    pub body: String,
+    /// html 内容，邮件里和纯文字内容一起发送，邮箱会优先显示它
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+    /// 通知对应的时间，一般是查到库存的时间。旧版本保存的通知没有这个字段，当成读取的时间
+    #[serde(default = "Local::now")]
+    pub time: DateTime<Local>,
+}
+
+/// 发送失败之后的重试设置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// 第一次重试等待的时间，之后每次翻倍
+    pub base: Duration,
+    /// 最长等待时间
+    pub max: Duration,
+    /// 最多发送几次，还是失败就放弃
+    pub max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            base: Duration::from_secs(30),
+            max: Duration::from_secs(30 * 60),
+            max_attempts: 8,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// 第 `attempts` 次失败之后等多久再重试
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1).min(16));
+        self.base.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// 通知在一个渠道的发送结果，`channel` 是 [`Notifier::name`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryEvent {
+    /// 发送成功
+    Sent { key: String, channel: String },
+    /// 发送失败，`retry_in` 之后重试
+    Retrying {
+        key: String,
+        channel: String,
+        attempts: u32,
+        retry_in: Duration,
+        error: String,
+    },
+    /// 放弃发送，已经记录到死信文件。
+    /// `permanent` 表示遇到了不会自己恢复的错误，例如密码错误，而不是重试次数用完了
+    Failed {
+        key: String,
+        channel: String,
+        error: String,
+        permanent: bool,
+    },
+}
+
+/// 发件箱里的一条通知，每个渠道一条
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    notification: Notification,
+    /// 发给哪个渠道
+    #[serde(default = "default_channel")]
+    channel: String,
+    /// 已经发送了几次
+    attempts: u32,
+    /// 创建时间，unix 时间戳(秒)
+    created_at: u64,
+    /// 下次发送时间，unix 时间戳(秒)
+    next_attempt_at: u64,
+    last_error: Option<String>,
+}
+
+/// 旧版本的发件箱里只有邮件通知
+fn default_channel() -> String {
+    "email".to_owned()
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
+/// 保存在文件里的发件箱
+struct Outbox {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+impl Outbox {
+    /// 读取发件箱，文件不存在或者格式不对就从空的开始
+    fn load(path: PathBuf) -> Self {
+        let entries = match fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("发件箱 {} 格式不正确，已忽略:{}", path.display(), e);
+                    vec![]
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => {
+                error!("读取发件箱 {} 出错:{}", path.display(), e);
+                vec![]
+            }
+        };
+        Outbox { path, entries }
+    }
+
+    /// 先写到临时文件再改名
+    fn save(&self) {
+        let text = match serde_json::to_string(&self.entries) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("保存发件箱出错:{}", e);
+                return;
+            }
+        };
+        let tmp = self.path.with_extension("json.tmp");
+        if let Err(e) = fs::write(&tmp, text).and_then(|_| fs::rename(&tmp, &self.path)) {
+            error!("保存发件箱 {} 出错:{}", self.path.display(), e);
+        }
+    }
+
+    /// 给每个渠道放一条，同一个渠道同一个 `key` 已经有了就换成新的内容，重试时间不变
+    fn push(&mut self, notification: Notification, channels: &[&str]) {
+        let now = unix_now();
+        for channel in channels {
+            if let Some(entry) = self
+                .entries
+                .iter_mut()
+                .find(|v| v.notification.key == notification.key && v.channel == *channel)
+            {
+                entry.notification = notification.clone();
+                continue;
+            }
+            self.entries.push(Entry {
+                notification: notification.clone(),
+                channel: channel.to_string(),
+                attempts: 0,
+                created_at: now,
+                next_attempt_at: now,
+                last_error: None,
+            });
+        }
+        self.save();
+    }
+
+    /// 不在 `busy` 里的渠道最早的一条还要等多久
+    fn next_due(&self, busy: &HashSet<String>) -> Option<Duration> {
+        let now = unix_now();
+        self.entries
+            .iter()
+            .filter(|v| !busy.contains(&v.channel))
+            .map(|v| Duration::from_secs(v.next_attempt_at.saturating_sub(now)))
+            .min()
+    }
+
+    /// 所有通知马上重新发送，例如修改了通知渠道之后
+    fn retry_now(&mut self) {
+        let now = unix_now();
+        for entry in &mut self.entries {
+            entry.next_attempt_at = entry.next_attempt_at.min(now);
+        }
+    }
+
+    /// 放弃发送，追加到死信文件
+    fn dead_letter(&self, entry: &Entry) {
+        let path = self.path.with_file_name(DEAD_LETTER_FILE);
+        let line = match serde_json::to_string(entry) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| writeln!(f, "{}", line));
+        if let Err(e) = res {
+            error!("写入死信文件 {} 出错:{}", path.display(), e);
+        }
+    }
+}
+
+enum Command {
+    Send(Notification),
+    SetNotifiers(Vec<Arc<dyn Notifier>>),
+}
+
+/// 在后台把通知发给所有的渠道，不会阻塞调用的地方
+///
+/// 通知先保存到发件箱文件，再由后台任务发送，每个渠道单独发送，一次发一条，
+/// 一个渠道很慢或者卡住不会耽误其它渠道。
+/// 失败的按照 [`RetryPolicy`] 重试，
+/// 临时错误重试次数用完或者遇到不会自己恢复的错误(例如密码错误)就放弃，
+/// 记录到死信文件。每条通知的结果通过 [`Dispatcher::spawn`] 返回的通道告诉调用方。
+#[derive(Debug, Clone)]
+pub struct Dispatcher {
+    commands: UnboundedSender<Command>,
+}
+
+impl Dispatcher {
+    /// 启动后台任务，发件箱里上次没发完的通知会继续发送。
+    /// 要在 tokio 运行时里调用，所有的 `Dispatcher` 都释放之后后台任务结束。
+    pub fn spawn<P: AsRef<Path>>(
+        outbox_path: P,
+        policy: RetryPolicy,
+    ) -> (Dispatcher, UnboundedReceiver<DeliveryEvent>) {
+        let (commands, receiver) = unbounded_channel();
+        let (events, events_receiver) = unbounded_channel();
+        let outbox = Outbox::load(outbox_path.as_ref().to_owned());
+        if !outbox.entries.is_empty() {
+            info!("发件箱里有 {} 条通知没有发送", outbox.entries.len());
+        }
+        tokio::spawn(run(outbox, policy, receiver, events));
+        (Dispatcher { commands }, events_receiver)
+    }
+
+    /// 放进发件箱等待发送
+    pub fn send(&self, notification: Notification) {
+        let _ = self.commands.send(Command::Send(notification));
+    }
+
+    /// 设置通知渠道，发件箱里已经删除的渠道的通知会放弃发送。
+    /// 设置之前发件箱里的通知不会发送，新的通知也不会放进发件箱。
+    pub fn set_notifiers(&self, notifiers: Vec<Arc<dyn Notifier>>) {
+        let _ = self.commands.send(Command::SetNotifiers(notifiers));
+    }
+}
+
+/// 一次发送的结果，由发送的后台任务交回给 [`run`]
+struct Outcome {
+    channel: String,
+    notification: Notification,
+    result: Result<(), NotifyError>,
+}
+
+async fn run(
+    mut outbox: Outbox,
+    policy: RetryPolicy,
+    mut commands: UnboundedReceiver<Command>,
+    events: UnboundedSender<DeliveryEvent>,
+) {
+    let mut notifiers: Option<Vec<Arc<dyn Notifier>>> = None;
+    // 每个渠道同时只发一条，各个渠道单独发送，一个渠道很慢不会耽误其它渠道
+    let mut busy: HashSet<String> = HashSet::new();
+    let (outcomes, mut outcomes_receiver) = unbounded_channel::<Outcome>();
+
+    loop {
+        if let Some(list) = &notifiers {
+            start_due(&outbox, list, &mut busy, &outcomes);
+        }
+
+        // 没有设置通知渠道的时候只等命令和还在发送的结果
+        let wait = match notifiers {
+            Some(_) => outbox.next_due(&busy),
+            None => None,
+        };
+        let sleep = async {
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            v = commands.recv() => match v {
+                Some(Command::Send(v)) => match &notifiers {
+                    Some(list) => {
+                        let channels: Vec<&str> = list.iter().map(|v| v.name()).collect();
+                        outbox.push(v, &channels);
+                    }
+                    None => warn!("还没有设置通知渠道，通知 {} 没有发送", v.key),
+                },
+                Some(Command::SetNotifiers(list)) => {
+                    set_notifiers(&mut outbox, &list, &events);
+                    notifiers = Some(list);
+                }
+                None => return,
+            },
+            Some(outcome) = outcomes_receiver.recv() => {
+                busy.remove(&outcome.channel);
+                if let Some(event) = finish(&mut outbox, &policy, outcome) {
+                    outbox.save();
+                    let _ = events.send(event);
+                }
+            }
+            _ = sleep => {}
+        }
+    }
+}
+
+/// 渠道被删除了，发件箱里发给它的通知放弃发送，其它的马上重新发送
+fn set_notifiers(
+    outbox: &mut Outbox,
+    list: &[Arc<dyn Notifier>],
+    events: &UnboundedSender<DeliveryEvent>,
+) {
+    let mut i = 0;
+    while i < outbox.entries.len() {
+        if list.iter().any(|v| v.name() == outbox.entries[i].channel) {
+            i += 1;
+            continue;
+        }
+        let mut entry = outbox.entries.remove(i);
+        let error = format!("通知渠道 {} 已经删除", entry.channel);
+        warn!("通知 {} {}，放弃发送", entry.notification.key, error);
+        entry.last_error = Some(error.clone());
+        outbox.dead_letter(&entry);
+        let _ = events.send(DeliveryEvent::Failed {
+            key: entry.notification.key,
+            channel: entry.channel,
+            error,
+            permanent: false,
+        });
+    }
+    outbox.retry_now();
+    outbox.save();
+}
+
+/// 到时间的通知交给后台任务发送，渠道正在发送别的通知的等它发完
+fn start_due(
+    outbox: &Outbox,
+    notifiers: &[Arc<dyn Notifier>],
+    busy: &mut HashSet<String>,
+    outcomes: &UnboundedSender<Outcome>,
+) {
+    let now = unix_now();
+    for entry in &outbox.entries {
+        if entry.next_attempt_at > now || busy.contains(&entry.channel) {
+            continue;
+        }
+        let notifier = match notifiers.iter().find(|v| v.name() == entry.channel) {
+            Some(v) => v.clone(),
+            None => continue,
+        };
+        busy.insert(entry.channel.clone());
+        let channel = entry.channel.clone();
+        let notification = entry.notification.clone();
+        let outcomes = outcomes.clone();
+        let task = {
+            let notification = notification.clone();
+            tokio::spawn(async move { notifier.notify(&notification).await })
+        };
+        tokio::spawn(async move {
+            // 渠道发送的时候崩溃了也要告诉发件箱，不然这个渠道一直被当作正在发送
+            let result = match task.await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(
+                        "通知 {} 通过 {} 发送的任务出错:{}",
+                        notification.key, channel, e
+                    );
+                    Err(NotifyError::Task(e.to_string()))
+                }
+            };
+            let _ = outcomes.send(Outcome {
+                channel,
+                notification,
+                result,
+            });
+        });
+    }
+}
+
+/// 按发送结果更新发件箱，返回要告诉调用方的事件。
+/// 发送期间渠道被删除了的话发件箱里已经没有这条，返回 `None`
+fn finish(outbox: &mut Outbox, policy: &RetryPolicy, outcome: Outcome) -> Option<DeliveryEvent> {
+    let Outcome {
+        channel,
+        notification,
+        result,
+    } = outcome;
+    let index = outbox
+        .entries
+        .iter()
+        .position(|v| v.notification.key == notification.key && v.channel == channel)?;
+
+    let entry = &mut outbox.entries[index];
+    entry.attempts += 1;
+    let key = notification.key.clone();
+    let event = match result {
+        Ok(_) => {
+            info!("通知 {} 通过 {} 发送成功", key, channel);
+            if entry.notification == notification {
+                outbox.entries.remove(index);
+            } else {
+                // 发送期间内容更新了，新的内容还要再发一次
+                entry.attempts = 0;
+                entry.last_error = None;
+            }
+            DeliveryEvent::Sent { key, channel }
+        }
+        Err(e) if e.is_transient() && entry.attempts < policy.max_attempts => {
+            let retry_in = policy.delay(entry.attempts);
+            warn!(
+                "通知 {} 通过 {} 第 {} 次发送失败，{} 秒后重试:{}",
+                key,
+                channel,
+                entry.attempts,
+                retry_in.as_secs(),
+                e
+            );
+            entry.next_attempt_at = unix_now() + retry_in.as_secs();
+            entry.last_error = Some(format!("{}", e));
+            DeliveryEvent::Retrying {
+                key,
+                channel,
+                attempts: entry.attempts,
+                retry_in,
+                error: format!("{}", e),
+            }
+        }
+        Err(e) => {
+            error!(
+                "通知 {} 通过 {} 发送 {} 次都失败了，放弃发送:{}",
+                key, channel, entry.attempts, e
+            );
+            entry.last_error = Some(format!("{}", e));
+            let entry = outbox.entries.remove(index);
+            outbox.dead_letter(&entry);
+            DeliveryEvent::Failed {
+                key,
+                channel,
+                error: format!("{}", e),
+                permanent: !e.is_transient(),
+            }
+        }
+    };
+    Some(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CommandNotifierConfig;
+    use crate::notify::CommandNotifier;
+    use async_trait::async_trait;
+
+    /// 一直发不完的渠道
+    struct Stuck;
+
+    #[async_trait]
+    impl Notifier for Stuck {
+        fn name(&self) -> &str {
+            "stuck"
+        }
+
+        async fn notify(&self, _: &Notification) -> Result<(), NotifyError> {
+            std::future::pending().await
+        }
+    }
+
+    /// 马上发送成功的渠道
+    struct Quick;
+
+    #[async_trait]
+    impl Notifier for Quick {
+        fn name(&self) -> &str {
+            "instant"
+        }
+
+        async fn notify(&self, _: &Notification) -> Result<(), NotifyError> {
+            Ok(())
+        }
+    }
+
+    /// 发送的时候崩溃的渠道
+    struct Panics;
+
+    #[async_trait]
+    impl Notifier for Panics {
+        fn name(&self) -> &str {
+            "panics"
+        }
+
+        async fn notify(&self, _: &Notification) -> Result<(), NotifyError> {
+            panic!("渠道崩溃了")
+        }
+    }
+
+    fn notification(key: &str) -> Notification {
+        Notification {
+            key: key.to_owned(),
+            subject: "subject".to_owned(),
+            body: "body".to_owned(),
+            html: None,
+            time: Local::now(),
+        }
+    }
+
+    fn outbox_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ti-outbox-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("outbox.json")
+    }
+
+    async fn next(events: &mut UnboundedReceiver<DeliveryEvent>) -> DeliveryEvent {
+        tokio::time::timeout(Duration::from_secs(10), events.recv())
+            .await
+            .expect("没有收到发送结果")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_channel_does_not_block_others() {
+        let (dispatcher, mut events) =
+            Dispatcher::spawn(outbox_path("slow"), RetryPolicy::default());
+        dispatcher.set_notifiers(vec![Arc::new(Stuck), Arc::new(Quick)]);
+        dispatcher.send(notification("A"));
+        dispatcher.send(notification("B"));
+
+        for key in ["A", "B"] {
+            assert_eq!(
+                next(&mut events).await,
+                DeliveryEvent::Sent {
+                    key: key.to_owned(),
+                    channel: "instant".to_owned(),
+                }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_command_is_permanent() {
+        let path = outbox_path("command");
+        let (dispatcher, mut events) = Dispatcher::spawn(&path, RetryPolicy::default());
+        let config = CommandNotifierConfig {
+            name: String::new(),
+            enabled: true,
+            program: "ti-no-such-command".to_owned(),
+            args: vec![],
+            timeout_secs: 5,
+        };
+        dispatcher.set_notifiers(vec![Arc::new(CommandNotifier::from_config(&config, 0))]);
+        dispatcher.send(notification("A"));
+
+        match next(&mut events).await {
+            DeliveryEvent::Failed {
+                key,
+                channel,
+                permanent,
+                ..
+            } => {
+                assert_eq!(key, "A");
+                assert_eq!(channel, "command-1");
+                assert!(permanent);
+            }
+            v => panic!("应该直接放弃发送:{:?}", v),
+        }
+        assert!(path.with_file_name(DEAD_LETTER_FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn panicking_channel_is_released() {
+        let (dispatcher, mut events) =
+            Dispatcher::spawn(outbox_path("panic"), RetryPolicy::default());
+        dispatcher.set_notifiers(vec![Arc::new(Panics)]);
+
+        // 崩溃之后渠道不会一直占着，下一条通知还能发送
+        for key in ["A", "B"] {
+            dispatcher.send(notification(key));
+            match next(&mut events).await {
+                DeliveryEvent::Failed {
+                    key: failed,
+                    channel,
+                    permanent,
+                    ..
+                } => {
+                    assert_eq!(failed, key);
+                    assert_eq!(channel, "panics");
+                    assert!(permanent);
+                }
+                v => panic!("应该放弃发送:{:?}", v),
+            }
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::alert::AlertRule;
+use crate::mail::{address_list, format_address_list, parse_address_list, Recipients};
+use crate::schedule::MAX_INTERVAL_SECS;
+
+/// 产品列表里的一行
+///
+/// 格式是 `型号 [选项...] [# 注释]`，选项之间用空格分隔，例如:
+///
+/// ```text
+/// OPA1622IDRCR
+/// TPS7A4701RGWR @5m
+/// LM358DR to=a@example.com,b@example.com cc=c@example.com
+/// TPS7A4701RGWR >= 500 < 100
+/// ```
+///
+/// `@` 开头的选项是这个产品单独的查询间隔，可以带单位 `s`、`m`、`h`，不带单位按秒算。
+/// `to=`、`cc=`、`bcc=` 是这个产品的邮件通知单独发给谁，多个地址用逗号分隔。
+/// `>=`、`<`、`+`、`=` 开头的选项是提醒规则，见 [`AlertRule`]，符号和数字之间可以有空格。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProductEntry {
+    /// 产品型号(OPN)
+    pub opn: String,
+    /// 单独设置的查询间隔，配置文件里和产品列表一样写成 `90s`、`5m` 这样
+    #[serde(
+        default,
+        with = "interval_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub interval: Option<Duration>,
+    /// 单独设置的邮件收件人
+    #[serde(
+        default,
+        deserialize_with = "address_list::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub to: Vec<String>,
+    /// 单独设置的邮件抄送
+    #[serde(
+        default,
+        deserialize_with = "address_list::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub cc: Vec<String>,
+    /// 单独设置的邮件密送
+    #[serde(
+        default,
+        deserialize_with = "address_list::deserialize",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub bcc: Vec<String>,
+    /// 提醒规则，不设置就是有库存的时候提醒
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alerts: Vec<AlertRule>,
+    /// 是否查询这个产品，停用的产品留在列表里但是不查询，产品列表里的都是启用的
+    #[serde(default = "default_enabled", skip_serializing_if = "is_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn is_enabled(v: &bool) -> bool {
+    *v
+}
+
+impl ProductEntry {
+    /// 解析一行，`#` 后面是注释，空行和只有注释的行返回 `Ok(None)`
+    pub fn parse(line: &str) -> Result<Option<ProductEntry>, String> {
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+
+        let mut tokens = line.split_whitespace();
+        let opn = tokens.next().unwrap_or_default().to_owned();
+
+        let mut entry = ProductEntry {
+            opn,
+            interval: None,
+            to: vec![],
+            cc: vec![],
+            bcc: vec![],
+            alerts: vec![],
+            enabled: true,
+        };
+
+        while let Some(token) = tokens.next() {
+            if token.starts_with(['>', '<', '+', '=']) {
+                // `>= 500` 这样符号和数字分开写的，把后面的数字拼上
+                let rule = if token.contains(|c: char| c.is_ascii_digit()) {
+                    token.to_owned()
+                } else {
+                    format!("{}{}", token, tokens.next().unwrap_or_default())
+                };
+                let rule = match rule.parse::<AlertRule>() {
+                    Ok(v) => v,
+                    Err(e) => return Err(format!("产品 {} 的{}", entry.opn, e)),
+                };
+                if !entry.alerts.contains(&rule) {
+                    entry.alerts.push(rule);
+                }
+            } else if let Some(v) = token.strip_prefix('@') {
+                let interval = parse_duration(v)?;
+                if interval.as_secs() > MAX_INTERVAL_SECS {
+                    return Err(format!(
+                        "产品 {} 的查询间隔不能超过 {}",
+                        entry.opn,
+                        format_duration(Duration::from_secs(MAX_INTERVAL_SECS))
+                    ));
+                }
+                entry.interval = Some(interval);
+            } else if let Some(v) = token.strip_prefix("to=") {
+                entry.to.extend(parse_address_list(v));
+            } else if let Some(v) = token.strip_prefix("cc=") {
+                entry.cc.extend(parse_address_list(v));
+            } else if let Some(v) = token.strip_prefix("bcc=") {
+                entry.bcc.extend(parse_address_list(v));
+            } else {
+                return Err(format!("产品 {} 的选项 {} 不认识", entry.opn, token));
+            }
+        }
+
+        Ok(Some(entry))
+    }
+
+    /// 单独设置的邮件收件人
+    pub fn recipients(&self) -> Recipients {
+        Recipients {
+            to: self.to.clone(),
+            cc: self.cc.clone(),
+            bcc: self.bcc.clone(),
+        }
+    }
+}
+
+/// 转回产品列表里的一行
+impl fmt::Display for ProductEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.opn)?;
+        if let Some(interval) = self.interval {
+            write!(f, " @{}", format_duration(interval))?;
+        }
+        for rule in &self.alerts {
+            write!(f, " {}", rule)?;
+        }
+        for (name, list) in [("to", &self.to), ("cc", &self.cc), ("bcc", &self.bcc)] {
+            if !list.is_empty() {
+                // 产品列表里用空格分隔选项，地址之间不能有空格
+                write!(
+                    f,
+                    " {}={}",
+                    name,
+                    format_address_list(list).replace(' ', "")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 解析整个产品列表，一行一个产品，返回解析成功的产品和每一行的错误信息
+pub fn parse_product_list(text: &str) -> (Vec<ProductEntry>, Vec<String>) {
+    let mut entries: Vec<ProductEntry> = vec![];
+    let mut errors = vec![];
+
+    for (i, line) in text.lines().enumerate() {
+        match ProductEntry::parse(line) {
+            Ok(Some(entry)) => {
+                // 重复的型号只保留第一个
+                if !entries.iter().any(|v| v.opn == entry.opn) {
+                    entries.push(entry);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => errors.push(format!("第 {} 行:{}", i + 1, e)),
+        }
+    }
+
+    (entries, errors)
+}
+
+/// 型号是否符合 `pattern`，`*` 匹配任意多个字符，`?` 匹配一个字符，不区分大小写
+pub fn opn_matches(pattern: &str, opn: &str) -> bool {
+    let pattern: Vec<char> = pattern.trim().to_uppercase().chars().collect();
+    let opn: Vec<char> = opn.trim().to_uppercase().chars().collect();
+
+    // 记住上一个 `*` 的位置，匹配失败时让它多匹配一个字符再试
+    let (mut p, mut o) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while o < opn.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == opn[o]) {
+            p += 1;
+            o += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, o));
+            p += 1;
+        } else if let Some((sp, so)) = star {
+            p = sp + 1;
+            o = so + 1;
+            star = Some((sp, so + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// 解析 `90`、`90s`、`5m`、`1h` 这样的时间
+pub fn parse_duration(v: &str) -> Result<Duration, String> {
+    let v = v.trim();
+    let (number, unit) = match v.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => v.split_at(i),
+        None => (v, "s"),
+    };
+
+    let number: u64 = match number.parse() {
+        Ok(v) => v,
+        Err(_) => return Err(format!("时间 {} 格式不正确", v)),
+    };
+
+    let secs = match unit {
+        "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(60 * 60),
+        _ => return Err(format!("时间 {} 的单位不正确，只支持 s、m、h", v)),
+    };
+
+    match secs {
+        Some(v) => Ok(Duration::from_secs(v)),
+        None => Err(format!("时间 {} 太长了", v)),
+    }
+}
+
+/// 把时间写成 [`parse_duration`] 能解析的最简短的格式
+pub fn format_duration(v: Duration) -> String {
+    let secs = v.as_secs();
+    if secs > 0 && secs.is_multiple_of(3600) {
+        format!("{}h", secs / 3600)
+    } else if secs > 0 && secs.is_multiple_of(60) {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// 产品列表转成文字，一行一个，停用的产品不写
+pub fn format_product_list(entries: &[ProductEntry]) -> String {
+    entries
+        .iter()
+        .filter(|v| v.enabled)
+        .map(|v| format!("{}\n", v))
+        .collect::<String>()
+}
+
+pub(crate) mod interval_serde {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(v: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match v {
+            Some(v) => serializer.serialize_str(&super::format_duration(*v)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(v) => super::parse_duration(&v)
+                .map(Some)
+                .map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration(" 90s "), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("-5").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration(&format!("{}h", u64::MAX / 60)).is_err());
+        assert!(parse_duration("99999999999999999999").is_err());
+
+        for secs in [0, 45, 60, 90, 300, 3600, 5400] {
+            let v = Duration::from_secs(secs);
+            assert_eq!(parse_duration(&format_duration(v)), Ok(v));
+        }
+        assert_eq!(format_duration(Duration::from_secs(7200)), "2h");
+        assert_eq!(format_duration(Duration::from_secs(90)), "90s");
+    }
+
+    #[test]
+    fn parse_product_lines() {
+        assert_eq!(ProductEntry::parse("  # 注释"), Ok(None));
+
+        let entry = ProductEntry::parse(
+            "TPS7A4701RGWR @5m >= 500 <100 to=a@example.com,b@example.com # 注释",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(entry.opn, "TPS7A4701RGWR");
+        assert_eq!(entry.interval, Some(Duration::from_secs(300)));
+        assert_eq!(
+            entry.alerts,
+            vec![AlertRule::AtLeast(500), AlertRule::Below(100)]
+        );
+        assert_eq!(entry.to, vec!["a@example.com", "b@example.com"]);
+        assert_eq!(ProductEntry::parse(&entry.to_string()), Ok(Some(entry)));
+
+        assert!(ProductEntry::parse("LM358DR @8d").is_err());
+        assert!(ProductEntry::parse("LM358DR @169h").is_err());
+        assert!(ProductEntry::parse("LM358DR >=0").is_err());
+        assert!(ProductEntry::parse("LM358DR foo").is_err());
+
+        let (entries, errors) = parse_product_list("A\nA @5m\n\nB foo\nC\n");
+        let opns: Vec<&str> = entries.iter().map(|v| v.opn.as_str()).collect();
+        assert_eq!(opns, ["A", "C"]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("第 4 行"));
+    }
+
+    #[test]
+    fn match_opn_patterns() {
+        assert!(opn_matches("TPS7A*", "tps7a4701rgwr"));
+        assert!(opn_matches("LM358?R", "LM358DR"));
+        assert!(opn_matches("*", "X"));
+        assert!(opn_matches("*DR", "LM358DR"));
+        assert!(!opn_matches("LM358?R", "LM358DDR"));
+        assert!(!opn_matches("TPS*", "LM358DR"));
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use chrono::NaiveTime;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// 默认每轮查询之间间隔的秒数
+pub const DEFAULT_INTERVAL_SECS: u64 = 60;
+
+/// 默认的随机抖动秒数
+pub const DEFAULT_JITTER_SECS: u64 = 10;
+
+/// 查询间隔最多 7 天
+pub const MAX_INTERVAL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// 随机抖动最多 1 小时
+pub const MAX_JITTER_SECS: u64 = 60 * 60;
+
+/// 轮询设置
+///
+/// 每个产品查询完之后，要等 `interval_secs` 再加上 0 到 `jitter_secs` 之间的随机秒数才会再次查询，
+/// 在静默时段内不查询。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Schedule {
+    /// 每轮查询之间间隔的秒数
+    pub interval_secs: u64,
+    /// 在间隔的基础上随机多等待的最大秒数，避免请求太规律
+    pub jitter_secs: u64,
+    /// 单独设置了间隔的产品，key 是产品型号，value 是秒数
+    pub overrides: HashMap<String, u64>,
+    /// 静默时段，这段时间内不查询
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            interval_secs: DEFAULT_INTERVAL_SECS,
+            jitter_secs: DEFAULT_JITTER_SECS,
+            overrides: HashMap::new(),
+            quiet_hours: None,
+        }
+    }
+}
+
+/// 静默时段，`start` 和 `end` 都是 `HH:MM` 格式的本地时间，可以跨过零点，例如 `23:00` 到 `07:00`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: String,
+    pub end: String,
+}
+
+impl QuietHours {
+    /// 检查时间格式是否正确
+    pub fn validate(&self) -> Result<(), String> {
+        parse_time(&self.start)?;
+        parse_time(&self.end)?;
+        Ok(())
+    }
+
+    /// `time` 是否在静默时段内，时间格式不正确时当作不在
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        let (start, end) = match (parse_time(&self.start), parse_time(&self.end)) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return false,
+        };
+
+        if start <= end {
+            start <= time && time < end
+        } else {
+            // 跨过零点
+            time >= start || time < end
+        }
+    }
+}
+
+fn parse_time(v: &str) -> Result<NaiveTime, String> {
+    match NaiveTime::parse_from_str(v.trim(), "%H:%M") {
+        Ok(v) => Ok(v),
+        Err(_) => Err(format!("时间 {} 格式不正确，应该是 HH:MM，例如 23:30", v)),
+    }
+}
+
+impl Schedule {
+    /// 产品的查询间隔，单独设置过的优先，最多 [`MAX_INTERVAL_SECS`]
+    pub fn interval_for(&self, product_name: &str) -> Duration {
+        let secs = self
+            .overrides
+            .get(product_name)
+            .copied()
+            .unwrap_or(self.interval_secs);
+        Duration::from_secs(secs.min(MAX_INTERVAL_SECS))
+    }
+
+    /// 用 `rng` 生成随机抖动时间，最多 [`MAX_JITTER_SECS`]
+    pub fn jitter<R: Rng + ?Sized>(&self, rng: &mut R) -> Duration {
+        let max = self.jitter_secs.min(MAX_JITTER_SECS);
+        if max == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_millis(rng.gen_range(0..=max * 1000))
+    }
+
+    /// 当前是否处于静默时段
+    pub fn is_quiet(&self, time: NaiveTime) -> bool {
+        match &self.quiet_hours {
+            Some(v) => v.contains(time),
+            None => false,
+        }
+    }
+}
+
+/// 记录每个产品下一次应该什么时候查询
+#[derive(Debug)]
+pub struct Scheduler {
+    next_check: HashMap<String, Instant>,
+    /// 生成随机抖动
+    rng: StdRng,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler {
+            next_check: HashMap::new(),
+            rng: StdRng::from_entropy(),
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 用固定的种子生成随机抖动，每次运行的查询时间都一样，测试的时候用
+    pub fn with_seed(seed: u64) -> Self {
+        Scheduler {
+            next_check: HashMap::new(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// 到时间该查询的产品，没有查询过的产品马上就要查
+    pub fn due<'a, I>(&self, product_names: I, now: Instant) -> Vec<&'a str>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        product_names
+            .into_iter()
+            .filter(|v| match self.next_check.get(*v) {
+                Some(next) => *next <= now,
+                None => true,
+            })
+            .collect()
+    }
+
+    /// 记录产品已经查询过了，同一轮查询的产品用同一个随机抖动，这样下一轮还能一起批量查询
+    pub fn checked<'a, I>(&mut self, schedule: &Schedule, product_names: I, now: Instant)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let jitter = schedule.jitter(&mut self.rng);
+        for product_name in product_names {
+            self.next_check.insert(
+                product_name.to_owned(),
+                now + schedule.interval_for(product_name) + jitter,
+            );
+        }
+    }
+
+    /// 最近一次需要查询的时间，没有记录就返回 `None`
+    pub fn next_wakeup<'a, I>(&self, product_names: I) -> Option<Instant>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        product_names
+            .into_iter()
+            .filter_map(|v| self.next_check.get(v).copied())
+            .min()
+    }
+
+    /// 忘掉某个产品的查询记录，下次马上查询
+    pub fn reset(&mut self, product_name: &str) {
+        self.next_check.remove(product_name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_jitter_is_repeatable() {
+        let schedule = Schedule {
+            jitter_secs: 30,
+            ..Schedule::default()
+        };
+        let now = Instant::now();
+        let next = |seed| {
+            let mut scheduler = Scheduler::with_seed(seed);
+            scheduler.checked(&schedule, ["A"], now);
+            scheduler.next_wakeup(["A"]).unwrap()
+        };
+        assert_eq!(next(1), next(1));
+        let wait = next(1) - now;
+        assert!(wait >= Duration::from_secs(60) && wait <= Duration::from_secs(90));
+
+        let schedule = Schedule {
+            jitter_secs: 0,
+            ..Schedule::default()
+        };
+        assert_eq!(
+            schedule.jitter(&mut StdRng::seed_from_u64(1)),
+            Duration::from_secs(0)
+        );
+    }
+}
@@ -0,0 +1,458 @@
+//! 库存提醒的模板
+//!
+//! 模板用的是 mustache 语法的一个子集:
+//!
+//! - `{{name}}` 换成对应的值，html 模板里会转义 `<`、`>`、`&` 这些字符，`a.b` 可以取下一层的值
+//! - `{{#name}}...{{/name}}` 值是列表的话每一项重复一次，里面可以直接用这一项的字段；
+//!   是其它有内容的值就显示一次，没有值、`false`、空字符串、空列表不显示
+//! - `{{^name}}...{{/name}}` 和上面相反，没有值的时候才显示
+//! - `{{! 注释 }}` 不会输出
+
+use std::fmt;
+
+use chrono::{DateTime, Local};
+use serde_json::{json, Value};
+
+use crate::account::Inventory;
+use crate::alert::Alert;
+use crate::outbox::Notification;
+
+/// 内置的标题模板
+pub const DEFAULT_SUBJECT_TEMPLATE: &str = "{{opn}} {{reason}}";
+
+/// 内置的纯文字模板
+pub const DEFAULT_TEXT_TEMPLATE: &str = include_str!("../templates/alert.txt");
+
+/// 内置的 html 模板
+pub const DEFAULT_HTML_TEMPLATE: &str = include_str!("../templates/alert.html");
+
+/// 模板格式不正确
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    /// `{{` 没有对应的 `}}`
+    Unterminated,
+    /// `{{#name}}` 没有对应的 `{{/name}}`
+    Unclosed(String),
+    /// `{{/name}}` 前面没有对应的 `{{#name}}`
+    Unopened(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Unterminated => write!(f, "模板里的 {{{{ 没有对应的 }}}}"),
+            TemplateError::Unclosed(v) => write!(f, "模板里的 {{{{#{}}}}} 没有结束", v),
+            TemplateError::Unopened(v) => {
+                write!(f, "模板里的 {{{{/{}}}}} 前面没有对应的开始", v)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Var(String),
+    Section {
+        name: String,
+        inverted: bool,
+        children: Vec<Node>,
+    },
+}
+
+/// 解析好的模板
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+    /// 输出 html，变量要转义
+    html: bool,
+}
+
+impl Template {
+    /// 解析纯文字模板
+    pub fn text(source: &str) -> Result<Template, TemplateError> {
+        Ok(Template {
+            nodes: parse(source)?,
+            html: false,
+        })
+    }
+
+    /// 解析 html 模板
+    pub fn html(source: &str) -> Result<Template, TemplateError> {
+        Ok(Template {
+            nodes: parse(source)?,
+            html: true,
+        })
+    }
+
+    pub fn render(&self, context: &Value) -> String {
+        let mut out = String::new();
+        let mut stack = vec![context];
+        render_nodes(&self.nodes, &mut stack, self.html, &mut out);
+        out
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<Node>, TemplateError> {
+    // 每一层是还没结束的区块的名字、是否反转和已经解析的内容
+    let mut stack: Vec<(String, bool, Vec<Node>)> = vec![(String::new(), false, vec![])];
+    let mut rest = source;
+
+    while let Some(start) = rest.find("{{") {
+        let current = &mut stack.last_mut().unwrap().2;
+        if start > 0 {
+            current.push(Node::Text(rest[..start].to_owned()));
+        }
+        let end = match rest[start..].find("}}") {
+            Some(v) => start + v,
+            None => return Err(TemplateError::Unterminated),
+        };
+        let tag = rest[start + 2..end].trim();
+        rest = &rest[end + 2..];
+
+        if let Some(name) = tag.strip_prefix('#') {
+            stack.push((name.trim().to_owned(), false, vec![]));
+        } else if let Some(name) = tag.strip_prefix('^') {
+            stack.push((name.trim().to_owned(), true, vec![]));
+        } else if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim();
+            if stack.len() == 1 || stack.last().unwrap().0 != name {
+                return Err(TemplateError::Unopened(name.to_owned()));
+            }
+            let (name, inverted, children) = stack.pop().unwrap();
+            stack.last_mut().unwrap().2.push(Node::Section {
+                name,
+                inverted,
+                children,
+            });
+        } else if !tag.starts_with('!') {
+            current.push(Node::Var(tag.to_owned()));
+        }
+    }
+
+    if stack.len() > 1 {
+        return Err(TemplateError::Unclosed(stack.pop().unwrap().0));
+    }
+    let mut nodes = stack.pop().unwrap().2;
+    if !rest.is_empty() {
+        nodes.push(Node::Text(rest.to_owned()));
+    }
+    Ok(nodes)
+}
+
+/// 从里往外找名字是 `name` 的值
+fn lookup<'a>(stack: &[&'a Value], name: &str) -> Option<&'a Value> {
+    if name == "." {
+        return stack.last().copied();
+    }
+    let mut parts = name.split('.');
+    let first = parts.next()?;
+    let mut value = stack.iter().rev().find_map(|v| v.get(first))?;
+    for part in parts {
+        value = value.get(part)?;
+    }
+    Some(value)
+}
+
+fn is_truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) | Some(Value::Bool(false)) => false,
+        Some(Value::String(v)) => !v.is_empty(),
+        Some(Value::Array(v)) => !v.is_empty(),
+        Some(_) => true,
+    }
+}
+
+fn render_nodes(nodes: &[Node], stack: &mut Vec<&Value>, html: bool, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(v) => out.push_str(v),
+            Node::Var(name) => {
+                let text = match lookup(stack, name) {
+                    Some(Value::String(v)) => v.clone(),
+                    Some(Value::Number(v)) => v.to_string(),
+                    Some(Value::Bool(true)) => "是".to_owned(),
+                    _ => String::new(),
+                };
+                if html {
+                    escape_html(&text, out);
+                } else {
+                    out.push_str(&text);
+                }
+            }
+            Node::Section {
+                name,
+                inverted,
+                children,
+            } => {
+                let value = lookup(stack, name);
+                if *inverted {
+                    if !is_truthy(value) {
+                        render_nodes(children, stack, html, out);
+                    }
+                    continue;
+                }
+                match value {
+                    Some(Value::Array(items)) => {
+                        for item in items {
+                            stack.push(item);
+                            render_nodes(children, stack, html, out);
+                            stack.pop();
+                        }
+                    }
+                    Some(v) if is_truthy(Some(v)) => {
+                        stack.push(v);
+                        render_nodes(children, stack, html, out);
+                        stack.pop();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn escape_html(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+/// 一次库存提醒的内容
+#[derive(Debug, Clone)]
+pub struct StockAlert<'a> {
+    /// 产品列表里的型号，通知的 `key` 也是它
+    pub opn: &'a str,
+    /// 这次查到的库存信息
+    pub inventory: &'a Inventory,
+    /// 触发的提醒规则
+    pub alert: &'a Alert,
+    /// 产品在 ti.com 的购买页面
+    pub url: String,
+    pub time: DateTime<Local>,
+    /// 其它现在有库存的产品和库存数量
+    pub others: Vec<(String, usize)>,
+}
+
+impl StockAlert<'_> {
+    /// 模板里能用的值
+    ///
+    /// ```json
+    /// {
+    ///   "opn": "OPA1622IDRCR", "stock": 250, "previous_stock": 0,
+    ///   "reason": "库存达到 200，现在有 250 个", "rule": ">=200", "repeat": false,
+    ///   "time": "2022-01-01 08:00:00", "url": "https://www.ti.com/...",
+    ///   "lifecycle": "ACTIVE", "packaging": "LARGE T&R", "package_quantity": 3000,
+    ///   "lead_time": "6 weeks", "minimum_order_quantity": 1, "purchase_limit": 500,
+    ///   "prices": [{"currency": "USD", "breaks": [{"quantity": 1, "price": "1.23"}]}],
+    ///   "has_others": true, "others": [{"opn": "TPS7A4701RGWR", "stock": 12}]
+    /// }
+    /// ```
+    pub fn context(&self) -> Value {
+        let inventory = self.inventory;
+        let prices: Vec<Value> = inventory
+            .pricing
+            .iter()
+            .filter(|v| !v.price_breaks.is_empty())
+            .map(|v| {
+                let breaks: Vec<Value> = v
+                    .price_breaks
+                    .iter()
+                    .map(|v| json!({"quantity": v.quantity, "price": format!("{}", v.price)}))
+                    .collect();
+                json!({"currency": v.currency, "breaks": breaks})
+            })
+            .collect();
+        let others: Vec<Value> = self
+            .others
+            .iter()
+            .map(|(opn, stock)| json!({"opn": opn, "stock": stock}))
+            .collect();
+
+        json!({
+            "opn": self.opn,
+            "stock": inventory.inventory,
+            "previous_stock": self.alert.previous,
+            "reason": self.alert.reason,
+            "rule": self.alert.rule.to_string(),
+            "repeat": self.alert.repeat,
+            "time": self.time.format("%Y-%m-%d %H:%M:%S").to_string(),
+            "url": self.url,
+            "lifecycle": inventory.lifecycle_status,
+            "packaging": inventory.packaging,
+            "package_quantity": inventory.package_quantity,
+            "lead_time": inventory.lead_time,
+            "minimum_order_quantity": inventory.minimum_order_quantity,
+            "purchase_limit": inventory.purchase_limit,
+            "prices": prices,
+            "has_others": !others.is_empty(),
+            "others": others,
+        })
+    }
+}
+
+/// 库存提醒的标题、纯文字内容和 html 内容的模板
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertTemplates {
+    pub subject: Template,
+    pub text: Template,
+    pub html: Template,
+}
+
+impl Default for AlertTemplates {
+    fn default() -> Self {
+        AlertTemplates {
+            subject: Template::text(DEFAULT_SUBJECT_TEMPLATE).unwrap(),
+            text: Template::text(DEFAULT_TEXT_TEMPLATE).unwrap(),
+            html: Template::html(DEFAULT_HTML_TEMPLATE).unwrap(),
+        }
+    }
+}
+
+impl AlertTemplates {
+    /// 生成通知
+    pub fn render(&self, alert: &StockAlert) -> Notification {
+        let context = alert.context();
+        // 标题里不能换行
+        let subject = self.subject.render(&context).replace(['\r', '\n'], " ");
+        Notification {
+            key: alert.opn.to_owned(),
+            subject: subject.trim().to_owned(),
+            body: self.text.render(&context),
+            html: Some(self.html.render(&context)),
+            time: alert.time,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alert::AlertRule;
+
+    fn render(source: &str, context: Value) -> String {
+        Template::text(source).unwrap().render(&context)
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Template::text("{{opn"), Err(TemplateError::Unterminated));
+        assert_eq!(
+            Template::text("{{#prices}}x"),
+            Err(TemplateError::Unclosed("prices".to_owned()))
+        );
+        assert_eq!(
+            Template::text("x{{/prices}}"),
+            Err(TemplateError::Unopened("prices".to_owned()))
+        );
+        assert_eq!(
+            Template::text("{{#a}}{{#b}}{{/a}}{{/b}}"),
+            Err(TemplateError::Unopened("a".to_owned()))
+        );
+    }
+
+    #[test]
+    fn render_values_and_sections() {
+        let context = json!({
+            "opn": "LM358DR",
+            "stock": 12,
+            "repeat": true,
+            "empty": "",
+            "inventory": {"lead_time": "6 weeks"},
+            "others": [{"opn": "A", "stock": 1}, {"opn": "B", "stock": 2}],
+        });
+        assert_eq!(
+            render(
+                "{{ opn }}: {{stock}} {{repeat}} [{{missing}}]{{! 注释 }}",
+                context.clone()
+            ),
+            "LM358DR: 12 是 []"
+        );
+        assert_eq!(
+            render("{{inventory.lead_time}}", context.clone()),
+            "6 weeks"
+        );
+        assert_eq!(
+            render("{{#others}}{{opn}}={{stock}} {{/others}}", context.clone()),
+            "A=1 B=2 "
+        );
+        assert_eq!(
+            render("{{#tags}}{{.}},{{/tags}}", json!({"tags": ["x", "y"]})),
+            "x,y,"
+        );
+        // 里面找不到的值从外层找
+        assert_eq!(
+            render(
+                "{{#others}}{{opn}}<{{stock}}/{{inventory.lead_time}}>{{/others}}",
+                context.clone()
+            ),
+            "A<1/6 weeks>B<2/6 weeks>"
+        );
+        assert_eq!(
+            render(
+                "{{#empty}}x{{/empty}}{{^empty}}空{{/empty}}{{^others}}没有{{/others}}",
+                context
+            ),
+            "空"
+        );
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        let template = Template::html("<b>{{v}}</b>").unwrap();
+        assert_eq!(
+            template.render(&json!({"v": "<a href=\"x\">&'</a>"})),
+            "<b>&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;</b>"
+        );
+        assert_eq!(
+            Template::text("{{v}}")
+                .unwrap()
+                .render(&json!({"v": "<b>"})),
+            "<b>"
+        );
+    }
+
+    #[test]
+    fn default_templates_render_alert() {
+        let inventory: Inventory = serde_json::from_value(json!({
+            "orderable_number": "OPA1622IDRCR",
+            "inventory": 620,
+            "pricing": [{"currency": "USD", "priceBreaks": [{"priceBreakQuantity": 1, "price": 1.23}]}],
+        }))
+        .unwrap();
+        let alert = Alert {
+            rule: AlertRule::AtLeast(500),
+            previous: Some(300),
+            reason: "库存达到 500，现在有 620 个".to_owned(),
+            repeat: false,
+        };
+        let notification = AlertTemplates::default().render(&StockAlert {
+            opn: "OPA1622IDRCR",
+            inventory: &inventory,
+            alert: &alert,
+            url: "https://www.ti.com/product/OPA1622".to_owned(),
+            time: Local::now(),
+            others: vec![("LM358DR".to_owned(), 12)],
+        });
+        assert_eq!(notification.key, "OPA1622IDRCR");
+        assert_eq!(
+            notification.subject,
+            "OPA1622IDRCR 库存达到 500，现在有 620 个"
+        );
+        assert!(notification.body.contains("620"));
+        assert!(notification.body.contains("LM358DR"));
+        assert!(notification
+            .html
+            .unwrap()
+            .contains("https://www.ti.com/product/OPA1622"));
+    }
+}
@@ -0,0 +1,437 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::error::AccountError;
+
+/// 令牌桶限速设置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// 每秒最多发几个请求，小于等于0表示不限速
+    pub per_second: f64,
+    /// 允许突发的请求数，也就是桶的大小
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            per_second: 2.0,
+            burst: 4,
+        }
+    }
+}
+
+/// 出错之后的退避和熔断设置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// 第一次熔断等待的时间，之后每次连续熔断翻倍
+    pub base: Duration,
+    /// 最长等待时间，服务器 Retry-After 要求的时间也不会超过它
+    pub max: Duration,
+    /// 连续失败多少次之后熔断，403/429 不管次数直接熔断
+    pub failure_threshold: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            base: Duration::from_secs(30),
+            max: Duration::from_secs(30 * 60),
+            failure_threshold: 5,
+        }
+    }
+}
+
+/// 当前的请求状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleState {
+    /// 正常请求
+    Closed,
+    /// 熔断中，`retry_in` 之后才会再次请求
+    Open { failures: u32, retry_in: Duration },
+    /// 熔断时间到了，正在用一个请求试探是否恢复
+    HalfOpen,
+}
+
+impl ThrottleState {
+    /// 是否是同一种状态，不比较剩余时间
+    pub fn same_kind(&self, other: &ThrottleState) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+impl fmt::Display for ThrottleState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThrottleState::Closed => write!(f, "正常"),
+            ThrottleState::Open { failures, retry_in } => write!(
+                f,
+                "连续失败 {} 次，暂停请求 {} 秒",
+                failures,
+                retry_in.as_secs()
+            ),
+            ThrottleState::HalfOpen => write!(f, "正在试探是否恢复"),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Closed,
+    Open(Instant),
+    HalfOpen { probing: bool },
+}
+
+#[derive(Debug)]
+struct Inner {
+    rate_limit: RateLimit,
+    backoff: Backoff,
+    tokens: f64,
+    last_refill: Instant,
+    // 连续失败次数
+    failures: u32,
+    // 连续熔断次数，用来计算退避时间
+    trips: u32,
+    state: State,
+}
+
+/// 限速和熔断，[`Account`](crate::Account) 的所有克隆共用同一个
+#[derive(Debug, Clone)]
+pub(crate) struct Throttle {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// 一次请求的许可，请求结束后用 [`Permit::finish`] 告诉限速器结果
+pub(crate) struct Permit<'a> {
+    throttle: &'a Throttle,
+    probe: bool,
+    done: bool,
+}
+
+impl Throttle {
+    pub(crate) fn new(rate_limit: RateLimit, backoff: Backoff) -> Self {
+        Throttle {
+            inner: Arc::new(Mutex::new(Inner {
+                rate_limit,
+                backoff,
+                tokens: rate_limit.burst.max(1) as f64,
+                last_refill: Instant::now(),
+                failures: 0,
+                trips: 0,
+                state: State::Closed,
+            })),
+        }
+    }
+
+    pub(crate) fn state(&self) -> ThrottleState {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Closed => ThrottleState::Closed,
+            State::Open(until) => ThrottleState::Open {
+                failures: inner.failures,
+                retry_in: until.saturating_duration_since(Instant::now()),
+            },
+            State::HalfOpen { .. } => ThrottleState::HalfOpen,
+        }
+    }
+
+    /// 等待可以发送请求，熔断中直接返回错误
+    pub(crate) async fn acquire(&self) -> Result<Permit<'_>, AccountError> {
+        let mut probe = false;
+        loop {
+            let wait = {
+                let mut inner = self.inner.lock().unwrap();
+                let now = Instant::now();
+
+                match inner.state {
+                    State::Open(until) if now < until => {
+                        return Err(AccountError::CircuitOpen(until - now));
+                    }
+                    State::Open(_) => {
+                        info!("熔断时间到了，发送一个请求试探是否恢复");
+                        inner.state = State::HalfOpen { probing: true };
+                        probe = true;
+                    }
+                    State::HalfOpen { probing: true } if !probe => {
+                        return Err(AccountError::CircuitOpen(Duration::from_secs(1)));
+                    }
+                    State::HalfOpen { probing: false } => {
+                        inner.state = State::HalfOpen { probing: true };
+                        probe = true;
+                    }
+                    _ => {}
+                }
+
+                inner.take_token(now)
+            };
+
+            match wait {
+                Some(v) => tokio::time::sleep(v).await,
+                None => {
+                    return Ok(Permit {
+                        throttle: self,
+                        probe,
+                        done: false,
+                    })
+                }
+            }
+        }
+    }
+}
+
+impl Inner {
+    /// 拿一个令牌，拿不到就返回需要等待的时间
+    fn take_token(&mut self, now: Instant) -> Option<Duration> {
+        let rate = self.rate_limit.per_second;
+        if rate <= 0.0 {
+            return None;
+        }
+
+        let burst = self.rate_limit.burst.max(1) as f64;
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+
+    fn success(&mut self) {
+        if !matches!(self.state, State::Closed) {
+            info!("请求恢复正常");
+        }
+        self.failures = 0;
+        self.trips = 0;
+        self.state = State::Closed;
+    }
+
+    fn failure(&mut self, e: &AccountError) {
+        self.failures += 1;
+
+        let probe_failed = matches!(self.state, State::HalfOpen { .. });
+        if !e.is_throttled() && !probe_failed && self.failures < self.backoff.failure_threshold {
+            return;
+        }
+
+        // 每次连续熔断等待时间翻倍
+        let factor = 2u32.saturating_pow(self.trips.min(16));
+        let mut wait = self
+            .backoff
+            .base
+            .saturating_mul(factor)
+            .min(self.backoff.max);
+        if let AccountError::RateLimited {
+            retry_after: Some(v),
+        } = e
+        {
+            // 服务器要求等更久就听服务器的，但也不超过最长等待时间，
+            // 太大的 Retry-After 加到 `Instant` 上会溢出
+            wait = wait.max((*v).min(self.backoff.max));
+        }
+        self.trips += 1;
+        self.state = State::Open(Instant::now() + wait);
+
+        warn!(
+            "请求连续失败 {} 次({})，暂停请求 {} 秒",
+            self.failures,
+            e,
+            wait.as_secs()
+        );
+    }
+}
+
+impl Permit<'_> {
+    /// 根据请求结果更新熔断状态
+    pub(crate) fn finish<T>(mut self, res: &Result<T, AccountError>) {
+        let mut inner = self.throttle.inner.lock().unwrap();
+        match res {
+            // 型号不存在说明服务器是正常响应的
+            Ok(_) | Err(AccountError::UnknownPart(_)) => inner.success(),
+            Err(e @ AccountError::Network(_))
+            | Err(e @ AccountError::Timeout(_))
+            | Err(e @ AccountError::RateLimited { .. })
+            | Err(e @ AccountError::Blocked(_)) => inner.failure(e),
+            Err(e @ AccountError::Status(status)) if status.is_server_error() => inner.failure(e),
+            Err(_) => {
+                // 其它错误和服务器状态无关，试探请求的话下次重新试探
+                if self.probe {
+                    inner.state = State::HalfOpen { probing: false };
+                }
+            }
+        }
+        self.done = true;
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        // 试探请求被取消了(例如超时)，让下一个请求重新试探
+        if self.probe && !self.done {
+            if let Ok(mut inner) = self.throttle.inner.lock() {
+                if let State::HalfOpen { .. } = inner.state {
+                    inner.state = State::HalfOpen { probing: false };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle() -> Throttle {
+        Throttle::new(
+            RateLimit {
+                per_second: 0.0,
+                burst: 1,
+            },
+            Backoff {
+                base: Duration::from_millis(100),
+                max: Duration::from_secs(1),
+                failure_threshold: 2,
+            },
+        )
+    }
+
+    fn timeout() -> Result<(), AccountError> {
+        Err(AccountError::Timeout(Duration::from_secs(1)))
+    }
+
+    #[test]
+    fn token_bucket() {
+        let now = Instant::now();
+        let mut inner = Inner {
+            rate_limit: RateLimit {
+                per_second: 2.0,
+                burst: 2,
+            },
+            backoff: Backoff::default(),
+            tokens: 2.0,
+            last_refill: now,
+            failures: 0,
+            trips: 0,
+            state: State::Closed,
+        };
+        assert_eq!(inner.take_token(now), None);
+        assert_eq!(inner.take_token(now), None);
+        let wait = inner.take_token(now).unwrap();
+        assert!(wait > Duration::from_millis(490) && wait <= Duration::from_millis(500));
+        // 过了半秒补回一个
+        assert_eq!(inner.take_token(now + Duration::from_millis(500)), None);
+    }
+
+    #[tokio::test]
+    async fn trips_after_consecutive_failures() {
+        let throttle = throttle();
+        throttle.acquire().await.unwrap().finish(&timeout());
+        assert_eq!(throttle.state(), ThrottleState::Closed);
+        // 成功一次之后重新计数
+        throttle.acquire().await.unwrap().finish(&Ok(()));
+        throttle.acquire().await.unwrap().finish(&timeout());
+        assert_eq!(throttle.state(), ThrottleState::Closed);
+        throttle.acquire().await.unwrap().finish(&timeout());
+        assert!(matches!(
+            throttle.state(),
+            ThrottleState::Open { failures: 2, .. }
+        ));
+        assert!(matches!(
+            throttle.acquire().await,
+            Err(AccountError::CircuitOpen(_))
+        ));
+        // 型号不存在不算失败
+        let throttle = self::throttle();
+        for _ in 0..3 {
+            throttle
+                .acquire()
+                .await
+                .unwrap()
+                .finish::<()>(&Err(AccountError::UnknownPart("X".to_owned())));
+        }
+        assert_eq!(throttle.state(), ThrottleState::Closed);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_trips_immediately_and_probe_recovers() {
+        let throttle = throttle();
+        throttle
+            .acquire()
+            .await
+            .unwrap()
+            .finish::<()>(&Err(AccountError::RateLimited { retry_after: None }));
+        match throttle.state() {
+            ThrottleState::Open { failures, retry_in } => {
+                assert_eq!(failures, 1);
+                assert!(retry_in <= Duration::from_millis(100));
+            }
+            v => panic!("{:?}", v),
+        }
+
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        let probe = throttle.acquire().await.unwrap();
+        assert_eq!(throttle.state(), ThrottleState::HalfOpen);
+        // 试探的时候其它请求不能发
+        assert!(matches!(
+            throttle.acquire().await,
+            Err(AccountError::CircuitOpen(_))
+        ));
+
+        // 试探失败，等待时间翻倍
+        probe.finish(&timeout());
+        match throttle.state() {
+            ThrottleState::Open { retry_in, .. } => {
+                assert!(retry_in > Duration::from_millis(100));
+                assert!(retry_in <= Duration::from_millis(200));
+            }
+            v => panic!("{:?}", v),
+        }
+
+        tokio::time::sleep(Duration::from_millis(220)).await;
+        // 试探请求被取消了，下一个请求接着试探
+        drop(throttle.acquire().await.unwrap());
+        let probe = throttle.acquire().await.unwrap();
+        probe.finish(&Ok(()));
+        assert_eq!(throttle.state(), ThrottleState::Closed);
+    }
+
+    #[tokio::test]
+    async fn retry_after_extends_wait() {
+        let throttle = throttle();
+        throttle
+            .acquire()
+            .await
+            .unwrap()
+            .finish::<()>(&Err(AccountError::RateLimited {
+                retry_after: Some(Duration::from_millis(800)),
+            }));
+        match throttle.state() {
+            ThrottleState::Open { retry_in, .. } => {
+                assert!(retry_in > Duration::from_millis(700))
+            }
+            v => panic!("{:?}", v),
+        }
+
+        // 不会超过最长等待时间，也不会溢出
+        let throttle = self::throttle();
+        throttle
+            .acquire()
+            .await
+            .unwrap()
+            .finish::<()>(&Err(AccountError::RateLimited {
+                retry_after: Some(Duration::MAX),
+            }));
+        match throttle.state() {
+            ThrottleState::Open { retry_in, .. } => assert!(retry_in <= Duration::from_secs(1)),
+            v => panic!("{:?}", v),
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+
+/// 设置了这个环境变量就用它派生加密密钥，不设置就用密钥文件
+pub const PASSPHRASE_ENV: &str = "TI_VAULT_PASSPHRASE";
+
+/// 默认的密钥文件名，和配置文件放在同一个目录
+pub const KEY_FILE: &str = "vault.key";
+
+/// 加密后的内容都以这个开头，方便以后换加密方式
+const PREFIX: &str = "enc:v1:";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+/// 加密或者解密出错
+#[derive(Debug)]
+pub enum VaultError {
+    /// 读写密钥文件失败
+    KeyFile { path: PathBuf, message: String },
+    /// 加密后的内容格式不对
+    Format,
+    /// 密钥不对或者内容被改过，解密不了
+    Decrypt,
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VaultError::KeyFile { path, message } => {
+                write!(f, "读写密钥文件 {} 出错:{}", path.display(), message)
+            }
+            VaultError::Format => write!(f, "加密的密码格式不正确"),
+            VaultError::Decrypt => write!(
+                f,
+                "密码解密失败，密钥文件或者 {} 和加密时不一样",
+                PASSPHRASE_ENV
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VaultError {}
+
+/// 用来加密保存在配置文件里的密码
+///
+/// 密钥来自环境变量 [`PASSPHRASE_ENV`] 里的口令，没有设置的话用密钥文件里的随机内容，
+/// 密钥文件第一次加密时自动生成。每次加密都用随机的盐和 nonce，
+/// 加密结果是 `enc:v1:` 加上 base64 编码的 盐 + nonce + 密文。
+#[derive(Debug, Clone)]
+pub struct Vault {
+    key_file: PathBuf,
+    /// 派生密钥用的口令，`None` 用密钥文件
+    passphrase: Option<String>,
+}
+
+impl Vault {
+    /// 密钥文件放在配置文件旁边，口令从环境变量 [`PASSPHRASE_ENV`] 读取
+    pub fn for_config<P: AsRef<Path>>(config_path: P) -> Self {
+        Vault {
+            key_file: config_path.as_ref().with_file_name(KEY_FILE),
+            passphrase: env::var(PASSPHRASE_ENV).ok(),
+        }
+    }
+
+    /// 用 `passphrase` 代替环境变量里的口令，`None` 表示用密钥文件
+    pub fn passphrase(mut self, passphrase: Option<String>) -> Self {
+        self.passphrase = passphrase;
+        self
+    }
+
+    /// 是否是 [`Vault::encrypt`] 加密出来的内容
+    pub fn is_encrypted(v: &str) -> bool {
+        v.starts_with(PREFIX)
+    }
+
+    /// 加密
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, VaultError> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+
+        let cipher = self.cipher(&salt, true)?;
+        let ciphertext = match cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes()) {
+            Ok(v) => v,
+            Err(_) => return Err(VaultError::Format),
+        };
+
+        let mut data = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+        data.extend_from_slice(&salt);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(format!("{}{}", PREFIX, BASE64.encode(data)))
+    }
+
+    /// 解密
+    pub fn decrypt(&self, v: &str) -> Result<String, VaultError> {
+        let data = match v.strip_prefix(PREFIX).map(|v| BASE64.decode(v.trim())) {
+            Some(Ok(v)) if v.len() > SALT_LEN + NONCE_LEN => v,
+            _ => return Err(VaultError::Format),
+        };
+        let (salt, rest) = data.split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        let cipher = self.cipher(salt, false)?;
+        let plaintext = match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+            Ok(v) => v,
+            Err(_) => return Err(VaultError::Decrypt),
+        };
+        String::from_utf8(plaintext).map_err(|_| VaultError::Decrypt)
+    }
+
+    fn cipher(&self, salt: &[u8], create: bool) -> Result<ChaCha20Poly1305, VaultError> {
+        let secret = match &self.passphrase {
+            Some(v) if !v.is_empty() => v.as_bytes().to_vec(),
+            _ => self.key_file_secret(create)?,
+        };
+
+        let mut key = [0u8; KEY_LEN];
+        pbkdf2::pbkdf2_hmac::<Sha256>(&secret, salt, PBKDF2_ROUNDS, &mut key);
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+
+    /// 读取密钥文件，`create` 为真并且文件不存在时生成一个新的
+    fn key_file_secret(&self, create: bool) -> Result<Vec<u8>, VaultError> {
+        let key_file_error = |e: std::io::Error| VaultError::KeyFile {
+            path: self.key_file.clone(),
+            message: format!("{}", e),
+        };
+
+        if self.key_file.exists() || !create {
+            #[cfg(unix)]
+            restrict_permissions(&self.key_file);
+            return fs::read(&self.key_file).map_err(key_file_error);
+        }
+
+        let mut secret = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut secret);
+        write_key_file(&self.key_file, &secret).map_err(key_file_error)?;
+        Ok(secret.to_vec())
+    }
+}
+
+/// 写密钥文件，先写到临时文件再改名，Unix 上只有自己能读写
+fn write_key_file(path: &Path, secret: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("key.tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let result = options
+        .open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(secret)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// 旧版本生成的密钥文件其他用户也能读，改成只有自己能读写
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) {
+    use log::warn;
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+                warn!("修改密钥文件 {} 的权限出错:{}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_private_key_file() {
+        let dir = std::env::temp_dir().join(format!("ti-vault-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let vault = Vault::for_config(dir.join("config.toml")).passphrase(None);
+
+        let sealed = vault.encrypt("secret").unwrap();
+        assert!(Vault::is_encrypted(&sealed));
+        assert_eq!(vault.decrypt(&sealed).unwrap(), "secret");
+        assert_eq!(fs::read(dir.join(KEY_FILE)).unwrap().len(), KEY_LEN);
+        assert!(!dir.join("vault.key.tmp").exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(dir.join(KEY_FILE))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+
+            // 旧版本生成的 644 密钥文件读取时改成 600
+            fs::set_permissions(dir.join(KEY_FILE), fs::Permissions::from_mode(0o644)).unwrap();
+            assert_eq!(vault.decrypt(&sealed).unwrap(), "secret");
+            let mode = fs::metadata(dir.join(KEY_FILE))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn passphrase_replaces_key_file() {
+        let dir = std::env::temp_dir().join(format!("ti-vault-pass-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let vault = Vault::for_config(&path).passphrase(Some("口令".to_owned()));
+
+        let sealed = vault.encrypt("secret").unwrap();
+        assert_eq!(vault.decrypt(&sealed).unwrap(), "secret");
+        assert!(!dir.join(KEY_FILE).exists());
+        let other = Vault::for_config(&path).passphrase(Some("别的口令".to_owned()));
+        assert!(matches!(other.decrypt(&sealed), Err(VaultError::Decrypt)));
+    }
+}